@@ -1,8 +1,70 @@
-use crate::config::Configuration;
+use std::{collections::HashMap, sync::Arc};
+
+use async_openai::{
+    config::Config as _,
+    error::OpenAIError,
+    types::{
+        ChatCompletionStreamResponseDelta, CompletionUsage, CreateChatCompletionRequest,
+        CreateEmbeddingRequest, EmbeddingInput, FinishReason,
+    },
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cache::ResponseCache,
+    config::{self, Configuration},
+    moderation::Moderator,
+    queue::Queue,
+};
+
+/// A streamed chat completion chunk. Unlike the `async-openai` type, this
+/// preserves the reasoning that some servers stream separately from the content.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChatStreamResponse {
+    #[serde(default)]
+    pub choices: Vec<ChatStreamChoice>,
+    pub usage: Option<CompletionUsage>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChatStreamChoice {
+    pub delta: ChatStreamDelta,
+    pub finish_reason: Option<FinishReason>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChatStreamDelta {
+    #[serde(flatten)]
+    pub inner: ChatCompletionStreamResponseDelta,
+    #[serde(default, alias = "reasoning")]
+    pub reasoning_content: Option<String>,
+}
+
+/// An embeddings response. Unlike the `async-openai` type, this doesn't require the
+/// fields that some servers leave out.
+#[derive(Deserialize)]
+struct EmbeddingResponse {
+    data: Vec<EmbeddingData>,
+}
+
+#[derive(Deserialize)]
+struct EmbeddingData {
+    #[serde(default)]
+    index: usize,
+    embedding: Vec<f32>,
+}
+
+pub type ChatStream = std::pin::Pin<
+    Box<dyn serenity::futures::Stream<Item = Result<ChatStreamResponse, OpenAIError>> + Send>,
+>;
 
 pub struct Ai {
     pub client: async_openai::Client<async_openai::config::OpenAIConfig>,
     pub models: Vec<String>,
+    pub moderator: Moderator,
+    pub queue: Arc<Queue>,
+    cache: Option<Arc<ResponseCache>>,
+    model_configs: HashMap<String, config::Model>,
 }
 impl Ai {
     pub async fn load(config: &Configuration) -> anyhow::Result<Self> {
@@ -27,6 +89,105 @@ impl Ai {
             .map(|m| m.id)
             .collect();
 
-        Ok(Self { client, models })
+        let moderator = Moderator::new(&config.moderation, client.clone())?;
+        let cache = if config.cache.enabled {
+            Some(Arc::new(ResponseCache::open(&config.cache)?))
+        } else {
+            None
+        };
+
+        Ok(Self {
+            client,
+            models,
+            moderator,
+            queue: Arc::new(Queue::new(&config.queue, &config.models)),
+            cache,
+            model_configs: config.models.clone(),
+        })
+    }
+
+    /// Starts streaming a chat completion.
+    pub async fn create_chat_stream(
+        &self,
+        request: CreateChatCompletionRequest,
+    ) -> Result<ChatStream, OpenAIError> {
+        self.client.chat().create_stream_byot(request).await
+    }
+
+    /// Returns a stream that replays the cached response to `request`, if responses are
+    /// cached, `use_cache` is set and there is one. Replaying doesn't use the backend, so
+    /// it doesn't need a turn in the queue.
+    pub fn replay_chat_stream(
+        &self,
+        request: &CreateChatCompletionRequest,
+        use_cache: bool,
+    ) -> anyhow::Result<Option<ChatStream>> {
+        let Some(cache) = self.cache.as_ref().filter(|_| use_cache) else {
+            return Ok(None);
+        };
+        let key = ResponseCache::key(self.client.config().api_base(), request)?;
+        cache.replay(&key)
+    }
+
+    /// Starts streaming a chat completion, caching the response if responses are cached.
+    pub async fn create_recorded_chat_stream(
+        &self,
+        request: CreateChatCompletionRequest,
+    ) -> anyhow::Result<ChatStream> {
+        let Some(cache) = &self.cache else {
+            return Ok(self.create_chat_stream(request).await?);
+        };
+
+        let key = ResponseCache::key(self.client.config().api_base(), &request)?;
+        Ok(cache.record(key, self.create_chat_stream(request).await?))
+    }
+
+    /// Embeds each of `inputs` with the given model, returning the embeddings in the same order.
+    pub async fn embed(&self, model: &str, inputs: Vec<String>) -> anyhow::Result<Vec<Vec<f32>>> {
+        let count = inputs.len();
+        let response: EmbeddingResponse = self
+            .client
+            .embeddings()
+            .create_byot(CreateEmbeddingRequest {
+                model: model.to_string(),
+                input: EmbeddingInput::StringArray(inputs),
+                encoding_format: None,
+                user: None,
+                dimensions: None,
+            })
+            .await?;
+
+        let mut data = response.data;
+        data.sort_by_key(|d| d.index);
+        if data.len() != count {
+            anyhow::bail!(
+                "expected {count} embeddings from `{model}`, but got {}",
+                data.len()
+            );
+        }
+        Ok(data.into_iter().map(|d| d.embedding).collect())
+    }
+
+    /// Whether or not responses are cached.
+    pub fn caches_responses(&self) -> bool {
+        self.cache.is_some()
+    }
+
+    /// The number of tokens the given model can take as input, if configured.
+    pub fn context_length(&self, model: &str) -> Option<usize> {
+        self.model_configs.get(model)?.context_length
+    }
+
+    /// Whether or not the given model has been marked as accepting images.
+    pub fn supports_vision(&self, model: &str) -> bool {
+        self.model_configs.get(model).is_some_and(|m| m.vision)
+    }
+
+    /// Whether or not the given model's output starts inside a `<think>` block that its
+    /// prompt template opened.
+    pub fn has_implicit_think_tag(&self, model: &str) -> bool {
+        self.model_configs
+            .get(model)
+            .is_some_and(|m| m.implicit_think_tag)
     }
 }
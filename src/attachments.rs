@@ -0,0 +1,156 @@
+use async_openai::types::{
+    ChatCompletionRequestMessageContentPartImage, ChatCompletionRequestMessageContentPartText,
+    ChatCompletionRequestUserMessageContent, ChatCompletionRequestUserMessageContentPart, ImageUrl,
+};
+use base64::Engine as _;
+use serenity::all::Attachment;
+
+/// Extensions of files that are treated as text, regardless of their reported content type.
+const TEXT_EXTENSIONS: &[&str] = &[
+    "txt", "md", "markdown", "log", "csv", "tsv", "json", "jsonl", "toml", "yaml", "yml", "xml",
+    "html", "css", "ini", "cfg", "conf", "rs", "lua", "py", "js", "ts", "jsx", "tsx", "c", "h",
+    "cpp", "hpp", "cs", "java", "kt", "go", "rb", "php", "sh", "bash", "ps1", "sql", "swift",
+    "zig", "hs", "ml", "ex", "exs", "erl", "clj", "scala", "r", "jl", "dart", "vue", "svelte",
+    "diff", "patch",
+];
+
+/// Extensions of files that are treated as images, if no content type is available.
+const IMAGE_EXTENSIONS: &[&str] = &["png", "jpg", "jpeg", "gif", "webp"];
+
+/// Content types that are text, but do not start with `text/`.
+const TEXT_CONTENT_TYPES: &[&str] = &[
+    "application/json",
+    "application/xml",
+    "application/javascript",
+    "application/toml",
+    "application/x-yaml",
+    "application/x-sh",
+];
+
+/// Returns the extension of the attachment's file name, if any.
+pub fn extension(attachment: &Attachment) -> Option<&str> {
+    attachment
+        .filename
+        .rsplit_once('.')
+        .map(|(_, extension)| extension)
+}
+
+/// Whether or not the attachment looks like a text file.
+pub fn is_text(attachment: &Attachment) -> bool {
+    let content_type = attachment
+        .content_type
+        .as_deref()
+        .map(|c| c.split(';').next().unwrap_or(c).trim());
+    if let Some(content_type) = content_type {
+        if content_type.starts_with("text/") || TEXT_CONTENT_TYPES.contains(&content_type) {
+            return true;
+        }
+    }
+
+    extension(attachment)
+        .is_some_and(|extension| TEXT_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str()))
+}
+
+/// Whether or not the attachment looks like an image.
+pub fn is_image(attachment: &Attachment) -> bool {
+    match attachment.content_type.as_deref() {
+        Some(content_type) => content_type.starts_with("image/"),
+        None => extension(attachment).is_some_and(|extension| {
+            IMAGE_EXTENSIONS.contains(&extension.to_ascii_lowercase().as_str())
+        }),
+    }
+}
+
+/// Downloads an image attachment, ensuring that it is no larger than `max_size` bytes,
+/// and returns it as a base64 data URL.
+pub async fn download_image(attachment: &Attachment, max_size: u32) -> anyhow::Result<String> {
+    if !is_image(attachment) {
+        anyhow::bail!("`{}` does not appear to be an image", attachment.filename);
+    }
+    check_size(attachment, max_size)?;
+
+    let bytes = attachment.download().await?;
+    let content_type = attachment.content_type.clone().unwrap_or_else(|| {
+        let extension = extension(attachment).unwrap_or("png").to_ascii_lowercase();
+        match extension.as_str() {
+            "jpg" => "image/jpeg".to_string(),
+            _ => format!("image/{extension}"),
+        }
+    });
+
+    Ok(format!(
+        "data:{content_type};base64,{}",
+        base64::engine::general_purpose::STANDARD.encode(bytes)
+    ))
+}
+
+/// Builds the content of a user message from its text and any image URLs.
+pub fn user_message_content(
+    text: String,
+    image_urls: Vec<String>,
+) -> ChatCompletionRequestUserMessageContent {
+    if image_urls.is_empty() {
+        return text.into();
+    }
+
+    let mut parts = vec![ChatCompletionRequestUserMessageContentPart::Text(
+        ChatCompletionRequestMessageContentPartText { text },
+    )];
+    parts.extend(image_urls.into_iter().map(|url| {
+        ChatCompletionRequestUserMessageContentPart::ImageUrl(
+            ChatCompletionRequestMessageContentPartImage {
+                image_url: ImageUrl { url, detail: None },
+            },
+        )
+    }));
+    ChatCompletionRequestUserMessageContent::Array(parts)
+}
+
+/// Downloads a text attachment, ensuring that it is no larger than `max_size` bytes
+/// and that it is valid UTF-8.
+pub async fn download_text(attachment: &Attachment, max_size: u32) -> anyhow::Result<String> {
+    if !is_text(attachment) {
+        anyhow::bail!(
+            "`{}` does not appear to be a text file",
+            attachment.filename
+        );
+    }
+    let bytes = download(attachment, max_size).await?;
+    String::from_utf8(bytes)
+        .map_err(|_| anyhow::anyhow!("`{}` is not valid UTF-8", attachment.filename))
+}
+
+/// Downloads an attachment of any kind, ensuring that it is no larger than `max_size` bytes.
+pub async fn download(attachment: &Attachment, max_size: u32) -> anyhow::Result<Vec<u8>> {
+    check_size(attachment, max_size)?;
+
+    let bytes = attachment.download().await?;
+    if bytes.len() > max_size as usize {
+        anyhow::bail!(
+            "`{}` is larger than the maximum of {max_size} bytes",
+            attachment.filename
+        );
+    }
+    Ok(bytes)
+}
+
+/// Formats a text attachment for inclusion in a prompt.
+pub fn format_text(attachment: &Attachment, content: &str) -> String {
+    let language = extension(attachment).unwrap_or_default();
+    format!(
+        "File `{}`:\n```{language}\n{}\n```",
+        attachment.filename,
+        content.trim_end()
+    )
+}
+
+fn check_size(attachment: &Attachment, max_size: u32) -> anyhow::Result<()> {
+    if attachment.size > max_size {
+        anyhow::bail!(
+            "`{}` is {} bytes, which is larger than the maximum of {max_size} bytes",
+            attachment.filename,
+            attachment.size
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,279 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::Context as _;
+use async_openai::types::CreateChatCompletionRequest;
+use rusqlite::{Connection, OptionalExtension};
+use serenity::futures::{StreamExt, stream};
+use sha2::{Digest, Sha256};
+
+use crate::{
+    ai::{ChatStream, ChatStreamDelta, ChatStreamResponse},
+    config,
+};
+
+/// The delay between each chunk of a replayed response.
+const REPLAY_INTERVAL: Duration = Duration::from_millis(25);
+/// The length of text that streamed chunks are combined up to before being cached,
+/// which sets how quickly responses are replayed.
+const REPLAY_CHUNK_LENGTH: usize = 100;
+
+/// Streamed responses kept on disk, so that identical requests can be answered again
+/// without generating them.
+pub struct ResponseCache {
+    connection: Mutex<Connection>,
+    ttl: Duration,
+    max_entry_size: usize,
+    max_size: usize,
+}
+impl ResponseCache {
+    const FILENAME: &str = "cache.db";
+
+    pub fn open(config: &config::Cache) -> anyhow::Result<Self> {
+        let connection =
+            Connection::open(Self::FILENAME).context("failed to open response cache")?;
+        connection
+            .execute_batch(
+                "CREATE TABLE IF NOT EXISTS responses (
+                    key TEXT PRIMARY KEY,
+                    chunks TEXT NOT NULL,
+                    size INTEGER NOT NULL,
+                    created_at INTEGER NOT NULL
+                );",
+            )
+            .context("failed to create response cache table")?;
+        Ok(Self {
+            connection: Mutex::new(connection),
+            ttl: Duration::from_secs(config.ttl_secs),
+            max_entry_size: config.max_entry_size,
+            max_size: config.max_size,
+        })
+    }
+
+    /// The key for a request to the given server. The request includes the model,
+    /// the parameters and the full list of messages, so any change to them results
+    /// in a different key.
+    pub fn key(server: &str, request: &CreateChatCompletionRequest) -> anyhow::Result<String> {
+        let mut hasher = Sha256::new();
+        hasher.update(server.as_bytes());
+        hasher.update([0]);
+        hasher.update(serde_json::to_vec(request)?);
+        Ok(hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{byte:02x}"))
+            .collect())
+    }
+
+    /// Returns a stream that replays the cached response for `key`, if there is one
+    /// that hasn't expired.
+    pub fn replay(&self, key: &str) -> anyhow::Result<Option<ChatStream>> {
+        let chunks: Option<String> = self
+            .connection
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT chunks FROM responses WHERE key = ?1 AND created_at > ?2",
+                rusqlite::params![key, self.expiry_time()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        let Some(chunks) = chunks else {
+            return Ok(None);
+        };
+
+        let chunks: Vec<ChatStreamResponse> =
+            serde_json::from_str(&chunks).context("invalid cached response")?;
+        Ok(Some(Box::pin(stream::iter(chunks).then(|chunk| async {
+            tokio::time::sleep(REPLAY_INTERVAL).await;
+            Ok(chunk)
+        }))))
+    }
+
+    /// Wraps `stream` so that its response is cached under `key` once it has finished.
+    /// Responses that are cut short by an error, or by the stream being dropped, are not cached.
+    pub fn record(self: &Arc<Self>, key: String, stream: ChatStream) -> ChatStream {
+        struct Recording {
+            cache: Arc<ResponseCache>,
+            key: String,
+            stream: ChatStream,
+            /// The chunks so far, or `None` once there has been an error
+            chunks: Option<Vec<ChatStreamResponse>>,
+        }
+
+        let recording = Recording {
+            cache: self.clone(),
+            key,
+            stream,
+            chunks: Some(vec![]),
+        };
+        Box::pin(stream::unfold(recording, |mut recording| async move {
+            match recording.stream.next().await {
+                Some(Ok(chunk)) => {
+                    if let Some(chunks) = &mut recording.chunks {
+                        push_chunk(chunks, chunk.clone());
+                    }
+                    Some((Ok(chunk), recording))
+                }
+                Some(Err(err)) => {
+                    recording.chunks = None;
+                    Some((Err(err), recording))
+                }
+                None => {
+                    if let Some(chunks) = recording.chunks.take() {
+                        if let Err(err) = recording.cache.insert(&recording.key, &chunks) {
+                            println!("Failed to cache response: {err:?}");
+                        }
+                    }
+                    None
+                }
+            }
+        }))
+    }
+
+    /// Stores the chunks of a response, then removes expired responses and the oldest
+    /// responses beyond the size limit.
+    fn insert(&self, key: &str, chunks: &[ChatStreamResponse]) -> anyhow::Result<()> {
+        let chunks = serde_json::to_string(chunks)?;
+        if chunks.len() > self.max_entry_size {
+            return Ok(());
+        }
+
+        let connection = self.connection.lock().unwrap();
+        connection.execute(
+            "INSERT OR REPLACE INTO responses (key, chunks, size, created_at) VALUES (?1, ?2, ?3, ?4)",
+            rusqlite::params![key, chunks, chunks.len(), unix_time(SystemTime::now())],
+        )?;
+        connection.execute(
+            "DELETE FROM responses WHERE created_at <= ?1",
+            [self.expiry_time()],
+        )?;
+        connection.execute(
+            "DELETE FROM responses WHERE key IN (
+                SELECT key FROM (
+                    SELECT key, SUM(size) OVER (ORDER BY created_at DESC, rowid DESC) AS total
+                    FROM responses
+                )
+                WHERE total > ?1
+            )",
+            [self.max_size],
+        )?;
+        Ok(())
+    }
+
+    /// Responses created at or before this time have expired.
+    fn expiry_time(&self) -> i64 {
+        unix_time(SystemTime::now() - self.ttl)
+    }
+}
+
+fn unix_time(time: SystemTime) -> i64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// Adds a streamed chunk to those to be cached, combining it with the previous chunk
+/// if both only contain text, so that there are fewer chunks to store and replay.
+fn push_chunk(chunks: &mut Vec<ChatStreamResponse>, chunk: ChatStreamResponse) {
+    let combine = chunks
+        .last()
+        .and_then(text_delta)
+        .zip(text_delta(&chunk))
+        .is_some_and(|(last, _)| text_length(last) < REPLAY_CHUNK_LENGTH);
+    let (Some(last), true) = (chunks.last_mut(), combine) else {
+        chunks.push(chunk);
+        return;
+    };
+
+    let last = &mut last.choices[0].delta;
+    let Some(choice) = chunk.choices.into_iter().next() else {
+        return;
+    };
+    for (text, addition) in [
+        (&mut last.inner.content, choice.delta.inner.content),
+        (&mut last.reasoning_content, choice.delta.reasoning_content),
+    ] {
+        if let Some(addition) = addition {
+            text.get_or_insert_default().push_str(&addition);
+        }
+    }
+}
+
+/// The delta of a chunk that only contains text, and so can be combined with others.
+fn text_delta(chunk: &ChatStreamResponse) -> Option<&ChatStreamDelta> {
+    match chunk.choices.as_slice() {
+        [choice]
+            if chunk.usage.is_none()
+                && choice.finish_reason.is_none()
+                && choice.delta.inner.tool_calls.is_none()
+                && choice.delta.inner.refusal.is_none() =>
+        {
+            Some(&choice.delta)
+        }
+        _ => None,
+    }
+}
+
+fn text_length(delta: &ChatStreamDelta) -> usize {
+    delta.inner.content.as_ref().map_or(0, String::len)
+        + delta.reasoning_content.as_ref().map_or(0, String::len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunk(json: serde_json::Value) -> ChatStreamResponse {
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn content(chunk: &ChatStreamResponse) -> Option<&str> {
+        chunk.choices[0].delta.inner.content.as_deref()
+    }
+
+    #[test]
+    fn test_push_chunk() {
+        let mut chunks = vec![];
+        for text in ["Hello", ", ", "world"] {
+            push_chunk(
+                &mut chunks,
+                chunk(serde_json::json!({"choices": [{"delta": {"content": text}}]})),
+            );
+        }
+        push_chunk(
+            &mut chunks,
+            chunk(serde_json::json!({"choices": [{"delta": {}, "finish_reason": "stop"}]})),
+        );
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(content(&chunks[0]), Some("Hello, world"));
+        assert!(chunks[1].choices[0].finish_reason.is_some());
+
+        // Chunks stop being combined once they're long enough
+        let mut chunks = vec![];
+        for _ in 0..3 {
+            push_chunk(
+                &mut chunks,
+                chunk(serde_json::json!({"choices": [{"delta": {"content": "a".repeat(60)}}]})),
+            );
+        }
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(content(&chunks[0]).map(str::len), Some(120));
+    }
+
+    #[test]
+    fn test_cached_chunks_round_trip() {
+        let original = chunk(serde_json::json!({
+            "choices": [{"delta": {"content": "Hi", "reasoning_content": "Greet them"}}],
+        }));
+        let cached: ChatStreamResponse =
+            serde_json::from_str(&serde_json::to_string(&original).unwrap()).unwrap();
+        assert_eq!(content(&cached), Some("Hi"));
+        assert_eq!(
+            cached.choices[0].delta.reasoning_content.as_deref(),
+            Some("Greet them")
+        );
+    }
+}
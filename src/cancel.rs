@@ -1,6 +1,6 @@
-use serenity::all::{
-    ButtonStyle, CreateActionRow, CreateButton, EditMessage, Http, Message, MessageId, UserId,
-};
+use serenity::all::{ButtonStyle, CreateButton, MessageId, UserId};
+
+use crate::localization;
 
 pub const CANCEL_ID_BASE: &str = "cancel";
 
@@ -21,21 +21,9 @@ pub fn parse_id(id: &str) -> Option<(MessageId, UserId)> {
     ))
 }
 
-/// Adds a cancel button to a message.
-pub async fn add_button(
-    http: &Http,
-    first_id: MessageId,
-    msg: &mut Message,
-    user_id: UserId,
-) -> anyhow::Result<()> {
-    Ok(msg
-        .edit(
-            http,
-            EditMessage::new().components(vec![CreateActionRow::Buttons(vec![
-                CreateButton::new(build_id(first_id, user_id))
-                    .style(ButtonStyle::Danger)
-                    .label("Cancel"),
-            ])]),
-        )
-        .await?)
+/// Creates the button that cancels the output starting at `first_id`.
+pub fn button(first_id: MessageId, user_id: UserId, locale: &str) -> CreateButton {
+    CreateButton::new(build_id(first_id, user_id))
+        .style(ButtonStyle::Danger)
+        .label(localization::text(locale, "Cancel"))
 }
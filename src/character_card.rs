@@ -0,0 +1,301 @@
+use anyhow::Context as _;
+use base64::Engine as _;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{ExampleMessage, ExampleRole};
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+/// The keyword of the PNG text chunk holding a V2 card.
+const PNG_KEYWORD: &str = "chara";
+/// The keyword of the PNG text chunk holding a V3 card, which is a superset of V2.
+const PNG_KEYWORD_V3: &str = "ccv3";
+/// Marks the start of each example conversation in `mes_example`.
+const EXAMPLE_SEPARATOR: &str = "<START>";
+const CHAR_MACRO: &str = "{{char}}";
+const USER_MACRO: &str = "{{user}}";
+/// What `{{user}}` is replaced with, as the user isn't known when a card is imported.
+const USER_NAME: &str = "User";
+
+/// A character card in the Character Card V2 format used by many roleplay frontends,
+/// which can be imported as a persona. Cards are JSON, either on their own or embedded
+/// as base64 in a `tEXt` chunk of a PNG. Only the fields that map onto a persona are kept.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(default)]
+pub struct CharacterCard {
+    pub name: String,
+    pub description: String,
+    pub personality: String,
+    pub scenario: String,
+    pub first_mes: String,
+    pub mes_example: String,
+    pub system_prompt: String,
+    pub creator_notes: String,
+}
+impl CharacterCard {
+    /// Parses a card from JSON or a PNG with an embedded card. Both V2 cards and
+    /// the older format without the `data` wrapper are accepted.
+    pub fn parse(bytes: &[u8]) -> anyhow::Result<Self> {
+        let json = if bytes.starts_with(PNG_SIGNATURE) {
+            let text = png_text_chunk(bytes, PNG_KEYWORD)
+                .or_else(|| png_text_chunk(bytes, PNG_KEYWORD_V3))
+                .context("this PNG does not contain a character card")?;
+            base64::engine::general_purpose::STANDARD
+                .decode(text.trim())
+                .context("the character card in this PNG is not valid base64")?
+        } else {
+            bytes.to_vec()
+        };
+
+        let mut value: serde_json::Value =
+            serde_json::from_slice(&json).context("the character card is not valid JSON")?;
+        if let Some(data) = value.get_mut("data") {
+            value = data.take();
+        }
+        let card: Self =
+            serde_json::from_value(value).context("the character card is not valid")?;
+        if card.name.trim().is_empty() {
+            anyhow::bail!("the character card has no name");
+        }
+        Ok(card)
+    }
+
+    /// Serializes the card as V2 JSON.
+    pub fn to_json(&self) -> anyhow::Result<String> {
+        Ok(serde_json::to_string_pretty(&serde_json::json!({
+            "spec": "chara_card_v2",
+            "spec_version": "2.0",
+            "data": {
+                "name": self.name,
+                "description": self.description,
+                "personality": self.personality,
+                "scenario": self.scenario,
+                "first_mes": self.first_mes,
+                "mes_example": self.mes_example,
+                "system_prompt": self.system_prompt,
+                "creator_notes": self.creator_notes,
+                "post_history_instructions": "",
+                "alternate_greetings": [],
+                "tags": [],
+                "creator": "",
+                "character_version": "",
+                "extensions": {},
+            },
+        }))?)
+    }
+
+    /// Combines the card's description, personality, scenario and system prompt into
+    /// a single system prompt.
+    pub fn persona_system_prompt(&self) -> String {
+        let sections = [
+            self.system_prompt.trim().to_string(),
+            self.description.trim().to_string(),
+            match self.personality.trim() {
+                "" => String::new(),
+                personality => format!("{}'s personality: {personality}", self.name),
+            },
+            match self.scenario.trim() {
+                "" => String::new(),
+                scenario => format!("Scenario: {scenario}"),
+            },
+        ];
+        let system_prompt = sections
+            .into_iter()
+            .filter(|s| !s.is_empty())
+            .collect::<Vec<_>>()
+            .join("\n\n");
+        self.replace_macros(&system_prompt)
+    }
+
+    /// The example dialogue as alternating turns.
+    pub fn persona_examples(&self) -> Vec<ExampleMessage> {
+        let mut examples: Vec<ExampleMessage> = vec![];
+        for conversation in self.mes_example.split(EXAMPLE_SEPARATOR) {
+            let mut current: Option<ExampleMessage> = None;
+            for line in conversation.lines() {
+                let trimmed = line.trim_start();
+                let turn = if let Some(rest) = trimmed.strip_prefix(USER_MACRO) {
+                    rest.strip_prefix(':').map(|rest| (ExampleRole::User, rest))
+                } else if let Some(rest) = trimmed.strip_prefix(CHAR_MACRO) {
+                    rest.strip_prefix(':')
+                        .map(|rest| (ExampleRole::Assistant, rest))
+                } else {
+                    None
+                };
+
+                match (turn, &mut current) {
+                    (Some((role, content)), _) => {
+                        examples.extend(current.take());
+                        current = Some(ExampleMessage {
+                            role,
+                            content: content.trim().to_string(),
+                        });
+                    }
+                    (None, Some(message)) => {
+                        message.content.push('\n');
+                        message.content.push_str(line);
+                    }
+                    (None, None) => {}
+                }
+            }
+            examples.extend(current);
+        }
+
+        for example in &mut examples {
+            example.content = self.replace_macros(example.content.trim());
+        }
+        examples.retain(|e| !e.content.is_empty());
+        examples
+    }
+
+    /// The message the character opens with, if any.
+    pub fn persona_greeting(&self) -> Option<String> {
+        Some(self.replace_macros(self.first_mes.trim())).filter(|g| !g.is_empty())
+    }
+
+    /// Builds a card from the parts of a persona.
+    pub fn from_persona(
+        name: &str,
+        system_prompt: &str,
+        examples: &[ExampleMessage],
+        greeting: Option<&str>,
+    ) -> Self {
+        let mut mes_example = String::new();
+        for example in examples {
+            if example.role == ExampleRole::User || mes_example.is_empty() {
+                mes_example.push_str(EXAMPLE_SEPARATOR);
+                mes_example.push('\n');
+            }
+            let speaker = match example.role {
+                ExampleRole::User => USER_MACRO,
+                ExampleRole::Assistant => CHAR_MACRO,
+            };
+            mes_example.push_str(&format!("{speaker}: {}\n", example.content));
+        }
+
+        Self {
+            name: name.to_string(),
+            description: system_prompt.to_string(),
+            first_mes: greeting.unwrap_or_default().to_string(),
+            mes_example: mes_example.trim_end().to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn replace_macros(&self, text: &str) -> String {
+        text.replace(CHAR_MACRO, &self.name)
+            .replace(USER_MACRO, USER_NAME)
+    }
+}
+
+/// Finds the text of the `tEXt` chunk with the given keyword in a PNG.
+fn png_text_chunk(bytes: &[u8], keyword: &str) -> Option<String> {
+    let mut rest = bytes.strip_prefix(PNG_SIGNATURE)?;
+    // Each chunk is a big-endian length, a four-byte type, the data and a CRC
+    while rest.len() >= 12 {
+        let length = u32::from_be_bytes(rest[0..4].try_into().ok()?) as usize;
+        let kind = &rest[4..8];
+        let data = rest.get(8..8 + length)?;
+        if kind == b"tEXt" {
+            if let Some((chunk_keyword, text)) = data
+                .iter()
+                .position(|&b| b == 0)
+                .map(|i| (&data[..i], &data[i + 1..]))
+            {
+                if chunk_keyword == keyword.as_bytes() {
+                    // tEXt chunks are Latin-1, but the card is base64 and so ASCII
+                    return Some(String::from_utf8_lossy(text).into_owned());
+                }
+            }
+        } else if kind == b"IEND" {
+            break;
+        }
+        rest = rest.get(8 + length + 4..)?;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn png_with_text(keyword: &str, text: &str) -> Vec<u8> {
+        let mut png = PNG_SIGNATURE.to_vec();
+        for (kind, data) in [
+            (&b"IHDR"[..], vec![0; 13]),
+            (
+                b"tEXt",
+                [keyword.as_bytes(), &[0], text.as_bytes()].concat(),
+            ),
+            (b"IEND", vec![]),
+        ] {
+            png.extend((data.len() as u32).to_be_bytes());
+            png.extend(kind);
+            png.extend(&data);
+            png.extend([0; 4]);
+        }
+        png
+    }
+
+    #[test]
+    fn test_parse() {
+        let v2 = r#"{"spec": "chara_card_v2", "data": {"name": "Alice", "description": "A cat."}}"#;
+        let card = CharacterCard::parse(v2.as_bytes()).unwrap();
+        assert_eq!(card.name, "Alice");
+        assert_eq!(card.description, "A cat.");
+
+        // Older cards have no `data` wrapper
+        let v1 = r#"{"name": "Bob", "personality": "Grumpy"}"#;
+        let card = CharacterCard::parse(v1.as_bytes()).unwrap();
+        assert_eq!(card.name, "Bob");
+        assert_eq!(card.personality, "Grumpy");
+
+        let png = png_with_text(
+            PNG_KEYWORD,
+            &base64::engine::general_purpose::STANDARD.encode(v2),
+        );
+        assert_eq!(CharacterCard::parse(&png).unwrap().name, "Alice");
+
+        assert!(CharacterCard::parse(&png_with_text("Comment", "hello")).is_err());
+        assert!(CharacterCard::parse(br#"{"description": "Nameless"}"#).is_err());
+    }
+
+    #[test]
+    fn test_persona_examples() {
+        let card = CharacterCard {
+            name: "Alice".to_string(),
+            mes_example: "<START>\n{{user}}: Hi!\n{{char}}: Hello, {{user}}.\nI'm {{char}}.\n<START>\n{{user}}: Bye.".to_string(),
+            ..Default::default()
+        };
+        let examples: Vec<_> = card
+            .persona_examples()
+            .into_iter()
+            .map(|e| (e.role, e.content))
+            .collect();
+        assert_eq!(
+            examples,
+            vec![
+                (ExampleRole::User, "Hi!".to_string()),
+                (
+                    ExampleRole::Assistant,
+                    "Hello, User.\nI'm Alice.".to_string()
+                ),
+                (ExampleRole::User, "Bye.".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let card = CharacterCard {
+            name: "Alice".to_string(),
+            mes_example: "<START>\n{{user}}: Hi!\n{{char}}: Hello.\n<START>\n{{user}}: Bye.\n{{char}}: Farewell.".to_string(),
+            ..Default::default()
+        };
+        let exported = CharacterCard::from_persona("Alice", "", &card.persona_examples(), None);
+        assert_eq!(exported.mes_example, card.mes_example);
+        assert_eq!(
+            CharacterCard::parse(exported.to_json().unwrap().as_bytes()).unwrap(),
+            exported
+        );
+    }
+}
@@ -0,0 +1,626 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::Context;
+use async_openai::types::CreateChatCompletionRequestArgs;
+use serenity::{
+    all::{
+        ChannelId, Colour, CommandInteraction, CommandOptionType, CreateActionRow,
+        CreateAllowedMentions, CreateAttachment, CreateCommand, CreateCommandOption, CreateEmbed,
+        CreateInteractionResponse, CreateInteractionResponseMessage, EditInteractionResponse, Http,
+        MessageId,
+    },
+    futures::{StreamExt, stream},
+};
+
+use crate::{
+    ai::Ai,
+    cancel, config, constant,
+    database::Database,
+    localization, mentions,
+    moderation::{Flag, Policy, StreamCheck, Verdict},
+    personas::{self, Persona},
+    preferences::Preferences,
+    queue, reasoning, review, util, votes,
+};
+
+use super::{CommandHandler, hallucinate};
+
+/// The most models that can be compared at once.
+const MAX_MODELS: usize = 4;
+/// The fewest models that can be compared at once.
+const MIN_MODELS: usize = 2;
+/// Discord limits the total length of a message's embeds to 6000 characters; this leaves
+/// room for the titles.
+const EMBEDS_SIZE: usize = 5600;
+/// Embed descriptions can be up to 4096 characters.
+const EMBED_DESCRIPTION_SIZE: usize = 4000;
+/// The amount of the prompt to show above the responses.
+const PROMPT_PREVIEW_SIZE: usize = 1500;
+
+/// Sends one prompt to several models at once with the same seed, showing their
+/// responses side by side with buttons to vote for the best one. The votes are saved,
+/// so that the team can see which models are preferred.
+pub struct Handler {
+    /// The enabled configured commands whose prompts can be used for the comparison,
+    /// which are those without required options
+    commands: Vec<(String, config::Command)>,
+    discord_config: config::Discord,
+    cancel_rx: flume::Receiver<MessageId>,
+    ai: Arc<Ai>,
+    database: Arc<Database>,
+    held: Arc<review::Store>,
+}
+impl Handler {
+    pub fn new(
+        commands: &HashMap<String, config::Command>,
+        discord_config: config::Discord,
+        cancel_rx: flume::Receiver<MessageId>,
+        ai: Arc<Ai>,
+        database: Arc<Database>,
+        held: Arc<review::Store>,
+    ) -> Self {
+        // `/compare` can't take each command's own options, so commands that require them
+        // are left out, and the rest are run without them
+        let mut commands: Vec<_> = commands
+            .iter()
+            .filter(|(_, command)| {
+                command.enabled && !command.options.values().any(|option| option.required)
+            })
+            .map(|(name, command)| (name.clone(), command.clone()))
+            .collect();
+        commands.sort_by(|a, b| a.0.cmp(&b.0));
+
+        Self {
+            commands,
+            discord_config,
+            cancel_rx,
+            ai,
+            database,
+            held,
+        }
+    }
+}
+#[serenity::async_trait]
+impl CommandHandler for Handler {
+    fn name(&self) -> &str {
+        constant::commands::COMPARE
+    }
+
+    async fn register(&self, http: &Http) -> anyhow::Result<()> {
+        if self.commands.is_empty() {
+            return Ok(());
+        }
+
+        let mut command_option = CreateCommandOption::new(
+            CommandOptionType::String,
+            constant::value::COMMAND,
+            "The command whose prompts to use.",
+        )
+        .required(true);
+        for (name, _) in &self.commands {
+            command_option = command_option.add_string_choice(name, name);
+        }
+
+        let mut command = CreateCommand::new(constant::commands::COMPARE)
+            .description("Compares the responses of several models to the same prompt.")
+            .add_option(command_option)
+            .add_option(
+                CreateCommandOption::new(
+                    CommandOptionType::String,
+                    constant::value::PROMPT,
+                    "The prompt.",
+                )
+                .required(true),
+            );
+        for i in 1..=MAX_MODELS {
+            let mut model_option = CreateCommandOption::new(
+                CommandOptionType::String,
+                model_option_name(i),
+                format!("Model #{i} to compare."),
+            )
+            .required(i <= MIN_MODELS);
+            for model in &self.ai.models {
+                model_option = model_option.add_string_choice(model, model);
+            }
+            command = command.add_option(model_option);
+        }
+        command = command.add_option(
+            CreateCommandOption::new(
+                CommandOptionType::Integer,
+                constant::value::SEED,
+                "The seed to use for sampling.",
+            )
+            .min_int_value(0)
+            .required(false),
+        );
+        command = command.add_option(
+            CreateCommandOption::new(
+                CommandOptionType::String,
+                constant::value::PERSONA,
+                "The name of a persona from `/persona` to use instead of the system prompt.",
+            )
+            .max_length(personas::MAX_NAME_LENGTH)
+            .required(false),
+        );
+
+        localization::register(http, command).await?;
+
+        Ok(())
+    }
+
+    async fn run(&self, http: &Http, cmd: &CommandInteraction) -> anyhow::Result<()> {
+        use constant::value as v;
+        use util::{value_to_integer, value_to_string};
+
+        let options = &cmd.data.options;
+        let command_name = util::get_value(options, v::COMMAND)
+            .and_then(value_to_string)
+            .context("no command specified")?;
+        let command = self
+            .commands
+            .iter()
+            .find(|(name, _)| *name == command_name)
+            .map(|(_, command)| command)
+            .with_context(|| format!("unknown command `{command_name}`"))?;
+
+        let prompt = util::get_value(options, v::PROMPT)
+            .and_then(value_to_string)
+            .context("no prompt specified")?;
+        let prompt = if self.discord_config.replace_newlines {
+            prompt.replace("\\n", "\n")
+        } else {
+            prompt
+        };
+
+        let seed = util::get_value(options, v::SEED)
+            .and_then(value_to_integer)
+            .map(|i| i as u32)
+            .unwrap_or(0);
+
+        let models: Vec<String> = (1..=MAX_MODELS)
+            .filter_map(|i| {
+                util::get_value(options, &model_option_name(i)).and_then(value_to_string)
+            })
+            .collect();
+        if models.len() < MIN_MODELS {
+            anyhow::bail!("at least {MIN_MODELS} models must be specified");
+        }
+
+        // Personas and preferences apply as they do when running the command itself
+        let persona = match util::get_value(options, v::PERSONA).and_then(value_to_string) {
+            Some(name) => Some(
+                Persona::find(&self.database, cmd.user.id, cmd.guild_id, &name)?
+                    .with_context(|| format!("unknown persona `{name}`; see `/persona list`"))?,
+            ),
+            None => None,
+        };
+        let preferences = Preferences::load(&self.database, cmd.user.id)?;
+        let temperature = persona
+            .as_ref()
+            .and_then(|p| p.temperature)
+            .or(preferences.temperature);
+
+        // Make mentions, emoji and timestamps readable for the models
+        let prompt = mentions::resolve(http, cmd.guild_id, &prompt).await;
+
+        let mut variables = HashMap::from_iter([(v::PROMPT.to_string(), prompt.clone())]);
+        if let Some(language) = preferences.language {
+            variables.entry(v::LANGUAGE.to_string()).or_insert(language);
+        }
+        command.fill_missing_options(&mut variables);
+        let mut system_prompt = util::render_template(
+            persona
+                .as_ref()
+                .map_or(&command.system_prompt, |p| &p.system_prompt),
+            &variables,
+        );
+        let mut user_message = match &command.prompt_template {
+            Some(template) => util::render_template(template, &variables),
+            None => prompt.clone(),
+        };
+
+        // As with the command itself, personas are user-written and so are moderated too
+        let moderator = &self.ai.moderator;
+        let policy = moderator.policy(cmd.guild_id);
+        let moderated_prompt = if persona.is_some() {
+            format!("{system_prompt}\n\n{user_message}")
+        } else {
+            user_message.clone()
+        };
+        let mut held = None;
+        match moderator.judge(cmd.guild_id, &moderated_prompt).await? {
+            None => {
+                user_message = policy.redact(&user_message).into_owned();
+                if persona.is_some() {
+                    system_prompt = policy.redact(&system_prompt).into_owned();
+                }
+            }
+            Some(Verdict::Block(flag)) => {
+                anyhow::bail!("your prompt was blocked by moderation ({})", flag.reason)
+            }
+            Some(Verdict::Hold(channel_id, flag)) => held = Some((channel_id, flag)),
+        }
+
+        let examples = match &persona {
+            Some(persona) if !persona.examples.is_empty() || persona.greeting.is_some() => {
+                persona.example_messages()
+            }
+            _ => command.examples.clone(),
+        };
+        let messages = hallucinate::create_messages(
+            &system_prompt,
+            &examples,
+            &variables,
+            user_message.into(),
+        );
+
+        let details = format!(
+            "-# {}",
+            localization::format(
+                &cmd.locale,
+                "Comparing with {{command}} and seed {{seed}}",
+                &[
+                    ("command", &format!("`/{command_name}`")),
+                    ("seed", &seed.to_string()),
+                ],
+            )
+        );
+        let header = format!(
+            "**{}**\n{details}",
+            util::truncate(&policy.redact(&prompt), PROMPT_PREVIEW_SIZE)
+        );
+        // Nothing but the details is shown while the comparison is held for review
+        let held_notice = format!(
+            "{details}\n{}",
+            localization::text(&cmd.locale, review::HELD_MESSAGE)
+        );
+        let show = |responses: &[Response], held: bool, finished: bool| {
+            if held {
+                EditInteractionResponse::new()
+                    .content(&held_notice)
+                    .embeds(vec![])
+            } else {
+                EditInteractionResponse::new()
+                    .content(&header)
+                    .embeds(create_embeds(
+                        responses,
+                        command.hide_reasoning,
+                        policy,
+                        &cmd.locale,
+                        finished,
+                    ))
+            }
+        };
+
+        cmd.create_response(
+            http,
+            CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new()
+                    .content(if held.is_some() {
+                        &held_notice
+                    } else {
+                        &header
+                    })
+                    .allowed_mentions(CreateAllowedMentions::new()),
+            ),
+        )
+        .await?;
+        let message_id = cmd.get_response(http).await?.id;
+
+        let mut responses: Vec<_> = models
+            .iter()
+            .map(String::as_str)
+            .map(|model| Response::new(model, self.ai.has_implicit_think_tag(model)))
+            .collect();
+        let mut checks: Vec<_> = models
+            .iter()
+            .map(|_| moderator.stream_check(cmd.guild_id))
+            .collect();
+        let mut streams = vec![];
+        let mut queued = false;
+        for (i, model) in models.iter().enumerate() {
+            let mut request = CreateChatCompletionRequestArgs::default();
+            request
+                .model(model)
+                .seed(seed)
+                .messages(messages.clone())
+                .stream(true);
+            if let Some(temperature) = temperature {
+                request.temperature(temperature);
+            }
+            let request = request.build()?;
+            let mut job = self.ai.queue.join(message_id, cmd.user.id, model);
+            queued |= job.status() != queue::Status::Running;
+
+            // Each model starts once it has had its turn, and holds it until it's finished
+            let ai = self.ai.clone();
+            let model_stream = stream::once(async move {
+                if !job.wait().await {
+                    anyhow::bail!("cancelled while queued");
+                }
+                let stream = ai.create_chat_stream(request).await?;
+                anyhow::Ok(stream.map(move |response| {
+                    let _job = &job;
+                    response.map_err(anyhow::Error::from)
+                }))
+            })
+            .flat_map(|result| match result {
+                Ok(stream) => stream.boxed(),
+                Err(err) => stream::once(async { Err(err) }).boxed(),
+            });
+            streams.push(model_stream.map(move |response| (i, response)).boxed());
+        }
+        if queued {
+            // Nothing is streamed while waiting, so show the cancel button straight away
+            cmd.edit_response(
+                http,
+                show(&responses, held.is_some(), false).components(vec![CreateActionRow::Buttons(
+                    vec![cancel::button(message_id, cmd.user.id, &cmd.locale)],
+                )]),
+            )
+            .await?;
+        }
+
+        let update_interval =
+            std::time::Duration::from_millis(self.discord_config.message_update_interval_ms);
+        let mut last_update = std::time::Instant::now();
+        let mut stream = stream::select_all(streams);
+        while let Some((i, stream_response)) = stream.next().await {
+            if let Ok(cancel_message_id) = self.cancel_rx.try_recv() {
+                if cancel_message_id == message_id {
+                    let edit = show(&responses, held.is_some(), true);
+                    let content = if held.is_some() {
+                        &held_notice
+                    } else {
+                        &header
+                    };
+                    cmd.edit_response(
+                        http,
+                        edit.content(format!(
+                            "{content}\n{}",
+                            localization::text(&cmd.locale, "The comparison was cancelled.")
+                        ))
+                        .components(vec![]),
+                    )
+                    .await?;
+                    return Ok(());
+                }
+            }
+
+            let response = &mut responses[i];
+            if response.error.is_some() {
+                continue;
+            }
+            match stream_response {
+                Ok(stream_response) => {
+                    let Some(choice) = stream_response.choices.first() else {
+                        continue;
+                    };
+                    if let Some(reasoning) = &choice.delta.reasoning_content {
+                        response.reasoning += reasoning;
+                    }
+                    if let Some(content) = &choice.delta.inner.content {
+                        response.message += content;
+                    }
+                }
+                Err(err) => response.error = Some(err.to_string()),
+            }
+            moderate(&mut checks[i], response, &mut held, &cmd.locale, false).await?;
+
+            if last_update.elapsed() > update_interval {
+                cmd.edit_response(
+                    http,
+                    show(&responses, held.is_some(), false).components(vec![
+                        CreateActionRow::Buttons(vec![cancel::button(
+                            message_id,
+                            cmd.user.id,
+                            &cmd.locale,
+                        )]),
+                    ]),
+                )
+                .await?;
+                last_update = std::time::Instant::now();
+            }
+        }
+        for (response, check) in responses.iter_mut().zip(&mut checks) {
+            moderate(check, response, &mut held, &cmd.locale, true).await?;
+        }
+
+        if let Some(held) = held {
+            cmd.edit_response(http, show(&responses, true, true).components(vec![]))
+                .await?;
+            let output = format!("{header}\n\n{}", full_responses(&responses, policy));
+            let guild_locale = cmd
+                .guild_locale
+                .as_deref()
+                .unwrap_or(localization::DEFAULT_LOCALE);
+            let source = localization::format(
+                guild_locale,
+                "{{command}} with {{base}} by {{user}} in {{channel}}",
+                &[
+                    ("command", &format!("`/{}`", constant::commands::COMPARE)),
+                    ("base", &format!("`/{command_name}`")),
+                    ("user", &format!("<@{}>", cmd.user.id)),
+                    ("channel", &format!("<#{}>", cmd.channel_id)),
+                ],
+            );
+            return review::submit(
+                http,
+                &self.held,
+                held,
+                (guild_locale, &source),
+                message_id,
+                &moderated_prompt,
+                review::Held {
+                    recipient: review::Recipient::Channel {
+                        channel_id: cmd.channel_id,
+                        reply_to: Some(message_id),
+                    },
+                    output,
+                },
+            )
+            .await;
+        }
+
+        let ballot =
+            votes::Ballot::create(&self.database, message_id, &command_name, &prompt, models)?;
+        let mut edit = show(&responses, false, true).components(vec![ballot.buttons()]);
+        // Attach the full responses if any of them were cut short
+        if responses
+            .iter()
+            .any(|r| r.answer().len() > embed_description_size(responses.len()))
+        {
+            edit = edit.new_attachment(CreateAttachment::bytes(
+                full_responses(&responses, policy).into_bytes(),
+                "comparison.md",
+            ));
+        }
+        cmd.edit_response(http, edit).await?;
+
+        Ok(())
+    }
+}
+
+fn model_option_name(index: usize) -> String {
+    format!("{}_{index}", constant::value::MODEL)
+}
+
+/// The amount of each response that can be shown, given the number of responses.
+fn embed_description_size(count: usize) -> usize {
+    (EMBEDS_SIZE / count).min(EMBED_DESCRIPTION_SIZE)
+}
+
+fn create_embeds(
+    responses: &[Response],
+    hide_reasoning: bool,
+    policy: &Policy,
+    locale: &str,
+    finished: bool,
+) -> Vec<CreateEmbed> {
+    let size = embed_description_size(responses.len());
+    responses
+        .iter()
+        .map(|r| r.embed(size, hide_reasoning, policy, locale, finished))
+        .collect()
+}
+
+/// Every response in full, one after the other.
+fn full_responses(responses: &[Response], policy: &Policy) -> String {
+    responses
+        .iter()
+        .map(|r| format!("## {}\n\n{}\n\n", r.model, policy.redact(r.answer().trim())))
+        .collect()
+}
+
+/// Checks a response with moderation, blocking it or holding the whole comparison for
+/// review if needed.
+async fn moderate(
+    check: &mut StreamCheck<'_>,
+    response: &mut Response,
+    held: &mut Option<(ChannelId, Flag)>,
+    locale: &str,
+    finished: bool,
+) -> anyhow::Result<()> {
+    if held.is_some() || response.error.is_some() {
+        return Ok(());
+    }
+    match check.check(response.answer(), finished).await? {
+        None => {}
+        Some(Verdict::Block(flag)) => {
+            response.message.clear();
+            response.reasoning.clear();
+            response.error = Some(localization::format(
+                locale,
+                "The response was blocked by moderation ({{reason}}).",
+                &[("reason", &flag.reason)],
+            ));
+        }
+        Some(Verdict::Hold(channel_id, flag)) => *held = Some((channel_id, flag)),
+    }
+    Ok(())
+}
+
+/// The response of one of the models being compared.
+struct Response {
+    model: String,
+    /// Whether or not the model's output starts inside a `<think>` block
+    implicit_think_tag: bool,
+    /// The reasoning streamed separately from the content, if any
+    reasoning: String,
+    message: String,
+    error: Option<String>,
+}
+impl Response {
+    fn new(model: &str, implicit_think_tag: bool) -> Self {
+        Self {
+            model: model.to_string(),
+            implicit_think_tag,
+            reasoning: String::new(),
+            message: String::new(),
+            error: None,
+        }
+    }
+
+    /// The response without any reasoning.
+    fn answer(&self) -> &str {
+        reasoning::split(&self.message, self.implicit_think_tag).1
+    }
+
+    fn embed(
+        &self,
+        max_length: usize,
+        hide_reasoning: bool,
+        policy: &Policy,
+        locale: &str,
+        finished: bool,
+    ) -> CreateEmbed {
+        /// Embed titles can be up to 256 characters.
+        const TITLE_SIZE: usize = 256;
+        /// The amount of an error to show.
+        const ERROR_SIZE: usize = 200;
+        /// The amount of the reasoning to show while thinking, as a fraction of the space.
+        const REASONING_FRACTION: usize = 4;
+
+        let (inline_reasoning, answer) = reasoning::split(&self.message, self.implicit_think_tag);
+        let reasoning = if self.reasoning.is_empty() {
+            inline_reasoning
+        } else {
+            self.reasoning.trim()
+        };
+        let answer = policy.redact(answer.trim());
+        let description = if !answer.is_empty() {
+            match util::truncate(&answer, max_length) {
+                truncated if truncated.len() < answer.len() => format!("{truncated}…"),
+                answer => answer.to_string(),
+            }
+        } else if finished {
+            format!("*{}*", localization::text(locale, "No response."))
+        } else if !reasoning.is_empty() && !hide_reasoning {
+            format!(
+                "*{}*\n||{}||",
+                localization::text(locale, "Thinking..."),
+                reasoning::tail(reasoning, max_length / REASONING_FRACTION)
+            )
+        } else if !reasoning.is_empty() {
+            format!("*{}*", localization::text(locale, "Thinking..."))
+        } else {
+            format!("*{}*", localization::text(locale, "Waiting..."))
+        };
+
+        let embed = CreateEmbed::new().title(util::truncate(&self.model, TITLE_SIZE));
+        match &self.error {
+            Some(error) => embed
+                .description(format!(
+                    "{}\n{}",
+                    util::truncate(&description, max_length.saturating_sub(ERROR_SIZE + 40)),
+                    localization::format(
+                        locale,
+                        "**Error:** {{error}}",
+                        &[("error", util::truncate(error, ERROR_SIZE))]
+                    )
+                ))
+                .colour(Colour::RED),
+            None => embed.description(description),
+        }
+    }
+}
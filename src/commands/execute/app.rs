@@ -1,6 +1,6 @@
-use serenity::all::{Command, CommandInteraction, CommandType, CreateCommand, Http};
+use serenity::all::{CommandInteraction, CommandType, CreateCommand, Http};
 
-use crate::constant;
+use crate::{constant, localization, outputter::Destination};
 
 use crate::commands::CommandHandler;
 
@@ -19,7 +19,7 @@ impl CommandHandler for Handler {
     }
 
     async fn register(&self, http: &Http) -> anyhow::Result<()> {
-        Command::create_global_command(
+        localization::register(
             http,
             CreateCommand::new(constant::commands::EXECUTE_THIS_CODE_BLOCK)
                 .kind(CommandType::Message),
@@ -34,6 +34,8 @@ impl CommandHandler for Handler {
             anyhow::bail!("no message found");
         };
 
-        self.base.run(http, cmd, unparsed_code).await
+        self.base
+            .run(http, Destination::Interaction(cmd), unparsed_code)
+            .await
     }
 }
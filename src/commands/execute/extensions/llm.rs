@@ -1,15 +1,31 @@
-use std::sync::Arc;
+use std::sync::{
+    Arc,
+    atomic::{AtomicBool, Ordering},
+};
 
 use async_openai::types::{
-    ChatCompletionRequestAssistantMessage, ChatCompletionRequestMessage,
-    ChatCompletionRequestSystemMessage, ChatCompletionRequestUserMessage,
+    ChatCompletionRequestAssistantMessage, ChatCompletionRequestAssistantMessageContent,
+    ChatCompletionRequestMessage, ChatCompletionRequestSystemMessage,
+    ChatCompletionRequestSystemMessageContent, ChatCompletionRequestUserMessage,
+    ChatCompletionRequestUserMessageContent, ChatCompletionRequestUserMessageContentPart,
     CreateChatCompletionRequestArgs,
 };
-use serenity::futures::StreamExt as _;
+use serenity::{
+    all::{GuildId, MessageId, UserId},
+    futures::StreamExt as _,
+};
 
-use crate::ai::Ai;
+use crate::{ai::Ai, attachments, moderation::Verdict, queue, reasoning};
 
-pub fn register(lua: &mlua::Lua, ai: Arc<Ai>) -> mlua::Result<()> {
+/// Registers the `llm` module. Requests take turns in the queue as `user_id`; see [`Turns`].
+pub fn register(
+    lua: &mlua::Lua,
+    ai: Arc<Ai>,
+    guild_id: Option<GuildId>,
+    key: MessageId,
+    user_id: UserId,
+) -> mlua::Result<()> {
+    let turns = Turns::new(ai.queue.clone(), key, user_id);
     let llm = lua.create_table()?;
     llm.set("models", ai.models.clone())?;
 
@@ -17,29 +33,75 @@ pub fn register(lua: &mlua::Lua, ai: Arc<Ai>) -> mlua::Result<()> {
     register_message(lua, &llm, "user")?;
     register_message(lua, &llm, "assistant")?;
 
+    llm.set(
+        "image",
+        lua.create_function(|lua, url: String| {
+            let output = lua.create_table()?;
+            output.set("type", "image_url")?;
+            output.set("url", url)?;
+            Ok(output)
+        })?,
+    )?;
+
     llm.set(
         "by_token",
         lua.create_async_function({
-            let client = ai.client.clone();
+            let ai = ai.clone();
+            let turns = turns.clone();
             move |_lua, args: mlua::Table| {
-                let client = client.clone();
+                let ai = ai.clone();
+                let turns = turns.clone();
                 async move {
-                    let (model, seed, messages, callback) = parse_llm_args(&args)?;
+                    let (model, seed, mut messages, callback) = parse_llm_args(&ai, &args)?;
                     let callback = callback.expect("by_token requires a callback");
+                    moderate_messages(&ai, guild_id, &mut messages).await?;
+                    let implicit_think_tag = ai.has_implicit_think_tag(&model);
 
-                    let mut stream = create_chat_stream(&client, &model, seed, messages).await?;
+                    let _turn = turns.take(&model).await?;
+                    let mut stream = create_chat_stream(&ai.client, &model, seed, messages).await?;
 
+                    let mut check = ai.moderator.stream_check(guild_id);
+                    let mut output = String::new();
+                    // The end of the output that has been passed to the callback
+                    let mut passed = 0;
                     while let Some(response) = stream.next().await {
                         let Ok(response) = response else { continue };
                         let Some(content) = &response.choices[0].delta.content else {
                             continue;
                         };
-                        let value = callback.call::<mlua::Value>(content.clone())?;
+                        output.push_str(content);
+                        // Reasoning is excluded, as with `llm.stream` and `llm.response`
+                        let (_, answer) = reasoning::split(&output, implicit_think_tag);
+                        if let Some(verdict) = check
+                            .check(answer, false)
+                            .await
+                            .map_err(mlua::Error::external)?
+                        {
+                            return Err(blocked_error("the response", &verdict));
+                        }
+                        // Pass on the part of the answer that's new, which may include earlier
+                        // tokens if they turned out not to be the start of a reasoning block
+                        let start = (output.len() - answer.len()).max(passed);
+                        if start == output.len() {
+                            continue;
+                        }
+                        let token = &output[start..];
+                        passed = output.len();
+                        // Only blocked words that arrive within a single token can be redacted
+                        let value =
+                            callback.call::<mlua::Value>(check.redact(token).into_owned())?;
                         if value.as_boolean().is_some_and(|b| !b) {
                             // Allow the user to cancel the stream by returning false
                             break;
                         }
                     }
+                    if let Some(verdict) = check
+                        .check(reasoning::split(&output, implicit_think_tag).1, true)
+                        .await
+                        .map_err(mlua::Error::external)?
+                    {
+                        return Err(blocked_error("the response", &verdict));
+                    }
 
                     Ok(())
                 }
@@ -50,15 +112,21 @@ pub fn register(lua: &mlua::Lua, ai: Arc<Ai>) -> mlua::Result<()> {
     llm.set(
         "stream",
         lua.create_async_function({
-            let client = ai.client.clone();
+            let ai = ai.clone();
+            let turns = turns.clone();
             move |_lua, args: mlua::Table| {
-                let client = client.clone();
+                let ai = ai.clone();
+                let turns = turns.clone();
                 async move {
-                    let (model, seed, messages, callback) = parse_llm_args(&args)?;
+                    let (model, seed, mut messages, callback) = parse_llm_args(&ai, &args)?;
                     let callback = callback.expect("stream requires a callback");
+                    moderate_messages(&ai, guild_id, &mut messages).await?;
+                    let implicit_think_tag = ai.has_implicit_think_tag(&model);
 
-                    let mut stream = create_chat_stream(&client, &model, seed, messages).await?;
+                    let _turn = turns.take(&model).await?;
+                    let mut stream = create_chat_stream(&ai.client, &model, seed, messages).await?;
 
+                    let mut check = ai.moderator.stream_check(guild_id);
                     let mut output = String::new();
 
                     while let Some(response) = stream.next().await {
@@ -67,12 +135,29 @@ pub fn register(lua: &mlua::Lua, ai: Arc<Ai>) -> mlua::Result<()> {
                             continue;
                         };
                         output.push_str(content);
-                        let value = callback.call::<mlua::Value>(output.clone())?;
+                        // Reasoning is excluded, so that the output can be used in conversations
+                        let (_, answer) = reasoning::split(&output, implicit_think_tag);
+                        if let Some(verdict) = check
+                            .check(answer, false)
+                            .await
+                            .map_err(mlua::Error::external)?
+                        {
+                            return Err(blocked_error("the response", &verdict));
+                        }
+                        let value =
+                            callback.call::<mlua::Value>(check.redact(answer).into_owned())?;
                         if value.as_boolean().is_some_and(|b| !b) {
                             // Allow the user to cancel the stream by returning false
                             break;
                         }
                     }
+                    if let Some(verdict) = check
+                        .check(reasoning::split(&output, implicit_think_tag).1, true)
+                        .await
+                        .map_err(mlua::Error::external)?
+                    {
+                        return Err(blocked_error("the response", &verdict));
+                    }
 
                     Ok(())
                 }
@@ -83,13 +168,19 @@ pub fn register(lua: &mlua::Lua, ai: Arc<Ai>) -> mlua::Result<()> {
     llm.set(
         "response",
         lua.create_async_function({
-            let client = ai.client.clone();
+            let ai = ai.clone();
+            let turns = turns.clone();
             move |_lua, args: mlua::Table| {
-                let client = client.clone();
+                let ai = ai.clone();
+                let turns = turns.clone();
                 async move {
-                    let (model, seed, messages, _) = parse_llm_args(&args)?;
+                    let (model, seed, mut messages, _) = parse_llm_args(&ai, &args)?;
+                    moderate_messages(&ai, guild_id, &mut messages).await?;
+                    let implicit_think_tag = ai.has_implicit_think_tag(&model);
 
-                    let response = client
+                    let turn = turns.take(&model).await?;
+                    let response = ai
+                        .client
                         .chat()
                         .create(
                             CreateChatCompletionRequestArgs::default()
@@ -101,8 +192,23 @@ pub fn register(lua: &mlua::Lua, ai: Arc<Ai>) -> mlua::Result<()> {
                         )
                         .await
                         .map_err(|e| mlua::Error::ExternalError(Arc::new(e)))?;
+                    drop(turn);
 
-                    Ok(response.choices[0].message.content.clone())
+                    let Some(content) = response.choices[0].message.content.as_deref() else {
+                        return Ok(None);
+                    };
+                    let (_, answer) = reasoning::split(content, implicit_think_tag);
+                    if let Some(verdict) = ai
+                        .moderator
+                        .judge(guild_id, answer)
+                        .await
+                        .map_err(mlua::Error::external)?
+                    {
+                        return Err(blocked_error("the response", &verdict));
+                    }
+                    Ok(Some(
+                        ai.moderator.policy(guild_id).redact(answer).into_owned(),
+                    ))
                 }
             }
         })?,
@@ -114,6 +220,7 @@ pub fn register(lua: &mlua::Lua, ai: Arc<Ai>) -> mlua::Result<()> {
 }
 
 fn parse_llm_args(
+    ai: &Ai,
     args: &mlua::Table,
 ) -> mlua::Result<(
     String,
@@ -139,9 +246,133 @@ fn parse_llm_args(
         .map(|table| from_message_table_to_message(table?))
         .collect::<mlua::Result<Vec<_>>>()?;
 
+    if !ai.supports_vision(&model) && messages.iter().any(has_image) {
+        return Err(mlua::Error::external(format!(
+            "`{model}` does not support images"
+        )));
+    }
+
     Ok((model, seed, messages, callback))
 }
 
+/// Checks the messages with moderation before they are sent, redacting them if the
+/// policy allows it.
+async fn moderate_messages(
+    ai: &Ai,
+    guild_id: Option<GuildId>,
+    messages: &mut [ChatCompletionRequestMessage],
+) -> mlua::Result<()> {
+    let text = messages
+        .iter_mut()
+        .flat_map(message_texts)
+        .map(|text| text.clone())
+        .collect::<Vec<_>>()
+        .join("\n\n");
+    if let Some(verdict) = ai
+        .moderator
+        .judge(guild_id, &text)
+        .await
+        .map_err(mlua::Error::external)?
+    {
+        return Err(blocked_error("the prompt", &verdict));
+    }
+
+    let policy = ai.moderator.policy(guild_id);
+    for text in messages.iter_mut().flat_map(message_texts) {
+        *text = policy.redact(text).into_owned();
+    }
+    Ok(())
+}
+
+/// The text in a message, for moderation.
+fn message_texts(message: &mut ChatCompletionRequestMessage) -> Vec<&mut String> {
+    match message {
+        ChatCompletionRequestMessage::System(ChatCompletionRequestSystemMessage {
+            content: ChatCompletionRequestSystemMessageContent::Text(text),
+            ..
+        }) => vec![text],
+        ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+            content, ..
+        }) => match content {
+            ChatCompletionRequestUserMessageContent::Text(text) => vec![text],
+            ChatCompletionRequestUserMessageContent::Array(parts) => parts
+                .iter_mut()
+                .filter_map(|part| match part {
+                    ChatCompletionRequestUserMessageContentPart::Text(part) => Some(&mut part.text),
+                    _ => None,
+                })
+                .collect(),
+        },
+        ChatCompletionRequestMessage::Assistant(ChatCompletionRequestAssistantMessage {
+            content: Some(ChatCompletionRequestAssistantMessageContent::Text(text)),
+            ..
+        }) => vec![text],
+        _ => vec![],
+    }
+}
+
+/// Content can't be held for review from Lua, so it's blocked instead.
+fn blocked_error(what: &str, verdict: &Verdict) -> mlua::Error {
+    mlua::Error::external(format!(
+        "{what} was blocked by moderation ({})",
+        verdict.flag().reason
+    ))
+}
+
+/// The turns in the queue taken by a script's `llm` requests, which are queued under `key`
+/// (the message the script's output is shown in) so that they can be cancelled with it.
+///
+/// Only one request can hold a turn at a time: a request made from a stream's callback would
+/// otherwise wait behind the stream calling it, forever if the model can only run one
+/// generation at once.
+#[derive(Clone)]
+struct Turns {
+    queue: Arc<queue::Queue>,
+    key: MessageId,
+    user_id: UserId,
+    taken: Arc<AtomicBool>,
+}
+impl Turns {
+    fn new(queue: Arc<queue::Queue>, key: MessageId, user_id: UserId) -> Self {
+        Self {
+            queue,
+            key,
+            user_id,
+            taken: Arc::default(),
+        }
+    }
+
+    /// Waits for a turn for `model`, which is held until the turn is dropped.
+    async fn take(&self, model: &str) -> mlua::Result<Turn> {
+        if self.taken.swap(true, Ordering::SeqCst) {
+            return Err(mlua::Error::external(
+                "`llm` can't be used while another `llm` request is in progress, such as from a stream's callback",
+            ));
+        }
+        let mut turn = Turn {
+            job: self.queue.join(self.key, self.user_id, model),
+            taken: self.taken.clone(),
+        };
+        if !turn.job.wait().await {
+            return Err(mlua::Error::external(
+                "the request was cancelled while waiting in the queue",
+            ));
+        }
+        Ok(turn)
+    }
+}
+
+/// A turn in the queue for one `llm` request.
+struct Turn {
+    job: queue::Job,
+    taken: Arc<AtomicBool>,
+}
+impl Drop for Turn {
+    fn drop(&mut self) {
+        self.taken.store(false, Ordering::SeqCst);
+    }
+}
+
 async fn create_chat_stream(
     client: &async_openai::Client<async_openai::config::OpenAIConfig>,
     model: &str,
@@ -177,7 +408,7 @@ fn register_message(lua: &mlua::Lua, table: &mlua::Table, role: &str) -> mlua::R
             let output = lua.create_table()?;
 
             if let Some(table) = value.as_table() {
-                output.set("content", table.get::<String>("content")?)?;
+                output.set("content", table.get::<mlua::Value>("content")?)?;
                 if let Ok(name) = table.get::<String>("name") {
                     output.set("name", name)?;
                 }
@@ -195,7 +426,7 @@ fn register_message(lua: &mlua::Lua, table: &mlua::Table, role: &str) -> mlua::R
 
 fn from_message_table_to_message(table: mlua::Table) -> mlua::Result<ChatCompletionRequestMessage> {
     let role = table.get::<String>("role")?;
-    let content = table.get::<String>("content")?;
+    let content = table.get::<mlua::Value>("content")?;
     let name = if table.contains_key("name")? {
         Some(table.get::<String>("name")?)
     } else {
@@ -205,19 +436,19 @@ fn from_message_table_to_message(table: mlua::Table) -> mlua::Result<ChatComplet
     match role.as_str() {
         "system" => Ok(ChatCompletionRequestMessage::System(
             ChatCompletionRequestSystemMessage {
-                content: content.into(),
+                content: content_to_text(content)?.into(),
                 name,
             },
         )),
         "user" => Ok(ChatCompletionRequestMessage::User(
             ChatCompletionRequestUserMessage {
-                content: content.into(),
+                content: content_to_user_content(content)?,
                 name,
             },
         )),
         "assistant" => Ok(ChatCompletionRequestMessage::Assistant(
             ChatCompletionRequestAssistantMessage {
-                content: Some(content.into()),
+                content: Some(content_to_text(content)?.into()),
                 name,
                 ..Default::default()
             },
@@ -229,3 +460,93 @@ fn from_message_table_to_message(table: mlua::Table) -> mlua::Result<ChatComplet
         }),
     }
 }
+
+fn content_to_text(content: mlua::Value) -> mlua::Result<String> {
+    match content {
+        mlua::Value::String(s) => Ok(s.to_str()?.to_string()),
+        mlua::Value::Table(_) => Err(mlua::Error::FromLuaConversionError {
+            from: content.type_name(),
+            to: "String".to_string(),
+            message: Some("only user messages can contain images".to_string()),
+        }),
+        _ => Err(mlua::Error::FromLuaConversionError {
+            from: content.type_name(),
+            to: "String".to_string(),
+            message: Some("message content must be a string".to_string()),
+        }),
+    }
+}
+
+/// Converts either a string or a sequence of strings and `llm.image` parts
+/// into user message content.
+fn content_to_user_content(
+    content: mlua::Value,
+) -> mlua::Result<ChatCompletionRequestUserMessageContent> {
+    let mlua::Value::Table(parts) = content else {
+        return Ok(content_to_text(content)?.into());
+    };
+
+    let mut text = String::new();
+    let mut image_urls = vec![];
+    for part in parts.sequence_values::<mlua::Value>() {
+        match part? {
+            mlua::Value::String(s) => {
+                if !text.is_empty() {
+                    text.push('\n');
+                }
+                text.push_str(&s.to_str()?);
+            }
+            mlua::Value::Table(part) if part.get::<String>("type")? == "image_url" => {
+                image_urls.push(part.get::<String>("url")?);
+            }
+            other => {
+                return Err(mlua::Error::FromLuaConversionError {
+                    from: other.type_name(),
+                    to: "ChatCompletionRequestUserMessageContentPart".to_string(),
+                    message: Some("expected a string or `llm.image`".to_string()),
+                });
+            }
+        }
+    }
+
+    Ok(attachments::user_message_content(text, image_urls))
+}
+
+fn has_image(message: &ChatCompletionRequestMessage) -> bool {
+    matches!(
+        message,
+        ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+            content: ChatCompletionRequestUserMessageContent::Array(parts),
+            ..
+        }) if parts
+            .iter()
+            .any(|p| matches!(p, ChatCompletionRequestUserMessageContentPart::ImageUrl(_)))
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+
+    use super::*;
+    use crate::config;
+
+    #[tokio::test]
+    async fn test_nested_turns() {
+        let queue = Arc::new(queue::Queue::new(
+            &config::Queue {
+                max_concurrent: Some(1),
+            },
+            &HashMap::new(),
+        ));
+        let turns = Turns::new(queue, MessageId::new(1), UserId::new(2));
+
+        let turn = turns.take("model").await.unwrap();
+        // A request made while another is streaming fails, rather than waiting behind it
+        let nested = tokio::time::timeout(Duration::from_secs(1), turns.take("model")).await;
+        assert!(nested.expect("nested request waited in the queue").is_err());
+
+        drop(turn);
+        assert!(turns.take("model").await.is_ok());
+    }
+}
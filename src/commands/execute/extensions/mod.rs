@@ -1,5 +1,7 @@
 use std::sync::Arc;
 
+use serenity::all::{GuildId, MessageId, UserId};
+
 use crate::ai::Ai;
 
 mod globals;
@@ -8,10 +10,13 @@ mod llm;
 pub fn register(
     lua: &mlua::Lua,
     ai: Arc<Ai>,
+    guild_id: Option<GuildId>,
+    key: MessageId,
+    user_id: UserId,
     output_tx: flume::Sender<String>,
     print_tx: flume::Sender<String>,
 ) -> mlua::Result<()> {
     globals::register(lua, output_tx, print_tx)?;
-    llm::register(lua, ai)?;
+    llm::register(lua, ai, guild_id, key, user_id)?;
     Ok(())
 }
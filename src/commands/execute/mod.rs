@@ -1,11 +1,17 @@
 use std::sync::Arc;
 
 use serenity::{
-    all::{CommandInteraction, Http, MessageId},
+    all::{GuildId, Http, MessageId, UserId},
     futures::StreamExt as _,
 };
 
-use crate::{ai::Ai, config, outputter::Outputter};
+use mlua::LuaSerdeExt as _;
+
+use crate::{
+    ai::Ai,
+    config, localization,
+    outputter::{Destination, Outputter},
+};
 
 pub mod app;
 pub mod slash;
@@ -31,17 +37,18 @@ impl Handler {
         }
     }
 
-    async fn run(
+    pub async fn run(
         &self,
         http: &Http,
-        cmd: &CommandInteraction,
+        destination: Destination<'_>,
         unparsed_code: &str,
     ) -> anyhow::Result<()> {
         let mut outputter = Outputter::new(
             http,
-            cmd,
-            std::time::Duration::from_millis(self.discord_config.message_update_interval_ms),
-            "Executing...",
+            destination,
+            false,
+            &self.discord_config,
+            localization::text(destination.locale(), "Executing..."),
         )
         .await?;
         let starting_message_id = outputter.starting_message_id();
@@ -51,7 +58,14 @@ impl Handler {
         let (output_tx, output_rx) = flume::unbounded::<String>();
         let (print_tx, print_rx) = flume::unbounded::<String>();
 
-        let lua = create_lua_state(self.ai.clone(), output_tx, print_tx)?;
+        let lua = create_lua_state(
+            self.ai.clone(),
+            destination.guild_id(),
+            starting_message_id,
+            destination.user_id(),
+            output_tx,
+            print_tx,
+        )?;
         let mut thread = load_async_expression::<Option<String>>(&lua, code)?;
 
         struct Output {
@@ -140,8 +154,55 @@ impl Handler {
     }
 }
 
+/// Runs the body of a tool implemented in Lua, with its arguments available as `args`,
+/// in the same environment as `/execute`. Its `llm` requests are queued under `key`, as
+/// `user_id`.
+///
+/// Non-string results are converted to JSON, and anything printed is appended to the result.
+pub async fn run_tool(
+    ai: Arc<Ai>,
+    guild_id: Option<GuildId>,
+    key: MessageId,
+    user_id: UserId,
+    code: &str,
+    args: serde_json::Value,
+) -> anyhow::Result<String> {
+    const TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+
+    let (output_tx, _output_rx) = flume::unbounded::<String>();
+    let (print_tx, print_rx) = flume::unbounded::<String>();
+
+    let lua = create_lua_state(ai, guild_id, key, user_id, output_tx, print_tx)?;
+    let function = lua
+        .load(format!("return function(args)\n{code}\nend"))
+        .eval::<mlua::Function>()?;
+    let result = tokio::time::timeout(
+        TIMEOUT,
+        function.call_async::<mlua::Value>(lua.to_value(&args)?),
+    )
+    .await
+    .map_err(|_| anyhow::anyhow!("tool timed out after {}s", TIMEOUT.as_secs()))??;
+
+    let mut output = match result {
+        mlua::Value::Nil => String::new(),
+        mlua::Value::String(s) => s.to_str()?.to_string(),
+        other => serde_json::to_string(&lua.from_value::<serde_json::Value>(other)?)?,
+    };
+
+    let print_log: Vec<_> = print_rx.drain().collect();
+    if !print_log.is_empty() {
+        output.push_str("\nPrinted:\n");
+        output.push_str(&print_log.join("\n"));
+    }
+
+    Ok(output)
+}
+
 fn create_lua_state(
     ai: Arc<Ai>,
+    guild_id: Option<GuildId>,
+    key: MessageId,
+    user_id: UserId,
     output_tx: flume::Sender<String>,
     print_tx: flume::Sender<String>,
 ) -> mlua::Result<mlua::Lua> {
@@ -153,7 +214,7 @@ fn create_lua_state(
         mlua::LuaOptions::new().catch_rust_panics(true),
     )?;
 
-    extensions::register(&lua, ai, output_tx, print_tx)?;
+    extensions::register(&lua, ai, guild_id, key, user_id, output_tx, print_tx)?;
 
     Ok(lua)
 }
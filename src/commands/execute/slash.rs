@@ -1,8 +1,8 @@
 use serenity::all::{
-    Command, CommandInteraction, CommandOptionType, CreateCommand, CreateCommandOption, Http,
+    CommandInteraction, CommandOptionType, CreateCommand, CreateCommandOption, Http,
 };
 
-use crate::{constant, util};
+use crate::{constant, localization, outputter::Destination, util};
 
 use crate::commands::CommandHandler;
 
@@ -21,7 +21,7 @@ impl CommandHandler for Handler {
     }
 
     async fn register(&self, http: &Http) -> anyhow::Result<()> {
-        Command::create_global_command(
+        localization::register(
             http,
             CreateCommand::new(constant::commands::EXECUTE)
                 .description("Execute the Lua code block from the given code snippet or message ID.")
@@ -68,6 +68,8 @@ impl CommandHandler for Handler {
             (None, None) => anyhow::bail!("no message ID or code specified"),
         };
 
-        self.base.run(http, cmd, &unparsed_code).await
+        self.base
+            .run(http, Destination::Interaction(cmd), &unparsed_code)
+            .await
     }
 }
@@ -0,0 +1,79 @@
+use serenity::all::{CommandInteraction, CommandType, CreateCommand, Http};
+
+use crate::{attachments, config, localization, outputter::Destination};
+
+use crate::commands::CommandHandler;
+
+/// The message context-menu variant of a configured command, which uses the
+/// content and attachments of the selected message as the prompt, along with the
+/// images in the message it replies to.
+pub struct Handler {
+    base: super::Handler,
+    context_menu: config::ContextMenu,
+}
+impl Handler {
+    pub fn new(base: super::Handler, context_menu: config::ContextMenu) -> Self {
+        Self { base, context_menu }
+    }
+}
+#[serenity::async_trait]
+impl CommandHandler for Handler {
+    fn name(&self) -> &str {
+        &self.context_menu.name
+    }
+
+    async fn register(&self, http: &Http) -> anyhow::Result<()> {
+        if !self.base.command.enabled {
+            return Ok(());
+        }
+
+        localization::register(
+            http,
+            CreateCommand::new(self.context_menu.name.as_str()).kind(CommandType::Message),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn run(&self, http: &Http, cmd: &CommandInteraction) -> anyhow::Result<()> {
+        let messages = &cmd.data.resolved.messages;
+        let Some(message) = messages.values().next() else {
+            anyhow::bail!("no message found");
+        };
+
+        let model = &self.context_menu.model;
+        let supports_vision = self.base.ai.supports_vision(model);
+        // Unlike the slash command, the attachments weren't chosen for the command,
+        // so skip anything we can't use instead of failing.
+        let referenced_images = message
+            .referenced_message
+            .iter()
+            .flat_map(|referenced| &referenced.attachments)
+            .filter(|a| supports_vision && attachments::is_image(a));
+        let attachments = message
+            .attachments
+            .iter()
+            .filter(|a| attachments::is_text(a) || (supports_vision && attachments::is_image(a)))
+            .chain(referenced_images)
+            .collect();
+
+        self.base
+            .run(
+                http,
+                Destination::Interaction(cmd),
+                super::Request {
+                    title: format!("{} {}", self.context_menu.name, message.link()),
+                    prompt: message.content.clone(),
+                    model: Some(model.clone()),
+                    seed: 0,
+                    variables: Default::default(),
+                    attachments,
+                    system_prompt: None,
+                    private: None,
+                    persona: None,
+                    use_cache: true,
+                },
+            )
+            .await
+    }
+}
@@ -0,0 +1,851 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+
+use anyhow::Context as _;
+use async_openai::types::{
+    ChatCompletionMessageToolCall, ChatCompletionRequestAssistantMessage,
+    ChatCompletionRequestMessage, ChatCompletionRequestSystemMessage,
+    ChatCompletionRequestToolMessage, ChatCompletionRequestUserMessage,
+    ChatCompletionRequestUserMessageContent, ChatCompletionStreamOptions, ChatCompletionTool,
+    ChatCompletionToolType, CreateChatCompletionRequestArgs, FunctionCall, FunctionObject,
+    ResponseFormat, ResponseFormatJsonSchema,
+};
+use serenity::{
+    all::{Attachment, ChannelId, Colour, GuildId, Http, MessageId, UserId},
+    futures::StreamExt,
+};
+
+use crate::{
+    ai::Ai,
+    attachments, config, constant,
+    database::Database,
+    details, documents, localization, mentions,
+    moderation::{Flag, StreamCheck, Verdict},
+    outputter::{Destination, EmbedStyle, Outputter},
+    personas::Persona,
+    preferences::Preferences,
+    publish,
+    queue::{self, Status},
+    reasoning, review,
+    statistics::Statistics,
+    structured_output, util,
+};
+
+use super::execute;
+
+pub mod app;
+pub mod slash;
+
+/// The maximum number of rounds of tool calls before the model must answer.
+const MAX_TOOL_ROUNDS: usize = 8;
+/// The amount of each tool result to show in the output.
+const TOOL_RESULT_PREVIEW_LENGTH: usize = 100;
+/// The number of times to retry an invalid structured response, if not configured.
+const DEFAULT_JSON_RETRIES: u32 = 2;
+
+/// A request to generate a response, independent of how the command was invoked.
+pub struct Request<'a> {
+    /// Shown in bold above the response.
+    pub title: String,
+    pub prompt: String,
+    /// The model to use. If not set, the user's preferred model is used.
+    pub model: Option<String>,
+    pub seed: u32,
+    /// Values of the custom options, made available to the templates.
+    pub variables: HashMap<String, String>,
+    pub attachments: Vec<&'a Attachment>,
+    /// Replaces the command's system prompt, if set.
+    pub system_prompt: Option<String>,
+    /// Whether or not to only show the response to the user. If not set, the user's
+    /// preference is used, followed by the command's default.
+    pub private: Option<bool>,
+    /// The name of a persona to use instead of the command's system prompt.
+    pub persona: Option<String>,
+    /// Whether or not a cached response can be used, if responses are cached.
+    pub use_cache: bool,
+}
+
+/// State shared between the handlers of all configured commands.
+#[derive(Clone)]
+pub struct Shared {
+    pub details: Arc<details::Store>,
+    pub published: Arc<publish::Store>,
+    pub held: Arc<review::Store>,
+    pub database: Arc<Database>,
+    pub documents: Arc<documents::Library>,
+}
+
+#[derive(Clone)]
+pub struct Handler {
+    cancel_rx: flume::Receiver<MessageId>,
+    name: String,
+    command: config::Command,
+    discord_config: config::Discord,
+    ai: Arc<Ai>,
+    shared: Shared,
+}
+impl Handler {
+    pub fn new(
+        command: config::Command,
+        name: String,
+        discord_config: config::Discord,
+        cancel_rx: flume::Receiver<MessageId>,
+        ai: Arc<Ai>,
+        shared: Shared,
+    ) -> Self {
+        Self {
+            cancel_rx,
+            name,
+            command,
+            discord_config,
+            ai,
+            shared,
+        }
+    }
+
+    /// Whether or not the command can be run with just a prompt, as scheduled jobs are.
+    pub fn can_run_unattended(&self) -> bool {
+        self.command.enabled && !self.command.options.values().any(|option| option.required)
+    }
+
+    pub async fn run(
+        &self,
+        http: &Http,
+        destination: Destination<'_>,
+        request: Request<'_>,
+    ) -> anyhow::Result<()> {
+        let Request {
+            title,
+            prompt,
+            model,
+            seed,
+            mut variables,
+            attachments,
+            system_prompt,
+            private,
+            persona,
+            use_cache,
+        } = request;
+
+        let user_id = destination.user_id();
+        let guild_id = destination.guild_id();
+        let persona = match persona {
+            Some(name) => Some(
+                Persona::find(&self.shared.database, user_id, guild_id, &name)?
+                    .with_context(|| format!("unknown persona `{name}`; see `/persona list`"))?,
+            ),
+            None => None,
+        };
+        let preferences = Preferences::load(&self.shared.database, user_id)?;
+        let temperature = persona
+            .as_ref()
+            .and_then(|p| p.temperature)
+            .or(preferences.temperature);
+        let model = model
+            .or_else(|| persona.as_ref().and_then(|p| p.model.clone()))
+            .or(preferences.model)
+            .context("no model specified; choose one, or set a default with `/settings`")?;
+        // Only responses to interactions can be private
+        let private = matches!(destination, Destination::Interaction(_))
+            && private
+                .or(preferences.private)
+                .unwrap_or(self.command.private);
+        if let Some(language) = preferences.language {
+            variables
+                .entry(constant::value::LANGUAGE.to_string())
+                .or_insert(language);
+        }
+        self.command.fill_missing_options(&mut variables);
+
+        // Make mentions, emoji and timestamps readable for the model
+        let prompt = mentions::resolve(http, guild_id, &prompt).await;
+
+        // The command's system prompt is trusted, but overrides and personas are user-written
+        let user_system_prompt = system_prompt.is_some() || persona.is_some();
+        variables.insert(constant::value::PROMPT.to_string(), prompt.clone());
+        let mut system_prompt = util::render_template(
+            system_prompt
+                .as_deref()
+                .or(persona.as_ref().map(|p| p.system_prompt.as_str()))
+                .unwrap_or(&self.command.system_prompt),
+            &variables,
+        );
+        let mut user_message = match &self.command.prompt_template {
+            Some(template) => util::render_template(template, &variables),
+            None => prompt,
+        };
+
+        let image_urls = self
+            .add_attachments(&model, &attachments, &mut user_message)
+            .await?;
+
+        let moderator = &self.ai.moderator;
+        let moderated_prompt = if user_system_prompt {
+            format!("{system_prompt}\n\n{user_message}")
+        } else {
+            user_message.clone()
+        };
+        let mut held = None;
+        match moderator.judge(guild_id, &moderated_prompt).await? {
+            None => {
+                let policy = moderator.policy(guild_id);
+                user_message = policy.redact(&user_message).into_owned();
+                if user_system_prompt {
+                    system_prompt = policy.redact(&system_prompt).into_owned();
+                }
+            }
+            Some(Verdict::Block(flag)) => {
+                anyhow::bail!("your prompt was blocked by moderation ({})", flag.reason)
+            }
+            Some(Verdict::Hold(channel_id, flag)) => held = Some((channel_id, flag)),
+        }
+
+        // The documents are trusted, so they're only added once the prompt has been moderated
+        let snippets = match &self.command.documents {
+            Some(name) => {
+                self.shared
+                    .documents
+                    .search(&self.ai, name, &variables[constant::value::PROMPT])
+                    .await?
+            }
+            None => vec![],
+        };
+        if !snippets.is_empty() {
+            user_message = documents::add_snippets(&user_message, &snippets);
+        }
+
+        // A persona's examples are written for its system prompt, so they replace the command's
+        let examples = match &persona {
+            Some(persona) if !persona.examples.is_empty() || persona.greeting.is_some() => {
+                persona.example_messages()
+            }
+            _ => self.command.examples.clone(),
+        };
+        let mut messages = create_messages(
+            &system_prompt,
+            &examples,
+            &variables,
+            attachments::user_message_content(user_message, image_urls),
+        );
+
+        let mut outputter = Outputter::new(
+            http,
+            destination,
+            private,
+            &self.discord_config,
+            localization::text(destination.locale(), "Generating..."),
+        )
+        .await?;
+        let starting_message_id = outputter.starting_message_id();
+        if let Some(embed) = &self.command.embed {
+            outputter.set_embed(EmbedStyle {
+                title: title.clone(),
+                colour: embed.colour.map(|colour| Colour::new(colour.0)),
+                footer: model.clone(),
+            });
+        }
+        let mut output_check = moderator.stream_check(guild_id);
+        if held.is_some() {
+            // Held prompts are still answered, but nothing is shown until it's been reviewed
+            outputter
+                .withdraw(localization::text(outputter.locale(), review::HELD_MESSAGE))
+                .await?;
+            output_check.stop();
+        }
+
+        let show_statistics = self.discord_config.show_statistics;
+        let tools: Vec<_> = self
+            .command
+            .tools
+            .iter()
+            .map(|(name, tool)| ChatCompletionTool {
+                r#type: ChatCompletionToolType::Function,
+                function: FunctionObject {
+                    name: name.clone(),
+                    description: Some(tool.description.clone()),
+                    parameters: Some(tool.parameters.clone()),
+                    strict: None,
+                },
+            })
+            .collect();
+
+        let mut statistics = Statistics::new(seed);
+        let mut output = Output {
+            title,
+            model: model.clone(),
+            implicit_think_tag: self.ai.has_implicit_think_tag(&model),
+            locale: destination.locale().to_string(),
+            hide_reasoning: self.command.hide_reasoning,
+            structured: self.command.response_format.is_some(),
+            embed: self.command.embed.is_some(),
+            sources: (!snippets.is_empty()).then(|| documents::sources_line(&snippets)),
+            log: vec![],
+            streamed_reasoning: String::new(),
+            message: String::new(),
+        };
+
+        let mut job = None;
+        let mut errored = false;
+        let mut round = 0;
+        let mut retries = 0;
+        let request = loop {
+            let mut request = CreateChatCompletionRequestArgs::default();
+            request
+                .model(model.clone())
+                .seed(seed)
+                .messages(messages.clone())
+                .stream(true);
+            if let Some(temperature) = temperature {
+                request.temperature(temperature);
+            }
+            if show_statistics {
+                request.stream_options(ChatCompletionStreamOptions {
+                    include_usage: true,
+                });
+            }
+            // Stop offering tools after the last round, so that the model has to answer
+            if !tools.is_empty() && round < MAX_TOOL_ROUNDS {
+                request.tools(tools.clone());
+            }
+            if let Some(response_format) = &self.command.response_format {
+                request.response_format(ResponseFormat::JsonSchema {
+                    json_schema: ResponseFormatJsonSchema {
+                        description: None,
+                        name: self.name.clone(),
+                        schema: Some(response_format.schema.clone()),
+                        strict: Some(true),
+                    },
+                });
+            }
+            let request = request.build()?;
+
+            // Cached responses are replayed straight away, as they don't use the backend;
+            // otherwise, a turn is taken in the queue and held until the end
+            let mut stream = match self.ai.replay_chat_stream(&request, use_cache)? {
+                Some(stream) => stream,
+                None => {
+                    if job.is_none() {
+                        let mut new_job = self.ai.queue.join(starting_message_id, user_id, &model);
+                        if !wait_in_queue(&mut outputter, &mut new_job).await? {
+                            return Ok(());
+                        }
+                        job = Some(new_job);
+                    }
+                    self.ai.create_recorded_chat_stream(request.clone()).await?
+                }
+            };
+            output.streamed_reasoning.clear();
+            output.message.clear();
+            let mut tool_calls = BTreeMap::<u32, ChatCompletionMessageToolCall>::new();
+            while let Some(response) = stream.next().await {
+                if let Ok(cancel_message_id) = self.cancel_rx.try_recv() {
+                    if cancel_message_id == starting_message_id {
+                        outputter.cancelled().await?;
+                        errored = true;
+                        break;
+                    }
+                }
+
+                match response {
+                    Ok(response) => {
+                        if let Some(usage) = response.usage {
+                            statistics.on_usage(usage);
+                        }
+                        let Some(choice) = response.choices.first() else {
+                            continue;
+                        };
+                        if let Some(finish_reason) = choice.finish_reason {
+                            statistics.on_finish_reason(finish_reason);
+                        }
+                        let delta = &choice.delta;
+                        for chunk in delta.inner.tool_calls.iter().flatten() {
+                            let call = tool_calls.entry(chunk.index).or_insert_with(|| {
+                                ChatCompletionMessageToolCall {
+                                    id: String::new(),
+                                    r#type: ChatCompletionToolType::Function,
+                                    function: FunctionCall {
+                                        name: String::new(),
+                                        arguments: String::new(),
+                                    },
+                                }
+                            });
+                            if let Some(id) = &chunk.id {
+                                call.id.push_str(id);
+                            }
+                            if let Some(function) = &chunk.function {
+                                call.function
+                                    .name
+                                    .push_str(function.name.as_deref().unwrap_or_default());
+                                call.function
+                                    .arguments
+                                    .push_str(function.arguments.as_deref().unwrap_or_default());
+                            }
+                        }
+                        if delta.reasoning_content.is_none() && delta.inner.content.is_none() {
+                            continue;
+                        }
+                        statistics.on_content();
+                        if let Some(reasoning) = &delta.reasoning_content {
+                            output.streamed_reasoning += reasoning;
+                        }
+                        if let Some(content) = &delta.inner.content {
+                            output.message += content;
+                        }
+                        let rendered = output.render();
+                        if moderate_output(
+                            &mut outputter,
+                            &mut output_check,
+                            &mut held,
+                            &rendered,
+                            false,
+                        )
+                        .await?
+                        {
+                            errored = true;
+                            break;
+                        }
+                    }
+                    Err(err) => {
+                        outputter.error(&err.to_string()).await?;
+                        errored = true;
+                        break;
+                    }
+                }
+            }
+
+            if errored {
+                break request;
+            }
+            if tool_calls.is_empty() {
+                let Some(response_format) = &self.command.response_format else {
+                    break request;
+                };
+                let (_, answer) = reasoning::split(&output.message, output.implicit_think_tag);
+                let errors = match structured_output::parse(answer) {
+                    Ok(value) => structured_output::validate(&response_format.schema, &value),
+                    Err(err) => vec![format!("invalid JSON: {err}")],
+                };
+                if errors.is_empty()
+                    || retries >= response_format.retries.unwrap_or(DEFAULT_JSON_RETRIES)
+                {
+                    break request;
+                }
+
+                retries += 1;
+                output.log.push(format!(
+                    "-# Invalid response ({}), retrying...",
+                    errors.join("; ").replace('`', "'")
+                ));
+                messages.push(ChatCompletionRequestMessage::Assistant(
+                    ChatCompletionRequestAssistantMessage {
+                        content: Some(answer.to_string().into()),
+                        ..Default::default()
+                    },
+                ));
+                messages.push(ChatCompletionRequestMessage::User(
+                    ChatCompletionRequestUserMessage {
+                        content: format!(
+                            "Your response did not match the schema:\n{}\nRespond again with only the corrected JSON.",
+                            errors.join("\n")
+                        )
+                        .into(),
+                        name: None,
+                    },
+                ));
+                continue;
+            }
+
+            let tool_calls: Vec<_> = tool_calls.into_values().collect();
+            let (_, answer) = reasoning::split(&output.message, output.implicit_think_tag);
+            messages.push(ChatCompletionRequestMessage::Assistant(
+                ChatCompletionRequestAssistantMessage {
+                    content: (!answer.trim().is_empty()).then(|| answer.to_string().into()),
+                    tool_calls: Some(tool_calls.clone()),
+                    ..Default::default()
+                },
+            ));
+            // Give up the turn while the tools run, as their `llm` requests take turns of
+            // their own; the next round joins the queue again
+            job = None;
+            for call in tool_calls {
+                let FunctionCall { name, arguments } = call.function;
+                output
+                    .log
+                    .push(format!("-# Calling `{name}` with `{arguments}`..."));
+                outputter
+                    .update(&output_check.redact(&output.render()))
+                    .await?;
+
+                let result = self
+                    .call_tool(guild_id, starting_message_id, user_id, &name, &arguments)
+                    .await;
+                *output.log.last_mut().unwrap() = format!(
+                    "-# Called `{name}` with `{arguments}`: `{}`",
+                    util::truncate(&result, TOOL_RESULT_PREVIEW_LENGTH)
+                        .replace('`', "'")
+                        .replace('\n', " ")
+                );
+                outputter
+                    .update(&output_check.redact(&output.render()))
+                    .await?;
+
+                messages.push(ChatCompletionRequestMessage::Tool(
+                    ChatCompletionRequestToolMessage {
+                        content: result.into(),
+                        tool_call_id: call.id,
+                    },
+                ));
+            }
+            round += 1;
+        };
+
+        if !errored {
+            // Ensure that the final output does not include the reasoning
+            let rendered = match &self.command.response_format {
+                Some(response_format) => self.render_structured(response_format, &output),
+                None => Ok(output.render_final()),
+            };
+            let rendered = match rendered {
+                Ok(rendered) => rendered,
+                Err(err) => {
+                    outputter.error(&err.to_string()).await?;
+                    return Ok(());
+                }
+            };
+            if moderate_output(
+                &mut outputter,
+                &mut output_check,
+                &mut held,
+                &rendered,
+                true,
+            )
+            .await?
+            {
+                return Ok(());
+            }
+            if let Some(held) = held {
+                let output =
+                    review::Held::new(&destination, private, starting_message_id, rendered);
+                let source = localization::format(
+                    destination.guild_locale(),
+                    "{{command}} by {{user}} in {{channel}}",
+                    &[
+                        ("command", &format!("`/{}`", self.name)),
+                        ("user", &format!("<@{}>", destination.user_id())),
+                        ("channel", &format!("<#{}>", destination.channel_id())),
+                    ],
+                );
+                return review::submit(
+                    http,
+                    &self.shared.held,
+                    held,
+                    (destination.guild_locale(), &source),
+                    starting_message_id,
+                    &moderated_prompt,
+                    output,
+                )
+                .await;
+            }
+
+            if show_statistics {
+                statistics.finish();
+                outputter.set_footer(statistics.footer(destination.locale()));
+                // Anyone can see the details, so a persona's system prompt is left out, as
+                // it belongs to whoever made the persona
+                let shown_system_prompt = persona.is_none().then_some(system_prompt.as_str());
+                self.shared.details.insert(
+                    starting_message_id,
+                    statistics.details(destination.locale(), shown_system_prompt, &request),
+                );
+                outputter
+                    .add_final_button(details::button(starting_message_id, destination.locale()));
+            }
+            if private {
+                outputter.set_publish_button(publish::button(
+                    starting_message_id,
+                    user_id,
+                    destination.locale(),
+                ));
+            }
+            outputter.finish().await?;
+            if private {
+                self.shared
+                    .published
+                    .insert(starting_message_id, outputter.published_messages());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Renders a structured response through the command's template, or as a code block.
+    fn render_structured(
+        &self,
+        response_format: &config::ResponseFormat,
+        output: &Output,
+    ) -> anyhow::Result<String> {
+        let (_, answer) = reasoning::split(&output.message, output.implicit_think_tag);
+        let value = structured_output::parse(answer)
+            .map_err(|err| anyhow::anyhow!("The response was not valid JSON: {err}"))?;
+        let errors = structured_output::validate(&response_format.schema, &value);
+        if !errors.is_empty() {
+            anyhow::bail!(
+                "The response did not match the schema: {}",
+                errors.join("; ")
+            );
+        }
+
+        let body = match &response_format.template {
+            Some(template) => {
+                util::render_template(template, &structured_output::template_variables(&value))
+            }
+            None => format!(
+                "```json\n{}\n```",
+                serde_json::to_string_pretty(&value).unwrap_or_default()
+            ),
+        };
+        Ok(output.with_header(&body))
+    }
+
+    /// Runs the named tool, returning its result or a description of the error
+    /// for the model. Its `llm` requests are queued under `key`, as `user_id`.
+    async fn call_tool(
+        &self,
+        guild_id: Option<GuildId>,
+        key: MessageId,
+        user_id: UserId,
+        name: &str,
+        arguments: &str,
+    ) -> String {
+        let Some(tool) = self.command.tools.get(name) else {
+            return format!("Error: unknown tool `{name}`");
+        };
+        let args = match serde_json::from_str(arguments) {
+            Ok(args) => args,
+            Err(err) => return format!("Error: invalid arguments: {err}"),
+        };
+
+        execute::run_tool(self.ai.clone(), guild_id, key, user_id, &tool.code, args)
+            .await
+            .unwrap_or_else(|err| format!("Error: {err}"))
+    }
+
+    /// Downloads the given attachments, appending text files to `user_message` and
+    /// returning the data URLs of any images.
+    async fn add_attachments(
+        &self,
+        model: &str,
+        attachments: &[&Attachment],
+        user_message: &mut String,
+    ) -> anyhow::Result<Vec<String>> {
+        let mut image_urls = vec![];
+        for attachment in attachments {
+            if attachments::is_image(attachment) {
+                if !self.ai.supports_vision(model) {
+                    anyhow::bail!("`{model}` does not support images");
+                }
+                image_urls.push(
+                    attachments::download_image(attachment, self.discord_config.max_image_size)
+                        .await?,
+                );
+            } else {
+                let content =
+                    attachments::download_text(attachment, self.discord_config.max_attachment_size)
+                        .await?;
+                user_message.push_str("\n\n");
+                user_message.push_str(&attachments::format_text(attachment, &content));
+            }
+        }
+        Ok(image_urls)
+    }
+}
+
+/// Waits for the job to start, showing its position in the queue until then. Returns
+/// whether or not it started, as it may have been cancelled instead.
+async fn wait_in_queue(
+    outputter: &mut Outputter<'_>,
+    job: &mut queue::Job,
+) -> anyhow::Result<bool> {
+    let mut shown_position = None;
+    loop {
+        match job.status() {
+            Status::Running => {
+                if shown_position.is_some() {
+                    outputter
+                        .status(localization::text(outputter.locale(), "Generating..."))
+                        .await?;
+                }
+                return Ok(true);
+            }
+            Status::Cancelled => {
+                outputter.cancelled().await?;
+                return Ok(false);
+            }
+            Status::Waiting(position) => {
+                if shown_position != Some(position) {
+                    let status = localization::format(
+                        outputter.locale(),
+                        "Queued (#{{position}})",
+                        &[("position", &position.to_string())],
+                    );
+                    outputter.status(&status).await?;
+                    shown_position = Some(position);
+                }
+            }
+        }
+        job.changed().await;
+    }
+}
+
+/// Checks the output with moderation before showing it, withdrawing it if it's blocked
+/// or held. Returns whether or not the output was blocked, in which case generation should stop.
+async fn moderate_output(
+    outputter: &mut Outputter<'_>,
+    check: &mut StreamCheck<'_>,
+    held: &mut Option<(ChannelId, Flag)>,
+    output: &str,
+    finished: bool,
+) -> anyhow::Result<bool> {
+    match check.check(output, finished).await? {
+        None => {
+            outputter.update(&check.redact(output)).await?;
+            Ok(false)
+        }
+        Some(Verdict::Block(flag)) => {
+            let message = localization::format(
+                outputter.locale(),
+                "The response was blocked by moderation ({{reason}}).",
+                &[("reason", &flag.reason)],
+            );
+            outputter.withdraw(&message).await?;
+            Ok(true)
+        }
+        Some(Verdict::Hold(channel_id, flag)) => {
+            // Keep generating, so that moderators can review the whole output
+            outputter
+                .withdraw(localization::text(outputter.locale(), review::HELD_MESSAGE))
+                .await?;
+            *held = Some((channel_id, flag));
+            Ok(false)
+        }
+    }
+}
+
+/// Builds the conversation for a command: the system prompt, followed by the examples
+/// and then the user's message.
+pub fn create_messages(
+    system_prompt: &str,
+    examples: &[config::ExampleMessage],
+    variables: &HashMap<String, String>,
+    user_content: ChatCompletionRequestUserMessageContent,
+) -> Vec<ChatCompletionRequestMessage> {
+    let mut messages = vec![ChatCompletionRequestMessage::System(
+        ChatCompletionRequestSystemMessage {
+            content: system_prompt.to_string().into(),
+            name: None,
+        },
+    )];
+    for example in examples {
+        let content = util::render_template(&example.content, variables);
+        messages.push(match example.role {
+            config::ExampleRole::User => {
+                ChatCompletionRequestMessage::User(ChatCompletionRequestUserMessage {
+                    content: content.into(),
+                    name: None,
+                })
+            }
+            config::ExampleRole::Assistant => {
+                ChatCompletionRequestMessage::Assistant(ChatCompletionRequestAssistantMessage {
+                    content: Some(content.into()),
+                    ..Default::default()
+                })
+            }
+        });
+    }
+    messages.push(ChatCompletionRequestMessage::User(
+        ChatCompletionRequestUserMessage {
+            content: user_content,
+            name: None,
+        },
+    ));
+    messages
+}
+
+/// The state of the output while it is being generated.
+struct Output {
+    title: String,
+    model: String,
+    /// Whether or not the model's output starts inside a `<think>` block
+    implicit_think_tag: bool,
+    /// The locale that the output's status text is translated into
+    locale: String,
+    hide_reasoning: bool,
+    /// Whether or not the output is shown in embeds, which show the title and model themselves
+    embed: bool,
+    /// Whether or not the response is JSON, and should be shown as such while streaming
+    structured: bool,
+    /// The documents the response was given, listed below it
+    sources: Option<String>,
+    /// A line for each tool call or retry made so far
+    log: Vec<String>,
+    /// The reasoning streamed separately from the content, if any
+    streamed_reasoning: String,
+    message: String,
+}
+impl Output {
+    /// Formats the output while it is being streamed, showing the model's reasoning
+    /// until it has started answering.
+    fn render(&self) -> String {
+        /// The amount of the reasoning to show; only the end is shown to keep it to one message.
+        const REASONING_TAIL_LENGTH: usize = 1000;
+
+        let (inline_reasoning, answer) = reasoning::split(&self.message, self.implicit_think_tag);
+        let reasoning = if self.streamed_reasoning.is_empty() {
+            inline_reasoning
+        } else {
+            self.streamed_reasoning.trim()
+        };
+
+        let body = if self.structured && !answer.trim().is_empty() {
+            format!("```json\n{}\n```", answer.trim())
+        } else if !answer.trim().is_empty() || reasoning.is_empty() {
+            answer.to_string()
+        } else if self.hide_reasoning {
+            format!("*{}*", localization::text(&self.locale, "Thinking..."))
+        } else {
+            format!(
+                "*{}*\n||{}||",
+                localization::text(&self.locale, "Thinking..."),
+                reasoning::tail(reasoning, REASONING_TAIL_LENGTH)
+            )
+        };
+
+        self.with_header(&body)
+    }
+
+    /// Formats the finished output, which never includes the reasoning.
+    fn render_final(&self) -> String {
+        let (_, answer) = reasoning::split(&self.message, self.implicit_think_tag);
+        self.with_header(answer)
+    }
+
+    fn with_header(&self, body: &str) -> String {
+        let mut output = if self.embed {
+            String::new()
+        } else {
+            format!("**{}** (*{}*)\n", self.title, self.model)
+        };
+        for line in &self.log {
+            output.push_str(line);
+            output.push('\n');
+        }
+        output.push_str(&mentions::neutralize(body));
+        if let Some(sources) = &self.sources {
+            output.push_str("\n\n");
+            output.push_str(sources);
+        }
+        output
+    }
+}
@@ -0,0 +1,363 @@
+use std::collections::HashMap;
+
+use anyhow::Context;
+use serenity::all::{
+    ActionRowComponent, CommandDataOptionValue, CommandInteraction, CommandOptionType,
+    CreateActionRow, CreateCommand, CreateCommandOption, CreateInputText,
+    CreateInteractionResponse, CreateModal, Http, InputTextStyle, InteractionId, ModalInteraction,
+};
+
+use crate::{config, constant, localization, outputter::Destination, personas, store::Store, util};
+
+use crate::commands::{self, CommandHandler};
+
+/// The amount of a prompt entered in the form to show as the title.
+const MODAL_TITLE_LENGTH: usize = 100;
+
+pub struct Handler {
+    base: super::Handler,
+    /// Invocations without a prompt, waiting for the prompt form to be submitted
+    pending: Store<InteractionId, CommandInteraction>,
+}
+impl Handler {
+    pub fn new(base: super::Handler) -> Self {
+        Self {
+            base,
+            pending: Store::default(),
+        }
+    }
+
+    /// Builds the request for an invocation from its options and the given prompt.
+    fn create_request<'a>(
+        &self,
+        cmd: &'a CommandInteraction,
+        title: String,
+        prompt: String,
+        system_prompt: Option<String>,
+    ) -> anyhow::Result<super::Request<'a>> {
+        use constant::value as v;
+        use util::{value_to_integer, value_to_string};
+
+        let options = &cmd.data.options;
+        let seed = util::get_value(options, v::SEED)
+            .and_then(value_to_integer)
+            .map(|i| i as u32)
+            .unwrap_or(0);
+
+        let model = util::get_value(options, v::MODEL).and_then(value_to_string);
+        let private = util::get_value(options, v::PRIVATE).and_then(util::value_to_bool);
+        let persona = util::get_value(options, v::PERSONA).and_then(value_to_string);
+        let use_cache = util::get_value(options, v::CACHE)
+            .and_then(util::value_to_bool)
+            .unwrap_or(true);
+
+        let attachments = util::get_value(options, v::ATTACHMENT)
+            .and_then(util::value_to_attachment_id)
+            .and_then(|id| cmd.data.resolved.attachments.get(&id))
+            .into_iter()
+            .collect();
+
+        Ok(super::Request {
+            title,
+            prompt,
+            model,
+            seed,
+            variables: self.template_variables(cmd),
+            attachments,
+            system_prompt,
+            private,
+            persona,
+            use_cache,
+        })
+    }
+
+    /// Builds the form for entering the prompt, and optionally replacing the system prompt.
+    fn create_modal(&self, cmd: &CommandInteraction) -> CreateModal {
+        /// Modal titles can be up to 45 characters.
+        const TITLE_SIZE: usize = 45;
+        /// Text input placeholders can be up to 100 characters.
+        const PLACEHOLDER_SIZE: usize = 100;
+
+        let mut system_prompt = CreateInputText::new(
+            InputTextStyle::Paragraph,
+            localization::text(&cmd.locale, "System prompt (optional)"),
+            constant::value::SYSTEM_PROMPT,
+        )
+        .required(false);
+        if !self.base.command.system_prompt.is_empty() {
+            system_prompt = system_prompt.placeholder(util::truncate(
+                &self.base.command.system_prompt,
+                PLACEHOLDER_SIZE,
+            ));
+        }
+
+        CreateModal::new(
+            commands::build_modal_id(&self.base.name, &cmd.id.to_string()),
+            util::truncate(&format!("/{}", self.base.name), TITLE_SIZE),
+        )
+        .components(vec![
+            CreateActionRow::InputText(
+                CreateInputText::new(
+                    InputTextStyle::Paragraph,
+                    localization::text(&cmd.locale, "Prompt"),
+                    constant::value::PROMPT,
+                )
+                .required(true),
+            ),
+            CreateActionRow::InputText(system_prompt),
+        ])
+    }
+
+    /// Builds the variables available to the prompt templates from the custom options.
+    fn template_variables(&self, cmd: &CommandInteraction) -> HashMap<String, String> {
+        let resolved = &cmd.data.resolved;
+        let mut variables = HashMap::new();
+
+        for name in self.base.command.options.keys() {
+            let Some(value) = util::get_value(&cmd.data.options, name) else {
+                continue;
+            };
+
+            let value = match value {
+                CommandDataOptionValue::String(v) => v.clone(),
+                CommandDataOptionValue::Integer(v) => v.to_string(),
+                CommandDataOptionValue::Boolean(v) => v.to_string(),
+                CommandDataOptionValue::User(id) => resolved
+                    .members
+                    .get(id)
+                    .and_then(|m| m.nick.clone())
+                    .or_else(|| resolved.users.get(id).map(|u| u.display_name().to_string()))
+                    .unwrap_or_else(|| id.to_string()),
+                CommandDataOptionValue::Channel(id) => resolved
+                    .channels
+                    .get(id)
+                    .and_then(|c| c.name.clone())
+                    .unwrap_or_else(|| id.to_string()),
+                CommandDataOptionValue::Attachment(id) => resolved
+                    .attachments
+                    .get(id)
+                    .map(|a| a.filename.clone())
+                    .unwrap_or_else(|| id.to_string()),
+                _ => continue,
+            };
+            variables.insert(name.clone(), value);
+        }
+
+        variables
+    }
+}
+#[serenity::async_trait]
+impl CommandHandler for Handler {
+    fn name(&self) -> &str {
+        &self.base.name
+    }
+
+    async fn register(&self, http: &Http) -> anyhow::Result<()> {
+        if !self.base.command.enabled {
+            return Ok(());
+        }
+
+        let mut model_option = CreateCommandOption::new(
+            CommandOptionType::String,
+            constant::value::MODEL,
+            "The model to use. If not specified, your default from `/settings` is used.",
+        )
+        .required(false);
+
+        for model in &self.base.ai.models {
+            model_option = model_option.add_string_choice(model, model);
+        }
+
+        // Discord requires all required options to come before optional ones
+        let mut custom_options: Vec<_> = self.base.command.options.iter().collect();
+        custom_options.sort_by_key(|(name, option)| (!option.required, name.as_str()));
+        let (required_options, optional_options): (Vec<_>, Vec<_>) = custom_options
+            .into_iter()
+            .partition(|(_, option)| option.required);
+
+        let mut command = CreateCommand::new(self.base.name.clone())
+            .description(self.base.command.description.as_str());
+        for (name, option) in required_options {
+            command = command.add_option(create_custom_option(name, option));
+        }
+        command = command.add_option(
+            CreateCommandOption::new(
+                CommandOptionType::String,
+                constant::value::PROMPT,
+                "The prompt. If not specified, a form to enter it in is shown.",
+            )
+            .required(false),
+        );
+        command = command.add_option(model_option);
+        command = command.add_option(
+            CreateCommandOption::new(
+                CommandOptionType::Integer,
+                constant::value::SEED,
+                "The seed to use for sampling.",
+            )
+            .min_int_value(0)
+            .required(false),
+        );
+        command = command.add_option(
+            CreateCommandOption::new(
+                CommandOptionType::Attachment,
+                constant::value::ATTACHMENT,
+                "A text file or image to include with the prompt.",
+            )
+            .required(false),
+        );
+        command = command.add_option(
+            CreateCommandOption::new(
+                CommandOptionType::Boolean,
+                constant::value::PRIVATE,
+                "Whether or not to only show the response to you.",
+            )
+            .required(false),
+        );
+        command = command.add_option(
+            CreateCommandOption::new(
+                CommandOptionType::String,
+                constant::value::PERSONA,
+                "The name of a persona from `/persona` to use instead of the system prompt.",
+            )
+            .max_length(personas::MAX_NAME_LENGTH)
+            .required(false),
+        );
+        if self.base.ai.caches_responses() {
+            command = command.add_option(
+                CreateCommandOption::new(
+                    CommandOptionType::Boolean,
+                    constant::value::CACHE,
+                    "Whether or not to use a cached response to the same request. Defaults to true.",
+                )
+                .required(false),
+            );
+        }
+        for (name, option) in optional_options {
+            command = command.add_option(create_custom_option(name, option));
+        }
+
+        let mut command = serde_json::to_value(command)?;
+        set_integer_bounds(&mut command, &self.base.command.options);
+        localization::register(http, command).await?;
+
+        Ok(())
+    }
+
+    async fn run(&self, http: &Http, cmd: &CommandInteraction) -> anyhow::Result<()> {
+        let Some(user_prompt) = util::get_value(&cmd.data.options, constant::value::PROMPT)
+            .and_then(util::value_to_string)
+        else {
+            // Ask for the prompt in a form instead, keeping the invocation for when it's submitted
+            self.pending.insert(cmd.id, cmd.clone());
+            cmd.create_response(
+                http,
+                CreateInteractionResponse::Modal(self.create_modal(cmd)),
+            )
+            .await?;
+            return Ok(());
+        };
+
+        let user_prompt = if self.base.discord_config.replace_newlines {
+            user_prompt.replace("\\n", "\n")
+        } else {
+            user_prompt
+        };
+
+        let request = self.create_request(cmd, user_prompt.clone(), user_prompt, None)?;
+        self.base
+            .run(http, Destination::Interaction(cmd), request)
+            .await
+    }
+
+    async fn run_modal(
+        &self,
+        http: &Http,
+        modal: &ModalInteraction,
+        key: &str,
+    ) -> anyhow::Result<()> {
+        let cmd = key
+            .parse::<u64>()
+            .ok()
+            .and_then(|id| self.pending.remove(&InteractionId::new(id)))
+            .context("this prompt form has expired; please run the command again")?;
+
+        let mut values: HashMap<&str, &str> = HashMap::new();
+        for component in modal.data.components.iter().flat_map(|row| &row.components) {
+            if let ActionRowComponent::InputText(input) = component {
+                if let Some(value) = input.value.as_deref().filter(|v| !v.trim().is_empty()) {
+                    values.insert(input.custom_id.as_str(), value);
+                }
+            }
+        }
+
+        let user_prompt = values
+            .get(constant::value::PROMPT)
+            .context("no prompt specified")?
+            .to_string();
+        let system_prompt = values
+            .get(constant::value::SYSTEM_PROMPT)
+            .map(|s| s.to_string());
+
+        // Multi-line prompts make for poor titles, so only use the first line
+        let first_line = user_prompt.lines().next().unwrap_or_default();
+        let title = match util::truncate(first_line, MODAL_TITLE_LENGTH) {
+            title if title.len() < user_prompt.trim_end().len() => format!("{title}…"),
+            title => title.to_string(),
+        };
+
+        let request = self.create_request(&cmd, title, user_prompt, system_prompt)?;
+        self.base
+            .run(http, Destination::Interaction(modal), request)
+            .await
+    }
+}
+
+fn create_custom_option(name: &str, option: &config::CommandOption) -> CreateCommandOption {
+    use config::CommandOptionKind as K;
+
+    let kind = match option.kind {
+        K::String => CommandOptionType::String,
+        K::Integer => CommandOptionType::Integer,
+        K::Boolean => CommandOptionType::Boolean,
+        K::User => CommandOptionType::User,
+        K::Channel => CommandOptionType::Channel,
+        K::Attachment => CommandOptionType::Attachment,
+    };
+
+    let mut output =
+        CreateCommandOption::new(kind, name, option.description.as_str()).required(option.required);
+    if option.kind == K::String {
+        for choice in &option.choices {
+            output = output.add_string_choice(choice, choice);
+        }
+    }
+    output
+}
+
+/// Sets the bounds of the integer options in a serialized command, as serenity only
+/// accepts unsigned bounds while Discord allows negative ones.
+fn set_integer_bounds(
+    command: &mut serde_json::Value,
+    options: &HashMap<String, config::CommandOption>,
+) {
+    let Some(registered) = command.get_mut("options").and_then(|o| o.as_array_mut()) else {
+        return;
+    };
+    for registered in registered {
+        let Some(option) = registered
+            .get("name")
+            .and_then(|name| name.as_str())
+            .and_then(|name| options.get(name))
+            .filter(|option| option.kind == config::CommandOptionKind::Integer)
+        else {
+            continue;
+        };
+        if let Some(min) = option.min_value {
+            registered["min_value"] = min.into();
+        }
+        if let Some(max) = option.max_value {
+            registered["max_value"] = max.into();
+        }
+    }
+}
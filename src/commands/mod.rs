@@ -1,11 +1,42 @@
-use serenity::all::{CommandInteraction, Http};
+use serenity::all::{CommandInteraction, Http, ModalInteraction};
 
+pub mod compare;
 pub mod execute;
 pub mod hallucinate;
+pub mod persona;
+pub mod schedule;
+pub mod settings;
+pub mod summarize;
 
 #[serenity::async_trait]
 pub trait CommandHandler: Send + Sync {
     fn name(&self) -> &str;
     async fn register(&self, http: &Http) -> anyhow::Result<()>;
     async fn run(&self, http: &Http, cmd: &CommandInteraction) -> anyhow::Result<()>;
+    /// Handles the submission of a modal opened by this command, with the `key` it was
+    /// opened with.
+    async fn run_modal(
+        &self,
+        _http: &Http,
+        _modal: &ModalInteraction,
+        _key: &str,
+    ) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+pub const MODAL_ID_BASE: &str = "modal";
+
+/// Builds a modal ID from the name of the command that opened it and a key for the handler.
+pub fn build_modal_id(command_name: &str, key: &str) -> String {
+    format!("{MODAL_ID_BASE}#{command_name}#{key}")
+}
+
+/// Parses a modal ID into the name of the command that opened it and its key.
+pub fn parse_modal_id(id: &str) -> Option<(&str, &str)> {
+    let mut split_id = id.splitn(3, '#');
+    if split_id.next() != Some(MODAL_ID_BASE) {
+        return None;
+    }
+    Some((split_id.next()?, split_id.next()?))
 }
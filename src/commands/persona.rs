@@ -0,0 +1,558 @@
+use std::sync::Arc;
+
+use anyhow::Context;
+use serenity::all::{
+    ActionRowComponent, CommandDataOption, CommandDataOptionValue, CommandInteraction,
+    CommandOptionType, CreateActionRow, CreateAllowedMentions, CreateAttachment, CreateCommand,
+    CreateCommandOption, CreateInputText, CreateInteractionResponse,
+    CreateInteractionResponseMessage, CreateModal, Http, InputTextStyle, InteractionId,
+    ModalInteraction,
+};
+
+use crate::{
+    ai::Ai,
+    attachments,
+    character_card::CharacterCard,
+    constant::{self, subcommands},
+    database::Database,
+    localization,
+    personas::{self, Persona},
+    store::Store,
+    util,
+};
+
+use super::CommandHandler;
+
+/// The amount of each system prompt to show when listing personas.
+const SYSTEM_PROMPT_PREVIEW_LENGTH: usize = 80;
+/// Messages can be up to 2000 characters.
+const MESSAGE_SIZE: usize = 2000;
+/// The largest character card that can be imported. Cards embedded in PNGs include the image.
+const MAX_CARD_SIZE: u32 = 8 * 1024 * 1024;
+
+/// Creates, edits, shares, deletes, imports and exports personas: named system prompts
+/// and parameters that can be used with the `persona` option of configured commands.
+pub struct Handler {
+    ai: Arc<Ai>,
+    database: Arc<Database>,
+    /// Personas being created or edited, waiting for their system prompt form to be submitted
+    pending: Store<InteractionId, Pending>,
+}
+impl Handler {
+    pub fn new(ai: Arc<Ai>, database: Arc<Database>) -> Self {
+        Self {
+            ai,
+            database,
+            pending: Store::default(),
+        }
+    }
+
+    /// Opens the form for the persona's system prompt, prefilled with the existing one
+    /// if it's being edited.
+    async fn open_form(
+        &self,
+        http: &Http,
+        cmd: &CommandInteraction,
+        options: &[CommandDataOption],
+        editing: bool,
+    ) -> anyhow::Result<()> {
+        /// Modal titles can be up to 45 characters.
+        const TITLE_SIZE: usize = 45;
+
+        let name = option_name(options)?;
+        let existing = Persona::find_owned(&self.database, cmd.user.id, &name)?;
+        match (&existing, editing) {
+            (Some(_), false) => anyhow::bail!(
+                "you already have a persona called `{name}`; use `/persona edit` to change it"
+            ),
+            (None, true) => anyhow::bail!("you have no persona called `{name}`"),
+            _ => {}
+        }
+
+        let mut system_prompt = CreateInputText::new(
+            InputTextStyle::Paragraph,
+            localization::text(&cmd.locale, "System prompt"),
+            constant::value::SYSTEM_PROMPT,
+        )
+        .max_length(personas::MAX_SYSTEM_PROMPT_LENGTH)
+        .required(true);
+        if let Some(existing) = &existing {
+            // Personas imported before the limit was enforced may not fit in the form
+            system_prompt = system_prompt.value(util::truncate(
+                &existing.system_prompt,
+                personas::MAX_SYSTEM_PROMPT_LENGTH as usize,
+            ));
+        }
+        let modal = CreateModal::new(
+            super::build_modal_id(constant::commands::PERSONA, &cmd.id.to_string()),
+            util::truncate(
+                &localization::format(&cmd.locale, "Persona: {{name}}", &[("name", &name)]),
+                TITLE_SIZE,
+            ),
+        )
+        .components(vec![CreateActionRow::InputText(system_prompt)]);
+
+        self.pending.insert(
+            cmd.id,
+            Pending {
+                name,
+                model: util::get_value(options, constant::value::MODEL)
+                    .and_then(util::value_to_string),
+                temperature: util::get_value(options, constant::value::TEMPERATURE)
+                    .and_then(util::value_to_number)
+                    .map(|t| t as f32),
+                existing,
+            },
+        );
+        cmd.create_response(http, CreateInteractionResponse::Modal(modal))
+            .await?;
+
+        Ok(())
+    }
+
+    fn share(
+        &self,
+        cmd: &CommandInteraction,
+        options: &[CommandDataOption],
+    ) -> anyhow::Result<String> {
+        let name = option_name(options)?;
+        let guild_id = cmd
+            .guild_id
+            .context("personas can only be shared from within a server")?;
+        let shared = util::get_value(options, constant::value::SHARED)
+            .and_then(util::value_to_bool)
+            .unwrap_or(true);
+
+        let mut persona = Persona::find_owned(&self.database, cmd.user.id, &name)?
+            .with_context(|| format!("you have no persona called `{name}`"))?;
+        persona.guild_id = shared.then_some(guild_id);
+        persona.save(&self.database)?;
+
+        let template = if shared {
+            "Persona `{{name}}` can now be used by everyone in this server."
+        } else {
+            "Persona `{{name}}` can now only be used by you."
+        };
+        Ok(localization::format(
+            &cmd.locale,
+            template,
+            &[("name", &name)],
+        ))
+    }
+
+    fn delete(
+        &self,
+        cmd: &CommandInteraction,
+        options: &[CommandDataOption],
+    ) -> anyhow::Result<String> {
+        let name = option_name(options)?;
+        if !Persona::delete(&self.database, cmd.user.id, &name)? {
+            anyhow::bail!("you have no persona called `{name}`");
+        }
+        Ok(localization::format(
+            &cmd.locale,
+            "Deleted persona `{{name}}`.",
+            &[("name", &name)],
+        ))
+    }
+
+    fn list(&self, cmd: &CommandInteraction) -> anyhow::Result<String> {
+        let personas = Persona::list(&self.database, cmd.user.id, cmd.guild_id)?;
+        if personas.is_empty() {
+            return Ok(localization::text(
+                &cmd.locale,
+                "There are no personas available to you. Create one with `/persona create`.",
+            )
+            .to_string());
+        }
+
+        let locale = cmd.locale.as_str();
+        let mut output = format!("**{}**\n", localization::text(locale, "Personas"));
+        for persona in personas {
+            output.push_str(&format!("- `{}`", persona.name));
+            if persona.owner_id != cmd.user.id {
+                let owner = format!("<@{}>", persona.owner_id);
+                output.push(' ');
+                output.push_str(&localization::format(
+                    locale,
+                    "by {{user}}",
+                    &[("user", &owner)],
+                ));
+            } else if persona.guild_id.is_some() {
+                output.push(' ');
+                output.push_str(localization::text(locale, "(shared)"));
+            }
+            if let Some(model) = &persona.model {
+                output.push_str(", ");
+                output.push_str(&localization::format(
+                    locale,
+                    "using `{{model}}`",
+                    &[("model", model)],
+                ));
+            }
+            if let Some(temperature) = persona.temperature {
+                output.push_str(", ");
+                output.push_str(&localization::format(
+                    locale,
+                    "temperature {{temperature}}",
+                    &[("temperature", &temperature.to_string())],
+                ));
+            }
+
+            let system_prompt = persona.system_prompt.replace('\n', " ");
+            let preview = util::truncate(&system_prompt, SYSTEM_PROMPT_PREVIEW_LENGTH);
+            output.push_str(&format!("\n  -# {preview}"));
+            if preview.len() < system_prompt.len() {
+                output.push('…');
+            }
+            output.push('\n');
+        }
+
+        Ok(util::truncate(&output, MESSAGE_SIZE).to_string())
+    }
+
+    async fn import(
+        &self,
+        cmd: &CommandInteraction,
+        options: &[CommandDataOption],
+    ) -> anyhow::Result<String> {
+        use constant::value as v;
+
+        let attachment = util::get_value(options, v::ATTACHMENT)
+            .and_then(util::value_to_attachment_id)
+            .and_then(|id| cmd.data.resolved.attachments.get(&id))
+            .context("no character card attached")?;
+        let card = CharacterCard::parse(&attachments::download(attachment, MAX_CARD_SIZE).await?)?;
+
+        let name = option_name(options).unwrap_or_else(|_| card.name.trim().to_string());
+        if name.chars().count() > personas::MAX_NAME_LENGTH as usize {
+            anyhow::bail!(
+                "`{name}` is too long to be the name of a persona; choose another with the `name` option"
+            );
+        }
+        if Persona::find_owned(&self.database, cmd.user.id, &name)?.is_some() {
+            anyhow::bail!(
+                "you already have a persona called `{name}`; delete it, or choose another name with the `name` option"
+            );
+        }
+
+        let persona = Persona {
+            name,
+            owner_id: cmd.user.id,
+            guild_id: None,
+            system_prompt: card.persona_system_prompt(),
+            model: util::get_value(options, v::MODEL).and_then(util::value_to_string),
+            temperature: util::get_value(options, v::TEMPERATURE)
+                .and_then(util::value_to_number)
+                .map(|t| t as f32),
+            examples: card.persona_examples(),
+            greeting: card.persona_greeting(),
+        };
+        if persona.system_prompt.is_empty() {
+            anyhow::bail!("the character card has no description");
+        }
+        personas::check_system_prompt(&persona.system_prompt)?;
+        persona.save(&self.database)?;
+
+        let template = if persona.greeting.is_some() {
+            "Imported `{{card}}` as persona `{{name}}`, with {{count}} example messages and a greeting."
+        } else {
+            "Imported `{{card}}` as persona `{{name}}`, with {{count}} example messages."
+        };
+        Ok(localization::format(
+            &cmd.locale,
+            template,
+            &[
+                ("card", &card.name),
+                ("name", &persona.name),
+                ("count", &persona.examples.len().to_string()),
+            ],
+        ))
+    }
+
+    async fn export(
+        &self,
+        http: &Http,
+        cmd: &CommandInteraction,
+        options: &[CommandDataOption],
+    ) -> anyhow::Result<()> {
+        let name = option_name(options)?;
+        let persona = Persona::find(&self.database, cmd.user.id, cmd.guild_id, &name)?
+            .with_context(|| format!("there is no persona called `{name}`"))?;
+        let card = CharacterCard::from_persona(
+            &persona.name,
+            &persona.system_prompt,
+            &persona.examples,
+            persona.greeting.as_deref(),
+        );
+
+        let filename: String = persona
+            .name
+            .chars()
+            .map(|c| {
+                if c.is_alphanumeric() || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        cmd.create_response(
+            http,
+            CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new()
+                    .content(localization::format(
+                        &cmd.locale,
+                        "Persona `{{name}}` as a character card:",
+                        &[("name", &persona.name)],
+                    ))
+                    .add_file(CreateAttachment::bytes(
+                        card.to_json()?.into_bytes(),
+                        format!("{filename}.json"),
+                    ))
+                    .ephemeral(true),
+            ),
+        )
+        .await?;
+
+        Ok(())
+    }
+}
+#[serenity::async_trait]
+impl CommandHandler for Handler {
+    fn name(&self) -> &str {
+        constant::commands::PERSONA
+    }
+
+    async fn register(&self, http: &Http) -> anyhow::Result<()> {
+        let name_option = || {
+            CreateCommandOption::new(
+                CommandOptionType::String,
+                constant::value::NAME,
+                "The name of the persona.",
+            )
+            .max_length(personas::MAX_NAME_LENGTH)
+            .required(true)
+        };
+        let parameter_options = || {
+            let mut model_option = CreateCommandOption::new(
+                CommandOptionType::String,
+                constant::value::MODEL,
+                "The model to use by default.",
+            )
+            .required(false);
+            for model in &self.ai.models {
+                model_option = model_option.add_string_choice(model, model);
+            }
+            [
+                model_option,
+                CreateCommandOption::new(
+                    CommandOptionType::Number,
+                    constant::value::TEMPERATURE,
+                    "The sampling temperature to use.",
+                )
+                .min_number_value(0.0)
+                .max_number_value(2.0)
+                .required(false),
+            ]
+        };
+        let subcommand = |name: &str, description: &str| {
+            CreateCommandOption::new(CommandOptionType::SubCommand, name, description)
+        };
+
+        let with_parameters = |subcommand: CreateCommandOption| {
+            parameter_options()
+                .into_iter()
+                .fold(subcommand, CreateCommandOption::add_sub_option)
+        };
+
+        let create = with_parameters(
+            subcommand(
+                subcommands::CREATE,
+                "Creates a persona, with a form to enter its system prompt in.",
+            )
+            .add_sub_option(name_option()),
+        );
+        let edit = with_parameters(
+            subcommand(
+                subcommands::EDIT,
+                "Edits one of your personas. Parameters that aren't specified are kept.",
+            )
+            .add_sub_option(name_option()),
+        );
+        let import = with_parameters(
+            subcommand(
+                subcommands::IMPORT,
+                "Creates a persona from a character card (JSON or PNG).",
+            )
+            .add_sub_option(
+                CreateCommandOption::new(
+                    CommandOptionType::Attachment,
+                    constant::value::ATTACHMENT,
+                    "The character card.",
+                )
+                .required(true),
+            )
+            .add_sub_option(
+                CreateCommandOption::new(
+                    CommandOptionType::String,
+                    constant::value::NAME,
+                    "The name of the persona. If not specified, the character's name is used.",
+                )
+                .max_length(personas::MAX_NAME_LENGTH)
+                .required(false),
+            ),
+        );
+
+        localization::register(
+            http,
+            CreateCommand::new(constant::commands::PERSONA)
+                .description("Manages personas, which can be used with the `persona` option.")
+                .add_option(create)
+                .add_option(edit)
+                .add_option(
+                    subcommand(
+                        subcommands::SHARE,
+                        "Shares one of your personas with this server, or stops sharing it.",
+                    )
+                    .add_sub_option(name_option())
+                    .add_sub_option(
+                        CreateCommandOption::new(
+                            CommandOptionType::Boolean,
+                            constant::value::SHARED,
+                            "Whether or not everyone in this server can use the persona.",
+                        )
+                        .required(false),
+                    ),
+                )
+                .add_option(
+                    subcommand(subcommands::DELETE, "Deletes one of your personas.")
+                        .add_sub_option(name_option()),
+                )
+                .add_option(subcommand(
+                    subcommands::LIST,
+                    "Lists your personas, and those shared with this server.",
+                ))
+                .add_option(import)
+                .add_option(
+                    subcommand(
+                        subcommands::EXPORT,
+                        "Exports a persona as a character card.",
+                    )
+                    .add_sub_option(name_option()),
+                ),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn run(&self, http: &Http, cmd: &CommandInteraction) -> anyhow::Result<()> {
+        let Some(subcommand) = cmd.data.options.first() else {
+            anyhow::bail!("no subcommand specified");
+        };
+        let CommandDataOptionValue::SubCommand(options) = &subcommand.value else {
+            anyhow::bail!("unknown subcommand `{}`", subcommand.name);
+        };
+
+        let response = match subcommand.name.as_str() {
+            subcommands::CREATE => return self.open_form(http, cmd, options, false).await,
+            subcommands::EDIT => return self.open_form(http, cmd, options, true).await,
+            subcommands::SHARE => self.share(cmd, options)?,
+            subcommands::DELETE => self.delete(cmd, options)?,
+            subcommands::LIST => self.list(cmd)?,
+            subcommands::IMPORT => self.import(cmd, options).await?,
+            subcommands::EXPORT => return self.export(http, cmd, options).await,
+            name => anyhow::bail!("unknown subcommand `{name}`"),
+        };
+        cmd.create_response(http, ephemeral_response(response))
+            .await?;
+
+        Ok(())
+    }
+
+    async fn run_modal(
+        &self,
+        http: &Http,
+        modal: &ModalInteraction,
+        key: &str,
+    ) -> anyhow::Result<()> {
+        let pending = key
+            .parse::<u64>()
+            .ok()
+            .and_then(|id| self.pending.remove(&InteractionId::new(id)))
+            .context("this persona form has expired; please run the command again")?;
+
+        let system_prompt = modal
+            .data
+            .components
+            .iter()
+            .flat_map(|row| &row.components)
+            .find_map(|component| match component {
+                ActionRowComponent::InputText(input)
+                    if input.custom_id == constant::value::SYSTEM_PROMPT =>
+                {
+                    input.value.clone()
+                }
+                _ => None,
+            })
+            .filter(|s| !s.trim().is_empty())
+            .context("no system prompt specified")?;
+
+        let existing = pending.existing.as_ref();
+        let persona = Persona {
+            name: pending.name,
+            owner_id: modal.user.id,
+            guild_id: existing.and_then(|p| p.guild_id),
+            system_prompt,
+            model: pending
+                .model
+                .or_else(|| existing.and_then(|p| p.model.clone())),
+            temperature: pending
+                .temperature
+                .or_else(|| existing.and_then(|p| p.temperature)),
+            examples: existing.map(|p| p.examples.clone()).unwrap_or_default(),
+            greeting: existing.and_then(|p| p.greeting.clone()),
+        };
+        persona.save(&self.database)?;
+
+        modal
+            .create_response(
+                http,
+                ephemeral_response(localization::format(
+                    &modal.locale,
+                    "Saved persona `{{name}}`. Use it with the `persona` option of any command.",
+                    &[("name", &persona.name)],
+                )),
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// A persona being created or edited.
+struct Pending {
+    name: String,
+    model: Option<String>,
+    temperature: Option<f32>,
+    /// The persona being edited, if any
+    existing: Option<Persona>,
+}
+
+fn option_name(options: &[CommandDataOption]) -> anyhow::Result<String> {
+    util::get_value(options, constant::value::NAME)
+        .and_then(util::value_to_string)
+        .map(|name| name.trim().to_string())
+        .filter(|name| !name.is_empty())
+        .context("no name specified")
+}
+
+fn ephemeral_response(content: String) -> CreateInteractionResponse {
+    CreateInteractionResponse::Message(
+        CreateInteractionResponseMessage::new()
+            .content(content)
+            .allowed_mentions(CreateAllowedMentions::new())
+            .ephemeral(true),
+    )
+}
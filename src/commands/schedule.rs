@@ -0,0 +1,387 @@
+use std::sync::Arc;
+
+use anyhow::Context;
+use chrono::Utc;
+use serenity::all::{
+    ChannelType, CommandDataOption, CommandDataOptionValue, CommandInteraction, CommandOptionType,
+    CreateAllowedMentions, CreateCommand, CreateCommandOption, CreateInteractionResponse,
+    CreateInteractionResponseMessage, Http, InteractionContext, Permissions,
+};
+
+use crate::{
+    ai::Ai,
+    constant::{self, subcommands},
+    database::Database,
+    localization,
+    schedules::{self, Job, Schedule, Scheduler},
+    util,
+};
+
+use super::CommandHandler;
+
+/// The amount of each prompt or script to show when listing schedules.
+const PREVIEW_LENGTH: usize = 80;
+/// Messages can be up to 2000 characters.
+const MESSAGE_SIZE: usize = 2000;
+
+/// Creates, lists and deletes scheduled jobs, which run a configured command or a Lua
+/// script on a recurring schedule and post the output to a channel.
+pub struct Handler {
+    ai: Arc<Ai>,
+    database: Arc<Database>,
+    scheduler: Arc<Scheduler>,
+}
+impl Handler {
+    pub fn new(ai: Arc<Ai>, database: Arc<Database>, scheduler: Arc<Scheduler>) -> Self {
+        Self {
+            ai,
+            database,
+            scheduler,
+        }
+    }
+
+    fn create_command(
+        &self,
+        cmd: &CommandInteraction,
+        options: &[CommandDataOption],
+    ) -> anyhow::Result<String> {
+        use constant::value as v;
+
+        let command = util::get_value(options, v::COMMAND)
+            .and_then(util::value_to_string)
+            .context("no command specified")?;
+        if !self
+            .scheduler
+            .schedulable_commands()
+            .contains(&command.as_str())
+        {
+            anyhow::bail!("`/{command}` can't be scheduled");
+        }
+        let prompt = util::get_value(options, v::PROMPT)
+            .and_then(util::value_to_string)
+            .filter(|prompt| !prompt.trim().is_empty())
+            .context("no prompt specified")?;
+        let model = util::get_value(options, v::MODEL).and_then(util::value_to_string);
+
+        self.create(
+            cmd,
+            options,
+            Job::Command {
+                command,
+                prompt,
+                model,
+            },
+        )
+    }
+
+    async fn create_script(
+        &self,
+        http: &Http,
+        cmd: &CommandInteraction,
+        options: &[CommandDataOption],
+    ) -> anyhow::Result<String> {
+        use constant::value as v;
+
+        let message_id = util::get_value(options, v::MESSAGE_ID).and_then(util::value_to_string);
+        let code = util::get_value(options, v::CODE).and_then(util::value_to_string);
+        let code = match (message_id, code) {
+            (Some(message_id), None) => {
+                cmd.channel_id
+                    .message(http, message_id.parse::<u64>()?)
+                    .await?
+                    .content
+            }
+            (None, Some(code)) => code,
+            (Some(_), Some(_)) => anyhow::bail!("message ID and code are mutually exclusive"),
+            (None, None) => anyhow::bail!("no message ID or code specified"),
+        };
+        if code.trim().is_empty() {
+            anyhow::bail!("the script is empty");
+        }
+
+        self.create(cmd, options, Job::Script { code })
+    }
+
+    /// Saves a schedule for `job` with the cron expression and channel from the options.
+    fn create(
+        &self,
+        cmd: &CommandInteraction,
+        options: &[CommandDataOption],
+        job: Job,
+    ) -> anyhow::Result<String> {
+        use constant::value as v;
+
+        let guild_id = cmd
+            .guild_id
+            .context("jobs can only be scheduled in a server")?;
+        let cron = util::get_value(options, v::CRON)
+            .and_then(util::value_to_string)
+            .map(|cron| cron.trim().to_string())
+            .context("no cron expression specified")?;
+        schedules::parse_cron(&cron)?;
+        let channel_id = util::get_value(options, v::CHANNEL)
+            .and_then(util::value_to_channel_id)
+            .unwrap_or(cmd.channel_id);
+
+        let schedule =
+            Schedule::create(&self.database, guild_id, channel_id, cmd.user.id, cron, job)?;
+        let (template, time) = match next_run(&schedule) {
+            Some(time) => (
+                "Scheduled job `#{{id}}`, which will post to {{channel}}, next running {{time}}.",
+                time,
+            ),
+            None => (
+                "Scheduled job `#{{id}}`, which will post to {{channel}}, but will never run.",
+                String::new(),
+            ),
+        };
+        Ok(localization::format(
+            &cmd.locale,
+            template,
+            &[
+                ("id", &schedule.id.to_string()),
+                ("channel", &format!("<#{}>", schedule.channel_id)),
+                ("time", &time),
+            ],
+        ))
+    }
+
+    fn delete(
+        &self,
+        cmd: &CommandInteraction,
+        options: &[CommandDataOption],
+    ) -> anyhow::Result<String> {
+        let guild_id = cmd
+            .guild_id
+            .context("schedules can only be managed in a server")?;
+        let id = util::get_value(options, constant::value::ID)
+            .and_then(util::value_to_integer)
+            .context("no ID specified")?;
+        if !Schedule::delete(&self.database, guild_id, id)? {
+            anyhow::bail!("there is no scheduled job `#{id}` in this server");
+        }
+        Ok(localization::format(
+            &cmd.locale,
+            "Deleted scheduled job `#{{id}}`.",
+            &[("id", &id.to_string())],
+        ))
+    }
+
+    fn list(&self, cmd: &CommandInteraction) -> anyhow::Result<String> {
+        let guild_id = cmd
+            .guild_id
+            .context("schedules can only be managed in a server")?;
+        let schedules = Schedule::list(&self.database, guild_id)?;
+        if schedules.is_empty() {
+            return Ok(localization::text(
+                &cmd.locale,
+                "There are no scheduled jobs in this server. Create one with `/schedule command` or `/schedule script`.",
+            )
+            .to_string());
+        }
+
+        let locale = cmd.locale.as_str();
+        let mut output = format!("**{}**\n", localization::text(locale, "Scheduled jobs"));
+        for schedule in schedules {
+            let (kind, text) = match &schedule.job {
+                Job::Command {
+                    command, prompt, ..
+                } => (format!("`/{command}`"), prompt),
+                Job::Script { code } => {
+                    (localization::text(locale, "Lua script").to_string(), code)
+                }
+            };
+            let (template, time) = match next_run(&schedule) {
+                Some(time) => (
+                    "{{job}} in {{channel}} by {{user}}, next running {{time}}",
+                    time,
+                ),
+                None => (
+                    "{{job}} in {{channel}} by {{user}}, but will never run",
+                    String::new(),
+                ),
+            };
+            let description = localization::format(
+                locale,
+                template,
+                &[
+                    ("job", &kind),
+                    ("channel", &format!("<#{}>", schedule.channel_id)),
+                    ("user", &format!("<@{}>", schedule.owner_id)),
+                    ("time", &time),
+                ],
+            );
+            output.push_str(&format!(
+                "- `#{}` `{}`: {description}",
+                schedule.id, schedule.cron,
+            ));
+
+            let text = text.replace('\n', " ");
+            let preview = util::truncate(&text, PREVIEW_LENGTH);
+            output.push_str(&format!("\n  -# {preview}"));
+            if preview.len() < text.len() {
+                output.push('…');
+            }
+            output.push('\n');
+        }
+
+        Ok(util::truncate(&output, MESSAGE_SIZE).to_string())
+    }
+}
+#[serenity::async_trait]
+impl CommandHandler for Handler {
+    fn name(&self) -> &str {
+        constant::commands::SCHEDULE
+    }
+
+    async fn register(&self, http: &Http) -> anyhow::Result<()> {
+        use constant::value as v;
+
+        let cron_option = || {
+            CreateCommandOption::new(
+                CommandOptionType::String,
+                v::CRON,
+                "When to run, as a cron expression in UTC (e.g. `0 9 * * MON-FRI`).",
+            )
+            .required(true)
+        };
+        let channel_option = || {
+            CreateCommandOption::new(
+                CommandOptionType::Channel,
+                v::CHANNEL,
+                "The channel to post to. Defaults to this channel.",
+            )
+            .channel_types(vec![ChannelType::Text, ChannelType::News])
+            .required(false)
+        };
+        let subcommand = |name: &str, description: &str| {
+            CreateCommandOption::new(CommandOptionType::SubCommand, name, description)
+        };
+
+        let mut command_option =
+            CreateCommandOption::new(CommandOptionType::String, v::COMMAND, "The command to run.")
+                .required(true);
+        for command in self.scheduler.schedulable_commands() {
+            command_option = command_option.add_string_choice(command, command);
+        }
+        let mut model_option = CreateCommandOption::new(
+            CommandOptionType::String,
+            v::MODEL,
+            "The model to use. Defaults to your preferred model.",
+        )
+        .required(false);
+        for model in &self.ai.models {
+            model_option = model_option.add_string_choice(model, model);
+        }
+
+        localization::register(
+            http,
+            CreateCommand::new(constant::commands::SCHEDULE)
+                .description("Manages jobs that post to a channel on a recurring schedule.")
+                .default_member_permissions(Permissions::MANAGE_GUILD)
+                .contexts(vec![InteractionContext::Guild])
+                .add_option(
+                    subcommand(
+                        subcommands::COMMAND,
+                        "Schedules a command to run with a fixed prompt.",
+                    )
+                    .add_sub_option(cron_option())
+                    .add_sub_option(command_option)
+                    .add_sub_option(
+                        CreateCommandOption::new(
+                            CommandOptionType::String,
+                            v::PROMPT,
+                            "The prompt to run the command with.",
+                        )
+                        .required(true),
+                    )
+                    .add_sub_option(channel_option())
+                    .add_sub_option(model_option),
+                )
+                .add_option(
+                    subcommand(subcommands::SCRIPT, "Schedules a Lua script to run.")
+                        .add_sub_option(cron_option())
+                        .add_sub_option(
+                            CreateCommandOption::new(
+                                CommandOptionType::String,
+                                v::CODE,
+                                "The Lua code block to run. Mutually exclusive with message ID.",
+                            )
+                            .required(false),
+                        )
+                        .add_sub_option(
+                            CreateCommandOption::new(
+                                CommandOptionType::String,
+                                v::MESSAGE_ID,
+                                "The ID of the message with the code block to run. Mutually exclusive with code.",
+                            )
+                            .required(false),
+                        )
+                        .add_sub_option(channel_option()),
+                )
+                .add_option(subcommand(
+                    subcommands::LIST,
+                    "Lists the scheduled jobs in this server.",
+                ))
+                .add_option(
+                    subcommand(subcommands::DELETE, "Deletes a scheduled job.").add_sub_option(
+                        CreateCommandOption::new(
+                            CommandOptionType::Integer,
+                            v::ID,
+                            "The ID of the job, as shown by `/schedule list`.",
+                        )
+                        .required(true),
+                    ),
+                ),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn run(&self, http: &Http, cmd: &CommandInteraction) -> anyhow::Result<()> {
+        // The default permissions can be changed by the server, so check them here too
+        let can_manage = cmd
+            .member
+            .as_ref()
+            .and_then(|member| member.permissions)
+            .is_some_and(|permissions| permissions.manage_guild());
+        if !can_manage {
+            anyhow::bail!("only members who can manage this server can schedule jobs");
+        }
+
+        let Some(subcommand) = cmd.data.options.first() else {
+            anyhow::bail!("no subcommand specified");
+        };
+        let CommandDataOptionValue::SubCommand(options) = &subcommand.value else {
+            anyhow::bail!("unknown subcommand `{}`", subcommand.name);
+        };
+
+        let response = match subcommand.name.as_str() {
+            subcommands::COMMAND => self.create_command(cmd, options)?,
+            subcommands::SCRIPT => self.create_script(http, cmd, options).await?,
+            subcommands::LIST => self.list(cmd)?,
+            subcommands::DELETE => self.delete(cmd, options)?,
+            name => anyhow::bail!("unknown subcommand `{name}`"),
+        };
+        cmd.create_response(
+            http,
+            CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new()
+                    .content(response)
+                    .allowed_mentions(CreateAllowedMentions::new())
+                    .ephemeral(true),
+            ),
+        )
+        .await?;
+
+        Ok(())
+    }
+}
+
+/// When the schedule will next run, as a relative Discord timestamp, if it ever will.
+fn next_run(schedule: &Schedule) -> Option<String> {
+    schedule
+        .next_run(Utc::now())
+        .map(|time| format!("<t:{}:R>", time.timestamp()))
+}
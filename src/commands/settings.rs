@@ -0,0 +1,164 @@
+use std::sync::Arc;
+
+use serenity::all::{
+    CommandInteraction, CommandOptionType, CreateCommand, CreateCommandOption,
+    CreateInteractionResponse, CreateInteractionResponseMessage, Http,
+};
+
+use crate::{ai::Ai, constant, database::Database, localization, preferences::Preferences, util};
+
+use super::CommandHandler;
+
+/// The longest preferred language that can be set.
+const MAX_LANGUAGE_LENGTH: u16 = 50;
+
+/// Shows and changes the user's preferences.
+pub struct Handler {
+    ai: Arc<Ai>,
+    database: Arc<Database>,
+}
+impl Handler {
+    pub fn new(ai: Arc<Ai>, database: Arc<Database>) -> Self {
+        Self { ai, database }
+    }
+}
+#[serenity::async_trait]
+impl CommandHandler for Handler {
+    fn name(&self) -> &str {
+        constant::commands::SETTINGS
+    }
+
+    async fn register(&self, http: &Http) -> anyhow::Result<()> {
+        let mut model_option = CreateCommandOption::new(
+            CommandOptionType::String,
+            constant::value::MODEL,
+            "The model to use by default.",
+        )
+        .required(false);
+        for model in &self.ai.models {
+            model_option = model_option.add_string_choice(model, model);
+        }
+
+        localization::register(
+            http,
+            CreateCommand::new(constant::commands::SETTINGS)
+                .description("Shows or changes your settings, which are used when an option is not specified.")
+                .add_option(model_option)
+                .add_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::Boolean,
+                        constant::value::PRIVATE,
+                        "Whether or not to only show responses to you by default.",
+                    )
+                    .required(false),
+                )
+                .add_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::String,
+                        constant::value::LANGUAGE,
+                        "Your preferred language, for commands that support it.",
+                    )
+                    .max_length(MAX_LANGUAGE_LENGTH)
+                    .required(false),
+                )
+                .add_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::Number,
+                        constant::value::TEMPERATURE,
+                        "The sampling temperature to use.",
+                    )
+                    .min_number_value(0.0)
+                    .max_number_value(2.0)
+                    .required(false),
+                )
+                .add_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::Boolean,
+                        constant::value::RESET,
+                        "Whether or not to clear your settings before applying any others.",
+                    )
+                    .required(false),
+                ),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn run(&self, http: &Http, cmd: &CommandInteraction) -> anyhow::Result<()> {
+        use constant::value as v;
+
+        let options = &cmd.data.options;
+        let reset = util::get_value(options, v::RESET)
+            .and_then(util::value_to_bool)
+            .unwrap_or(false);
+
+        let mut preferences = if reset {
+            Preferences::default()
+        } else {
+            Preferences::load(&self.database, cmd.user.id)?
+        };
+        if let Some(model) = util::get_value(options, v::MODEL).and_then(util::value_to_string) {
+            preferences.model = Some(model);
+        }
+        if let Some(private) = util::get_value(options, v::PRIVATE).and_then(util::value_to_bool) {
+            preferences.private = Some(private);
+        }
+        if let Some(language) =
+            util::get_value(options, v::LANGUAGE).and_then(util::value_to_string)
+        {
+            preferences.language = Some(language.trim().to_string()).filter(|l| !l.is_empty());
+        }
+        if let Some(temperature) =
+            util::get_value(options, v::TEMPERATURE).and_then(util::value_to_number)
+        {
+            preferences.temperature = Some(temperature as f32);
+        }
+        if reset || !options.is_empty() {
+            preferences.save(&self.database, cmd.user.id)?;
+        }
+
+        let locale = cmd.locale.as_str();
+        let or_not_set = |value: Option<String>| {
+            value.unwrap_or_else(|| localization::text(locale, "*not set*").to_string())
+        };
+        let response = localization::format(
+            locale,
+            "**Your settings**\n\
+            Model: {{model}}\n\
+            Private: {{private}}\n\
+            Language: {{language}}\n\
+            Temperature: {{temperature}}\n\
+            -# These are used when the corresponding option is not specified.",
+            &[
+                (
+                    "model",
+                    &or_not_set(preferences.model.map(|m| format!("`{m}`"))),
+                ),
+                (
+                    "private",
+                    &or_not_set(preferences.private.map(|p| {
+                        localization::text(locale, if p { "yes" } else { "no" }).to_string()
+                    })),
+                ),
+                ("language", &or_not_set(preferences.language)),
+                (
+                    "temperature",
+                    &or_not_set(preferences.temperature.map(|t| t.to_string())),
+                ),
+            ],
+        );
+
+        cmd.create_response(
+            http,
+            CreateInteractionResponse::Message(
+                CreateInteractionResponseMessage::new()
+                    .content(response)
+                    .ephemeral(true),
+            ),
+        )
+        .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,470 @@
+use std::{collections::HashMap, sync::Arc};
+
+use anyhow::Context as _;
+use serenity::all::{
+    ChannelId, CommandInteraction, CommandOptionType, CreateCommand, CreateCommandOption,
+    GetMessages, Http, Message, MessageId,
+};
+
+use crate::{
+    ai::Ai, config, constant, database::Database, localization, mentions, outputter::Destination,
+    preferences::Preferences, util,
+};
+
+use super::{CommandHandler, hallucinate};
+
+/// The number of messages summarized if neither a count nor a starting point is given.
+const DEFAULT_COUNT: u16 = 100;
+/// The most messages that can be summarized at once.
+const MAX_COUNT: u16 = 500;
+/// The most messages Discord returns per request.
+const PAGE_SIZE: u8 = 100;
+/// The context length assumed for models that haven't configured one, in tokens.
+const DEFAULT_CONTEXT_LENGTH: usize = 8192;
+/// A rough estimate, as the tokenizer isn't known.
+const CHARS_PER_TOKEN: usize = 4;
+/// The fraction of the context left for the system prompt and the summary itself.
+const RESERVED_CONTEXT_FRACTION: usize = 4;
+/// Discord's epoch, in milliseconds since the Unix epoch, which snowflakes count from.
+const DISCORD_EPOCH_MS: u64 = 1_420_070_400_000;
+
+const SYSTEM_PROMPT: &str = "You summarize Discord conversations. The user will give you a transcript \
+of a channel, with one message per line, each starting with when it was sent (in UTC) and who sent it. \
+{{style}}{{focus}} Refer to people by name, and only include what is in the transcript.";
+
+/// Summarizes the recent messages of the current channel or thread.
+pub struct Handler {
+    base: hallucinate::Handler,
+    ai: Arc<Ai>,
+    database: Arc<Database>,
+}
+impl Handler {
+    pub fn new(
+        discord_config: config::Discord,
+        cancel_rx: flume::Receiver<MessageId>,
+        ai: Arc<Ai>,
+        shared: hallucinate::Shared,
+    ) -> Self {
+        let database = shared.database.clone();
+        let base = hallucinate::Handler::new(
+            command(),
+            constant::commands::SUMMARIZE.to_string(),
+            discord_config,
+            cancel_rx,
+            ai.clone(),
+            shared,
+        );
+        Self { base, ai, database }
+    }
+}
+#[serenity::async_trait]
+impl CommandHandler for Handler {
+    fn name(&self) -> &str {
+        constant::commands::SUMMARIZE
+    }
+
+    async fn register(&self, http: &Http) -> anyhow::Result<()> {
+        let mut model_option = CreateCommandOption::new(
+            CommandOptionType::String,
+            constant::value::MODEL,
+            "The model to use.",
+        )
+        .required(false);
+        for model in &self.ai.models {
+            model_option = model_option.add_string_choice(model, model);
+        }
+
+        let mut style_option = CreateCommandOption::new(
+            CommandOptionType::String,
+            constant::value::STYLE,
+            "How to write the summary. Defaults to bullet points.",
+        )
+        .required(false);
+        for style in Style::ALL {
+            style_option = style_option.add_string_choice(style.label(), style.name());
+        }
+
+        localization::register(
+            http,
+            CreateCommand::new(constant::commands::SUMMARIZE)
+                .description("Summarizes the recent messages in this channel.")
+                .add_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::Integer,
+                        constant::value::COUNT,
+                        format!("The number of recent messages to summarize. Defaults to {DEFAULT_COUNT}."),
+                    )
+                    .min_int_value(1)
+                    .max_int_value(MAX_COUNT.into())
+                    .required(false),
+                )
+                .add_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::String,
+                        constant::value::SINCE,
+                        "Summarize from a message (link or ID), a timestamp, or a time ago (e.g. 2h).",
+                    )
+                    .required(false),
+                )
+                .add_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::String,
+                        constant::value::FOCUS,
+                        "A topic to focus the summary on.",
+                    )
+                    .max_length(200)
+                    .required(false),
+                )
+                .add_option(style_option)
+                .add_option(model_option)
+                .add_option(
+                    CreateCommandOption::new(
+                        CommandOptionType::Boolean,
+                        constant::value::PRIVATE,
+                        "Whether or not to only show the summary to you.",
+                    )
+                    .required(false),
+                ),
+        )
+        .await?;
+
+        Ok(())
+    }
+
+    async fn run(&self, http: &Http, cmd: &CommandInteraction) -> anyhow::Result<()> {
+        use constant::value as v;
+        use util::{value_to_integer, value_to_string};
+
+        let options = &cmd.data.options;
+        let since = util::get_value(options, v::SINCE)
+            .and_then(value_to_string)
+            .map(|since| {
+                parse_since(&since, unix_time_ms()).with_context(|| {
+                    format!("`{since}` is not a message link, message ID, timestamp or duration")
+                })
+            })
+            .transpose()?;
+        let count = util::get_value(options, v::COUNT)
+            .and_then(value_to_integer)
+            .map(|count| count.clamp(1, MAX_COUNT.into()) as u16)
+            .unwrap_or(if since.is_some() {
+                MAX_COUNT
+            } else {
+                DEFAULT_COUNT
+            });
+        let style = util::get_value(options, v::STYLE)
+            .and_then(value_to_string)
+            .and_then(|style| Style::from_name(&style))
+            .unwrap_or(Style::Bullets);
+        let focus = util::get_value(options, v::FOCUS).and_then(value_to_string);
+
+        // The model is needed up front to know how much of the channel fits
+        let model = match util::get_value(options, v::MODEL).and_then(value_to_string) {
+            Some(model) => model,
+            None => Preferences::load(&self.database, cmd.user.id)?
+                .model
+                .context("no model specified; choose one, or set a default with `/settings`")?,
+        };
+        let context_length = self
+            .ai
+            .context_length(&model)
+            .unwrap_or(DEFAULT_CONTEXT_LENGTH);
+        let budget = context_length * CHARS_PER_TOKEN / RESERVED_CONTEXT_FRACTION
+            * (RESERVED_CONTEXT_FRACTION - 1);
+
+        let messages = fetch_messages(http, cmd.channel_id, since, count).await?;
+        let lines: Vec<_> = messages.iter().filter_map(format_message).collect();
+        let (transcript, included) = fit_transcript(&lines, budget);
+        if included == 0 {
+            anyhow::bail!("there are no messages to summarize");
+        }
+
+        let variables = HashMap::from_iter([
+            (v::STYLE.to_string(), style.instruction().to_string()),
+            (
+                v::FOCUS.to_string(),
+                focus
+                    .map(|focus| format!(" Focus on {focus}, leaving out anything unrelated."))
+                    .unwrap_or_default(),
+            ),
+        ]);
+        self.base
+            .run(
+                http,
+                Destination::Interaction(cmd),
+                hallucinate::Request {
+                    title: localization::format(
+                        &cmd.locale,
+                        if included == 1 {
+                            "Summary of 1 message in {{channel}}"
+                        } else {
+                            "Summary of {{count}} messages in {{channel}}"
+                        },
+                        &[
+                            ("count", &included.to_string()),
+                            ("channel", &format!("<#{}>", cmd.channel_id)),
+                        ],
+                    ),
+                    prompt: transcript,
+                    model: Some(model),
+                    seed: 0,
+                    variables,
+                    attachments: vec![],
+                    system_prompt: None,
+                    private: util::get_value(options, v::PRIVATE).and_then(util::value_to_bool),
+                    persona: None,
+                    use_cache: true,
+                },
+            )
+            .await
+    }
+}
+
+/// The command that summaries are generated with.
+fn command() -> config::Command {
+    config::Command {
+        enabled: true,
+        description: String::new(),
+        system_prompt: SYSTEM_PROMPT.to_string(),
+        prompt_template: None,
+        options: HashMap::new(),
+        examples: vec![],
+        examples_path: None,
+        context_menu: None,
+        hide_reasoning: true,
+        tools: HashMap::new(),
+        response_format: None,
+        embed: None,
+        private: false,
+        documents: None,
+    }
+}
+
+/// How the summary is written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Style {
+    Bullets,
+    Paragraphs,
+    Brief,
+}
+impl Style {
+    const ALL: [Style; 3] = [Style::Bullets, Style::Paragraphs, Style::Brief];
+
+    fn name(self) -> &'static str {
+        match self {
+            Style::Bullets => "bullets",
+            Style::Paragraphs => "paragraphs",
+            Style::Brief => "brief",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|style| style.name() == name)
+    }
+
+    /// The name of the style's choice, which is translated when the command is registered.
+    fn label(self) -> &'static str {
+        match self {
+            Style::Bullets => "Bullet points",
+            Style::Paragraphs => "Paragraphs",
+            Style::Brief => "One or two sentences",
+        }
+    }
+
+    fn instruction(self) -> &'static str {
+        match self {
+            Style::Bullets => "Summarize it as a bulleted list of the key points.",
+            Style::Paragraphs => "Summarize it in a few short paragraphs.",
+            Style::Brief => "Summarize it in one or two sentences.",
+        }
+    }
+}
+
+/// Fetches up to `count` messages from the channel, oldest first: either the latest
+/// messages, or those after `since`.
+async fn fetch_messages(
+    http: &Http,
+    channel_id: ChannelId,
+    since: Option<MessageId>,
+    count: u16,
+) -> anyhow::Result<Vec<Message>> {
+    let mut messages: Vec<Message> = vec![];
+    let mut cursor = since;
+    while messages.len() < count as usize {
+        let limit = (count as usize - messages.len()).min(PAGE_SIZE as usize) as u8;
+        let request = GetMessages::new().limit(limit);
+        let request = match (since, cursor) {
+            (Some(_), Some(cursor)) => request.after(cursor),
+            (None, Some(cursor)) => request.before(cursor),
+            (_, None) => request,
+        };
+        let page = channel_id
+            .messages(http, request)
+            .await
+            .context("failed to fetch the channel's messages")?;
+        let page_len = page.len();
+        // Pages run forwards from `since`, or backwards from the latest message
+        cursor = if since.is_some() {
+            page.iter().map(|m| m.id).max()
+        } else {
+            page.iter().map(|m| m.id).min()
+        };
+        messages.extend(page);
+        if page_len < limit as usize || cursor.is_none() {
+            break;
+        }
+    }
+
+    messages.sort_by_key(|m| m.id);
+    messages.dedup_by_key(|m| m.id);
+    Ok(messages)
+}
+
+/// Formats a message as a line of the transcript, if it has anything to summarize.
+fn format_message(message: &Message) -> Option<String> {
+    let author = message
+        .member
+        .as_ref()
+        .and_then(|m| m.nick.as_deref())
+        .unwrap_or(message.author.display_name());
+    let mut content = message.content.trim().to_string();
+    for attachment in &message.attachments {
+        content.push_str(&format!(" [attachment: {}]", attachment.filename));
+    }
+    for embed in &message.embeds {
+        if let Some(text) = embed.title.as_deref().or(embed.description.as_deref()) {
+            content.push_str(&format!(" [embed: {}]", text.trim()));
+        }
+    }
+    let content = content.trim();
+    if content.is_empty() {
+        return None;
+    }
+
+    let time = mentions::format_timestamp(message.timestamp.unix_timestamp(), None)?;
+    Some(format!("[{time}] {author}: {content}"))
+}
+
+/// Joins as many of the most recent lines as fit in `budget` characters, returning the
+/// transcript and the number of lines in it.
+fn fit_transcript(lines: &[String], budget: usize) -> (String, usize) {
+    let mut length = 0;
+    let included = lines
+        .iter()
+        .rev()
+        .take_while(|line| {
+            length += line.len() + 1;
+            length <= budget
+        })
+        .count();
+
+    let mut transcript = String::new();
+    let omitted = lines.len() - included;
+    if omitted > 0 {
+        transcript.push_str(&format!(
+            "({omitted} earlier messages were left out to fit the context.)\n"
+        ));
+    }
+    transcript.push_str(&lines[omitted..].join("\n"));
+    (transcript, included)
+}
+
+/// Parses where to summarize from: a message link or ID, a Unix timestamp in seconds
+/// (`1700000000`, or `<t:1700000000>` as Discord formats it), or a duration before
+/// `now_ms` (e.g. `30m`, `2h` or `1d`).
+fn parse_since(since: &str, now_ms: u64) -> Option<MessageId> {
+    // Any snowflake made after the first few milliseconds of Discord's epoch is at least
+    // this large, while Unix timestamps in seconds stay below it until 2106
+    const MIN_SNOWFLAKE: u64 = 1 << 32;
+
+    let since = since.trim();
+    if let Some(id) = since
+        .strip_prefix("https://")
+        .and_then(|link| link.rsplit('/').next())
+        .and_then(|id| id.parse::<u64>().ok())
+        .filter(|&id| id != 0)
+    {
+        return Some(MessageId::new(id));
+    }
+    if let Some(id) = since.parse::<u64>().ok().filter(|&id| id >= MIN_SNOWFLAKE) {
+        return Some(MessageId::new(id));
+    }
+
+    let time_ms = if let Ok(seconds) = since.parse::<u64>() {
+        seconds * 1000
+    } else if let Some(timestamp) = since.strip_prefix("<t:") {
+        let seconds = timestamp.trim_end_matches('>').split(':').next()?;
+        seconds.parse::<u64>().ok()? * 1000
+    } else {
+        let unit = since.chars().last()?;
+        let amount: u64 = since[..since.len() - unit.len_utf8()].trim().parse().ok()?;
+        let unit_ms = match unit {
+            'm' => 60 * 1000,
+            'h' => 60 * 60 * 1000,
+            'd' => 24 * 60 * 60 * 1000,
+            'w' => 7 * 24 * 60 * 60 * 1000,
+            _ => return None,
+        };
+        now_ms.checked_sub(amount.checked_mul(unit_ms)?)?
+    };
+
+    // Snowflakes start with the time they were created, so this sorts before any
+    // message sent from then on
+    let snowflake = time_ms.checked_sub(DISCORD_EPOCH_MS)? << 22;
+    Some(MessageId::new(snowflake.max(1)))
+}
+
+fn unix_time_ms() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_since() {
+        let now_ms = DISCORD_EPOCH_MS + 10 * 60 * 60 * 1000;
+        assert_eq!(
+            parse_since("https://discord.com/channels/1/2/345", now_ms),
+            Some(MessageId::new(345))
+        );
+        assert_eq!(
+            parse_since("1234567890123456789", now_ms),
+            Some(MessageId::new(1234567890123456789))
+        );
+        assert_eq!(
+            parse_since("1700000000", now_ms),
+            Some(MessageId::new((1_700_000_000_000 - DISCORD_EPOCH_MS) << 22))
+        );
+        assert_eq!(parse_since("345", now_ms), None);
+        assert_eq!(
+            parse_since("2h", now_ms),
+            Some(MessageId::new((8 * 60 * 60 * 1000) << 22))
+        );
+        assert_eq!(
+            parse_since(&format!("<t:{}:R>", DISCORD_EPOCH_MS / 1000 + 60), now_ms),
+            Some(MessageId::new((60 * 1000) << 22))
+        );
+        assert_eq!(parse_since("yesterday", now_ms), None);
+    }
+
+    #[test]
+    fn test_fit_transcript() {
+        let lines: Vec<_> = ["first", "second", "third"].map(String::from).to_vec();
+        assert_eq!(
+            fit_transcript(&lines, 100),
+            ("first\nsecond\nthird".to_string(), 3)
+        );
+        assert_eq!(
+            fit_transcript(&lines, 13),
+            (
+                "(1 earlier messages were left out to fit the context.)\nsecond\nthird".to_string(),
+                2
+            )
+        );
+    }
+}
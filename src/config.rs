@@ -1,6 +1,22 @@
 use anyhow::Context;
 use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, path::PathBuf};
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use crate::constant;
+
+/// Option names that are always registered, and cannot be used by custom options.
+pub const RESERVED_OPTION_NAMES: &[&str] = &[
+    constant::value::MODEL,
+    constant::value::PROMPT,
+    constant::value::SEED,
+    constant::value::ATTACHMENT,
+    constant::value::PRIVATE,
+    constant::value::PERSONA,
+    constant::value::CACHE,
+];
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(default)]
@@ -8,6 +24,13 @@ pub struct Configuration {
     pub authentication: Authentication,
     pub commands: HashMap<String, Command>,
     pub discord: Discord,
+    /// Per-model settings, keyed by the model's ID.
+    pub models: HashMap<String, Model>,
+    pub moderation: Moderation,
+    pub cache: Cache,
+    pub queue: Queue,
+    /// Collections of documents that commands can answer from, keyed by their name.
+    pub documents: HashMap<String, Documents>,
 }
 impl Default for Configuration {
     fn default() -> Self {
@@ -23,9 +46,25 @@ impl Default for Configuration {
                     enabled: false,
                     description: "Responds to the provided instruction.".into(),
                     system_prompt: "You are a helpful assistant.".into(),
+                    prompt_template: None,
+                    options: HashMap::new(),
+                    examples: vec![],
+                    examples_path: None,
+                    context_menu: None,
+                    hide_reasoning: false,
+                    tools: HashMap::new(),
+                    response_format: None,
+                    embed: None,
+                    private: false,
+                    documents: None,
                 },
             )]),
             discord: Discord::default(),
+            models: HashMap::new(),
+            moderation: Moderation::default(),
+            cache: Cache::default(),
+            queue: Queue::default(),
+            documents: HashMap::new(),
         }
     }
 }
@@ -33,16 +72,75 @@ impl Configuration {
     const FILENAME: &str = "config.toml";
 
     pub fn load() -> anyhow::Result<Self> {
-        let config = if let Ok(file) = std::fs::read_to_string(Self::FILENAME) {
+        let mut config: Self = if let Ok(file) = std::fs::read_to_string(Self::FILENAME) {
             toml::from_str(&file).context("failed to load config")?
         } else {
             Self::default()
         };
         config.save()?;
+        config.validate()?;
+
+        // Read example files once, after saving so that they aren't written into the config
+        for (name, command) in &mut config.commands {
+            if !command.enabled {
+                continue;
+            }
+            if let Some(path) = &command.examples_path {
+                let examples = load_examples_file(path)
+                    .with_context(|| format!("failed to load examples for command `{name}`"))?;
+                command.examples.extend(examples);
+            }
+        }
 
         Ok(config)
     }
 
+    /// Checks for mistakes that can't be caught while parsing, so that they are reported
+    /// at startup rather than when the commands are registered.
+    fn validate(&self) -> anyhow::Result<()> {
+        // Commands are looked up by name, whether they are slash or context-menu commands
+        let mut names: HashSet<&str> = [
+            constant::commands::EXECUTE_THIS_CODE_BLOCK,
+            constant::commands::EXECUTE,
+            constant::commands::COMPARE,
+            constant::commands::SETTINGS,
+            constant::commands::PERSONA,
+            constant::commands::SUMMARIZE,
+            constant::commands::SCHEDULE,
+        ]
+        .into();
+        for (name, command) in &self.commands {
+            if !names.insert(name) {
+                anyhow::bail!("command name `{name}` is already used by another command");
+            }
+            if let Some(context_menu) = &command.context_menu {
+                if !names.insert(&context_menu.name) {
+                    anyhow::bail!(
+                        "context menu name `{}` of command `{name}` is already used by another command",
+                        context_menu.name
+                    );
+                }
+            }
+        }
+
+        for (name, command) in self.commands.iter().filter(|(_, c)| c.enabled) {
+            if let Some(option) = command
+                .options
+                .keys()
+                .find(|option| RESERVED_OPTION_NAMES.contains(&option.as_str()))
+            {
+                anyhow::bail!("command `{name}` uses reserved option name `{option}`");
+            }
+            // Context-menu commands can't take options, so required ones would be left empty
+            if command.context_menu.is_some() && command.options.values().any(|o| o.required) {
+                anyhow::bail!(
+                    "command `{name}` has required options, so it can't have a context menu"
+                );
+            }
+        }
+        Ok(())
+    }
+
     fn save(&self) -> anyhow::Result<()> {
         Ok(std::fs::write(
             Self::FILENAME,
@@ -58,24 +156,42 @@ pub struct Authentication {
     pub openai_api_key: Option<String>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
 pub struct Model {
-    pub path: PathBuf,
-    pub context_token_length: usize,
-    /// Whether or not to use GPU support. Note that `llmcord` must be
-    /// compiled with GPU support for this to work.
-    pub use_gpu: bool,
-    /// The number of layers to offload to the GPU (if `use_gpu` is on).
-    /// If not set, all layers will be offloaded.
-    pub gpu_layers: Option<usize>,
+    /// Whether or not the model accepts images as input
+    pub vision: bool,
+    /// Whether or not the model's prompt template opens its `<think>` block, so that its
+    /// output only has the closing tag
+    pub implicit_think_tag: bool,
+    /// The most generations with this model to run at once, in addition to the limit
+    /// for the API server
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_concurrent: Option<usize>,
+    /// The number of tokens the model can take as input, which long inputs (such as the
+    /// messages for `/summarize`) are fitted to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context_length: Option<usize>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
 pub struct Discord {
     /// Low values will result in you getting throttled by Discord
     pub message_update_interval_ms: u64,
     /// Whether or not to replace '\n' with newlines
     pub replace_newlines: bool,
+    /// The maximum size, in bytes, of text files attached to prompts
+    pub max_attachment_size: u32,
+    /// The maximum size, in bytes, of images attached to prompts
+    pub max_image_size: u32,
+    /// Whether or not to show generation statistics below responses, along with
+    /// a button to show more details
+    pub show_statistics: bool,
+    /// What to do with outputs that need more than `max_output_messages` messages
+    pub overflow: Overflow,
+    /// The number of messages an output can use before `overflow` applies
+    pub max_output_messages: usize,
 }
 
 impl Default for Discord {
@@ -83,13 +199,404 @@ impl Default for Discord {
         Self {
             message_update_interval_ms: 1000,
             replace_newlines: true,
+            max_attachment_size: 100_000,
+            max_image_size: 8_000_000,
+            show_statistics: false,
+            overflow: Overflow::Replies,
+            max_output_messages: 3,
         }
     }
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Overflow {
+    /// Continue the output in further replies
+    Replies,
+    /// Show a preview of the output, and attach the full output as a file once finished
+    Attachment,
+    /// Continue the output in a thread. Private outputs and outputs in DMs fall back to
+    /// `attachment`, as they can't have threads
+    Thread,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Moderation {
+    /// Words that prompts and outputs may not contain, matched case-insensitively as whole words
+    pub blocked_words: Vec<String>,
+    /// Regular expressions that prompts and outputs may not match
+    pub blocked_patterns: Vec<String>,
+    /// If set, prompts and outputs are also checked by the API server's moderation
+    /// endpoint with this model (e.g. `omni-moderation-latest`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub endpoint_model: Option<String>,
+    /// The number of characters of output to stream between checks with the moderation
+    /// endpoint. The blocklists are checked on every update.
+    pub endpoint_interval: usize,
+    /// What to do with flagged prompts and outputs
+    pub action: ModerationAction,
+    /// Overrides for individual servers, keyed by their ID
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub guilds: HashMap<String, GuildModeration>,
+}
+impl Default for Moderation {
+    fn default() -> Self {
+        Self {
+            blocked_words: vec![],
+            blocked_patterns: vec![],
+            endpoint_model: None,
+            endpoint_interval: 500,
+            action: ModerationAction::Block,
+            guilds: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct GuildModeration {
+    /// Replaces the default action in this server
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub action: Option<ModerationAction>,
+    /// The channel that held prompts and outputs are sent to for review
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub review_channel_id: Option<u64>,
+    /// Words that may not be used in this server, in addition to the global ones
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub blocked_words: Vec<String>,
+    /// Patterns that may not be matched in this server, in addition to the global ones
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub blocked_patterns: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ModerationAction {
+    /// Refuse flagged prompts, and stop flagged outputs
+    Block,
+    /// Replace text matching the blocklists. Anything flagged by the moderation endpoint
+    /// is blocked, as it can't be pinpointed
+    Redact,
+    /// Generate the response, but only post it once a moderator has approved it in the
+    /// server's `review_channel_id`. Falls back to `block` where there is no review channel
+    Hold,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Cache {
+    /// Whether or not to keep responses on disk, so that identical requests to configured
+    /// commands (same server, model, parameters and messages) are answered from the cache
+    pub enabled: bool,
+    /// How long, in seconds, cached responses are used for
+    pub ttl_secs: u64,
+    /// The largest response, in bytes, that will be cached
+    pub max_entry_size: usize,
+    /// The total size, in bytes, of the cache. The oldest responses are removed beyond this
+    pub max_size: usize,
+}
+impl Default for Cache {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            ttl_secs: 60 * 60 * 24,
+            max_entry_size: 1_000_000,
+            max_size: 100_000_000,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct Queue {
+    /// The most generations to run on the API server at once. Further requests wait in
+    /// a queue, taking turns between users. If not set, there is no limit
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_concurrent: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Documents {
+    /// The directory of Markdown and text files in the collection, including subdirectories
+    pub path: PathBuf,
+    /// The model used to embed the documents and prompts (e.g. `text-embedding-3-small`)
+    pub embedding_model: String,
+    /// The most text, in bytes, in each chunk that the documents are split into.
+    /// Defaults to 1000.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunk_size: Option<usize>,
+    /// The most text, in bytes, repeated from the end of each chunk at the start of the
+    /// next, in whole paragraphs. Defaults to 200.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chunk_overlap: Option<usize>,
+    /// The number of chunks added to each prompt. Defaults to 4.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub top_k: Option<usize>,
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Command {
     pub enabled: bool,
     pub description: String,
+    /// The system prompt. `{{name}}` is replaced with the value of the option `name`.
     pub system_prompt: String,
+    /// The template for the user message. `{{prompt}}` is replaced with the prompt,
+    /// and other options are available as with the system prompt.
+    /// If not set, the prompt is sent as-is.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt_template: Option<String>,
+    /// Additional options to register with Discord, keyed by their name.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub options: HashMap<String, CommandOption>,
+    /// Example conversation turns, inserted between the system prompt and the user's message.
+    /// These are templated in the same way as the system prompt.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub examples: Vec<ExampleMessage>,
+    /// The path to a TOML file with further example turns, specified as `[[examples]]`.
+    /// These are read when the configuration is loaded, and inserted after `examples`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub examples_path: Option<PathBuf>,
+    /// If set, the command is also registered as a message context-menu command,
+    /// which uses the selected message as the prompt.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub context_menu: Option<ContextMenu>,
+    /// Whether or not to hide the reasoning of reasoning models while they are thinking.
+    /// The reasoning is never included in the final response.
+    #[serde(default)]
+    pub hide_reasoning: bool,
+    /// Tools that the model can call, keyed by their name.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub tools: HashMap<String, Tool>,
+    /// If set, the model is asked to respond with JSON matching a schema.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub response_format: Option<ResponseFormat>,
+    /// If set, responses are shown in embeds instead of as message content.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub embed: Option<Embed>,
+    /// Whether or not responses are only visible to the user who ran the command.
+    /// This can be overridden with the `private` option.
+    #[serde(default)]
+    pub private: bool,
+    /// The name of a document collection to answer from. The most relevant parts of the
+    /// documents are added to each prompt, and listed as sources below the response.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub documents: Option<String>,
+}
+impl Command {
+    /// Gives each option the user left out an empty value, so that its placeholders
+    /// aren't left in the rendered prompts.
+    pub fn fill_missing_options(&self, variables: &mut HashMap<String, String>) {
+        for name in self.options.keys() {
+            variables.entry(name.clone()).or_default();
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Tool {
+    pub description: String,
+    /// The JSON schema of the tool's parameters
+    pub parameters: serde_json::Value,
+    /// The body of a Lua function that implements the tool. The arguments are
+    /// available as `args`, and the return value is sent to the model.
+    pub code: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ResponseFormat {
+    /// The JSON schema that the response must match
+    pub schema: serde_json::Value,
+    /// The template used to display the response. Properties of the response are
+    /// available as `{{name}}` (or `{{a.b}}` when nested), and the entire response
+    /// as `{{json}}`. If not set, the response is shown as a code block.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub template: Option<String>,
+    /// The number of times to ask the model again if its response is invalid.
+    /// Defaults to 2.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retries: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Embed {
+    /// The colour of the embed's sidebar, as a hex code (e.g. `#5865F2`)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub colour: Option<HexColour>,
+}
+
+/// An RGB colour, written as a hex code (e.g. `#5865F2`). Invalid colours are rejected
+/// when the configuration is loaded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HexColour(pub u32);
+impl Serialize for HexColour {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("#{:06X}", self.0))
+    }
+}
+impl<'de> Deserialize<'de> for HexColour {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let colour = String::deserialize(deserializer)?;
+        let hex = colour.strip_prefix('#').unwrap_or(&colour);
+        match u32::from_str_radix(hex, 16) {
+            Ok(value) if hex.len() == 6 && hex.chars().all(|c| c.is_ascii_hexdigit()) => {
+                Ok(Self(value))
+            }
+            _ => Err(serde::de::Error::custom(format!(
+                "invalid colour `{colour}`; expected a hex code like `#5865F2`"
+            ))),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ContextMenu {
+    /// The name shown in the context menu (e.g. "Summarize")
+    pub name: String,
+    /// The model to use, as context-menu commands cannot take options
+    pub model: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExampleMessage {
+    pub role: ExampleRole,
+    pub content: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExampleRole {
+    User,
+    Assistant,
+}
+
+fn load_examples_file(path: &Path) -> anyhow::Result<Vec<ExampleMessage>> {
+    #[derive(Deserialize)]
+    struct ExamplesFile {
+        examples: Vec<ExampleMessage>,
+    }
+
+    let file = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read examples from {}", path.display()))?;
+    let file: ExamplesFile = toml::from_str(&file)
+        .with_context(|| format!("failed to parse examples from {}", path.display()))?;
+    Ok(file.examples)
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CommandOption {
+    #[serde(rename = "type")]
+    pub kind: CommandOptionKind,
+    pub description: String,
+    #[serde(default)]
+    pub required: bool,
+    /// The values the user can pick from (string options only).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub choices: Vec<String>,
+    /// The minimum value (integer options only).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub min_value: Option<i64>,
+    /// The maximum value (integer options only).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_value: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CommandOptionKind {
+    String,
+    Integer,
+    Boolean,
+    User,
+    Channel,
+    Attachment,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util;
+
+    #[test]
+    fn test_fill_missing_options() {
+        let command: Command = toml::from_str(
+            r#"
+            enabled = true
+            description = "Translates text."
+            system_prompt = "Translate into {{language}}.{{notes}}"
+
+            [options.language]
+            type = "string"
+            description = "The language to translate into."
+            required = true
+
+            [options.notes]
+            type = "string"
+            description = "Notes for the translator."
+            "#,
+        )
+        .unwrap();
+
+        let mut variables = HashMap::from([("language".to_string(), "French".to_string())]);
+        command.fill_missing_options(&mut variables);
+        assert_eq!(
+            util::render_template(&command.system_prompt, &variables),
+            "Translate into French."
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let config: Configuration = toml::from_str(
+            r#"
+            [commands.translate]
+            enabled = true
+            description = "Translates text."
+            system_prompt = "Translate into {{model}}."
+
+            [commands.translate.options.model]
+            type = "string"
+            description = "The language to translate into."
+            "#,
+        )
+        .unwrap();
+        assert!(config.validate().is_err());
+        assert!(Configuration::default().validate().is_ok());
+
+        let context_menu = |name: &str, required: bool| -> Configuration {
+            toml::from_str(&format!(
+                r#"
+                [commands.translate]
+                enabled = true
+                description = "Translates text."
+                system_prompt = "Translate into {{{{language}}}}."
+                context_menu = {{ name = "{name}", model = "llama" }}
+
+                [commands.translate.options.language]
+                type = "string"
+                description = "The language to translate into."
+                required = {required}
+                "#
+            ))
+            .unwrap()
+        };
+        assert!(context_menu("Translate", false).validate().is_ok());
+        // Context-menu names share a namespace with every other command
+        assert!(context_menu("translate", false).validate().is_err());
+        assert!(context_menu("compare", false).validate().is_err());
+        assert!(context_menu("Translate", true).validate().is_err());
+    }
+
+    #[test]
+    fn test_hex_colour() {
+        let embed: Embed = toml::from_str(r##"colour = "#5865f2""##).unwrap();
+        assert_eq!(embed.colour, Some(HexColour(0x5865F2)));
+        assert_eq!(
+            toml::to_string(&embed).unwrap().trim(),
+            r##"colour = "#5865F2""##
+        );
+
+        assert!(toml::from_str::<Embed>(r##"colour = "blurple""##).is_err());
+        assert!(toml::from_str::<Embed>(r##"colour = "#5865F2FF""##).is_err());
+    }
 }
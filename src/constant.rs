@@ -3,6 +3,24 @@ pub mod value {
     pub const PROMPT: &str = "prompt";
     pub const SEED: &str = "seed";
     pub const MODEL: &str = "model";
+    pub const ATTACHMENT: &str = "attachment";
+    pub const PRIVATE: &str = "private";
+    pub const SYSTEM_PROMPT: &str = "system_prompt";
+    pub const COMMAND: &str = "command";
+    pub const LANGUAGE: &str = "language";
+    pub const TEMPERATURE: &str = "temperature";
+    pub const RESET: &str = "reset";
+    pub const PERSONA: &str = "persona";
+    pub const NAME: &str = "name";
+    pub const SHARED: &str = "shared";
+    pub const CACHE: &str = "cache";
+    pub const COUNT: &str = "count";
+    pub const SINCE: &str = "since";
+    pub const FOCUS: &str = "focus";
+    pub const STYLE: &str = "style";
+    pub const CRON: &str = "cron";
+    pub const CHANNEL: &str = "channel";
+    pub const ID: &str = "id";
 
     pub const MESSAGE_ID: &str = "message_id";
     pub const CODE: &str = "code";
@@ -14,4 +32,22 @@ pub mod commands {
     pub const EXECUTE_THIS_CODE_BLOCK: &str = "Execute this code block";
     /// Used by the slash command
     pub const EXECUTE: &str = "execute";
+    pub const COMPARE: &str = "compare";
+    pub const SETTINGS: &str = "settings";
+    pub const PERSONA: &str = "persona";
+    pub const SUMMARIZE: &str = "summarize";
+    pub const SCHEDULE: &str = "schedule";
+}
+
+/// names of subcommands
+pub mod subcommands {
+    pub const CREATE: &str = "create";
+    pub const EDIT: &str = "edit";
+    pub const SHARE: &str = "share";
+    pub const DELETE: &str = "delete";
+    pub const LIST: &str = "list";
+    pub const IMPORT: &str = "import";
+    pub const EXPORT: &str = "export";
+    pub const COMMAND: &str = "command";
+    pub const SCRIPT: &str = "script";
 }
@@ -0,0 +1,113 @@
+use std::sync::Mutex;
+
+use anyhow::Context;
+use rusqlite::Connection;
+
+/// The tables of the database. Each is created if it does not already exist.
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS preferences (
+    user_id INTEGER PRIMARY KEY,
+    model TEXT,
+    private INTEGER,
+    language TEXT,
+    temperature REAL
+);
+
+CREATE TABLE IF NOT EXISTS personas (
+    name TEXT NOT NULL,
+    owner_id INTEGER NOT NULL,
+    guild_id INTEGER,
+    system_prompt TEXT NOT NULL,
+    model TEXT,
+    temperature REAL,
+    PRIMARY KEY (owner_id, name)
+);
+
+CREATE TABLE IF NOT EXISTS schedules (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    guild_id INTEGER NOT NULL,
+    channel_id INTEGER NOT NULL,
+    owner_id INTEGER NOT NULL,
+    cron TEXT NOT NULL,
+    command TEXT,
+    prompt TEXT,
+    model TEXT,
+    code TEXT
+);
+
+CREATE TABLE IF NOT EXISTS comparisons (
+    message_id INTEGER PRIMARY KEY,
+    command TEXT NOT NULL,
+    prompt TEXT NOT NULL,
+    models TEXT NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS comparison_votes (
+    message_id INTEGER NOT NULL,
+    user_id INTEGER NOT NULL,
+    choice INTEGER NOT NULL,
+    PRIMARY KEY (message_id, user_id)
+);
+
+CREATE TABLE IF NOT EXISTS document_chunks (
+    collection TEXT NOT NULL,
+    source TEXT NOT NULL,
+    hash TEXT NOT NULL,
+    position INTEGER NOT NULL,
+    text TEXT NOT NULL,
+    embedding BLOB NOT NULL,
+    PRIMARY KEY (collection, source, position)
+);
+";
+
+/// Changes to the tables after they were first created, in order. Each is applied once,
+/// with the number applied so far kept in the database's `user_version`.
+const MIGRATIONS: &[&str] = &["
+ALTER TABLE personas ADD COLUMN examples TEXT NOT NULL DEFAULT '[]';
+ALTER TABLE personas ADD COLUMN greeting TEXT;
+"];
+
+/// The local SQLite database, which keeps state that should persist between runs.
+pub struct Database {
+    connection: Mutex<Connection>,
+}
+impl Database {
+    const FILENAME: &str = "llmcord.db";
+
+    pub fn open() -> anyhow::Result<Self> {
+        let connection = Connection::open(Self::FILENAME).context("failed to open database")?;
+        connection
+            .execute_batch(SCHEMA)
+            .context("failed to create database tables")?;
+
+        let version: usize = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            connection
+                .execute_batch(&format!(
+                    "BEGIN;\n{migration}\nPRAGMA user_version = {};\nCOMMIT;",
+                    i + 1
+                ))
+                .with_context(|| format!("failed to apply database migration {}", i + 1))?;
+        }
+        Ok(Self {
+            connection: Mutex::new(connection),
+        })
+    }
+
+    /// Runs `f` with the connection to the database.
+    pub fn with_connection<R>(
+        &self,
+        f: impl FnOnce(&Connection) -> rusqlite::Result<R>,
+    ) -> anyhow::Result<R> {
+        Ok(f(&self.connection.lock().unwrap())?)
+    }
+}
+
+/// SQLite integers are signed, so Discord IDs are stored reinterpreted as `i64`.
+pub fn to_sql_id(id: u64) -> i64 {
+    id as i64
+}
+
+pub fn from_sql_id(id: i64) -> u64 {
+    id as u64
+}
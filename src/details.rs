@@ -0,0 +1,30 @@
+use serenity::all::{ButtonStyle, CreateButton, MessageId};
+
+use crate::localization;
+
+pub const DETAILS_ID_BASE: &str = "details";
+
+/// Builds a details button message ID from a message ID.
+pub fn build_id(first_id: MessageId) -> String {
+    format!("{DETAILS_ID_BASE}#{first_id}")
+}
+
+/// Parses a details button message ID into a message ID.
+pub fn parse_id(id: &str) -> Option<MessageId> {
+    let mut split_id = id.split('#');
+    if split_id.next() != Some(DETAILS_ID_BASE) {
+        return None;
+    }
+    Some(MessageId::new(split_id.next()?.parse::<u64>().ok()?))
+}
+
+/// Creates the button that shows the details for the output starting at `first_id`.
+pub fn button(first_id: MessageId, locale: &str) -> CreateButton {
+    CreateButton::new(build_id(first_id))
+        .style(ButtonStyle::Secondary)
+        .label(localization::text(locale, "Details"))
+}
+
+/// The details of recent generations, keyed by the ID of their first message, so that
+/// they can be shown when the details button is pressed.
+pub type Store = crate::store::Store<MessageId, String>;
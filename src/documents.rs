@@ -0,0 +1,448 @@
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+};
+
+use anyhow::Context as _;
+use rusqlite::OptionalExtension;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    ai::Ai,
+    config::{self, Configuration},
+    database::Database,
+};
+
+/// The length of each chunk, in bytes, if not configured.
+const DEFAULT_CHUNK_SIZE: usize = 1000;
+/// The overlap between chunks, in bytes, if not configured.
+const DEFAULT_CHUNK_OVERLAP: usize = 200;
+/// The number of chunks added to each prompt, if not configured.
+const DEFAULT_TOP_K: usize = 4;
+/// The most chunks to embed in one request.
+const EMBEDDING_BATCH_SIZE: usize = 64;
+/// The extensions of the files that are included in collections.
+const EXTENSIONS: &[&str] = &["md", "markdown", "txt"];
+
+/// The document collections that commands can answer from, each embedded into a local
+/// index that is searched with each prompt.
+pub struct Library {
+    collections: HashMap<String, Collection>,
+}
+struct Collection {
+    embedding_model: String,
+    top_k: usize,
+    chunks: Vec<Chunk>,
+}
+struct Chunk {
+    source: String,
+    text: String,
+    /// Normalized, so that similarity is the dot product
+    embedding: Vec<f32>,
+}
+
+/// A part of a document that was found for a prompt.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Snippet {
+    /// The path of the document, relative to the collection's directory
+    pub source: String,
+    pub text: String,
+}
+
+impl Library {
+    /// Loads the configured collections, embedding any documents that are new or have
+    /// changed since they were last indexed.
+    pub async fn load(
+        config: &Configuration,
+        ai: &Ai,
+        database: &Database,
+    ) -> anyhow::Result<Self> {
+        for (name, command) in &config.commands {
+            if let Some(documents) = &command.documents {
+                if !config.documents.contains_key(documents) {
+                    anyhow::bail!(
+                        "command `{name}` uses unknown document collection `{documents}`"
+                    );
+                }
+            }
+        }
+
+        let mut collections = HashMap::new();
+        for (name, documents) in &config.documents {
+            let collection = Collection::load(name, documents, ai, database)
+                .await
+                .with_context(|| format!("failed to index document collection `{name}`"))?;
+            println!(
+                "Indexed {} chunks of documents in collection `{name}`",
+                collection.chunks.len()
+            );
+            collections.insert(name.clone(), collection);
+        }
+        Ok(Self { collections })
+    }
+
+    /// Finds the parts of the collection's documents most relevant to `query`, best first.
+    pub async fn search(&self, ai: &Ai, name: &str, query: &str) -> anyhow::Result<Vec<Snippet>> {
+        let collection = self
+            .collections
+            .get(name)
+            .with_context(|| format!("unknown document collection `{name}`"))?;
+        if collection.chunks.is_empty() || query.trim().is_empty() {
+            return Ok(vec![]);
+        }
+
+        let query = ai
+            .embed(&collection.embedding_model, vec![query.to_string()])
+            .await
+            .context("failed to embed the prompt")?;
+        let query = normalize(query.into_iter().next().unwrap_or_default());
+
+        let mut scored: Vec<_> = collection
+            .chunks
+            .iter()
+            .map(|chunk| (dot(&query, &chunk.embedding), chunk))
+            .collect();
+        scored.sort_by(|(a, _), (b, _)| b.total_cmp(a));
+        Ok(scored
+            .into_iter()
+            .take(collection.top_k)
+            .map(|(_, chunk)| Snippet {
+                source: chunk.source.clone(),
+                text: chunk.text.clone(),
+            })
+            .collect())
+    }
+}
+
+impl Collection {
+    async fn load(
+        name: &str,
+        config: &config::Documents,
+        ai: &Ai,
+        database: &Database,
+    ) -> anyhow::Result<Self> {
+        let chunk_size = config.chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE).max(1);
+        let chunk_overlap = config.chunk_overlap.unwrap_or(DEFAULT_CHUNK_OVERLAP);
+
+        let mut paths = vec![];
+        find_documents(&config.path, &mut paths)
+            .with_context(|| format!("failed to read {}", config.path.display()))?;
+        paths.sort();
+
+        let mut chunks = vec![];
+        let mut sources = HashSet::new();
+        for path in paths {
+            let source = path
+                .strip_prefix(&config.path)
+                .unwrap_or(&path)
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            let content = std::fs::read_to_string(&path)
+                .with_context(|| format!("failed to read {}", path.display()))?;
+
+            // Documents are embedded again if they or the way they're embedded change
+            let hash = hash(&[
+                &config.embedding_model,
+                &chunk_size.to_string(),
+                &chunk_overlap.to_string(),
+                &content,
+            ]);
+            let stored = load_chunks(database, name, &source, &hash)?;
+            let document_chunks = match stored {
+                Some(stored) => stored,
+                None => {
+                    let texts = chunk_text(&content, chunk_size, chunk_overlap);
+                    let mut embeddings = vec![];
+                    for batch in texts.chunks(EMBEDDING_BATCH_SIZE) {
+                        embeddings.extend(ai.embed(&config.embedding_model, batch.to_vec()).await?);
+                    }
+                    let document_chunks: Vec<_> = texts
+                        .into_iter()
+                        .zip(embeddings)
+                        .map(|(text, embedding)| Chunk {
+                            source: source.clone(),
+                            text,
+                            embedding: normalize(embedding),
+                        })
+                        .collect();
+                    store_chunks(database, name, &source, &hash, &document_chunks)?;
+                    document_chunks
+                }
+            };
+            chunks.extend(document_chunks);
+            sources.insert(source);
+        }
+        remove_missing(database, name, &sources)?;
+
+        Ok(Self {
+            embedding_model: config.embedding_model.clone(),
+            top_k: config.top_k.unwrap_or(DEFAULT_TOP_K),
+            chunks,
+        })
+    }
+}
+
+/// Adds the snippets to the start of the user's message, numbered so that the model can
+/// cite them.
+pub fn add_snippets(message: &str, snippets: &[Snippet]) -> String {
+    let mut output = "Answer using the following excerpts from the documentation where they're relevant, citing them by number (such as [1]). If they don't cover the question, say so.\n\n".to_string();
+    for (i, snippet) in snippets.iter().enumerate() {
+        output.push_str(&format!(
+            "[{}] {}\n{}\n\n",
+            i + 1,
+            snippet.source,
+            snippet.text
+        ));
+    }
+    output.push_str("---\n\n");
+    output.push_str(message);
+    output
+}
+
+/// The line listing the snippets' sources, shown below the response.
+pub fn sources_line(snippets: &[Snippet]) -> String {
+    let sources: Vec<_> = snippets
+        .iter()
+        .enumerate()
+        .map(|(i, snippet)| format!("[{}] `{}`", i + 1, snippet.source))
+        .collect();
+    format!("-# Sources: {}", sources.join(", "))
+}
+
+/// Splits text into chunks of at most `size` bytes, keeping paragraphs together where
+/// possible. Each chunk starts with up to `overlap` bytes of whole paragraphs from the
+/// end of the previous one, so that text near the boundary can be found from either.
+fn chunk_text(text: &str, size: usize, overlap: usize) -> Vec<String> {
+    const SEPARATOR: &str = "\n\n";
+
+    let text = text.replace("\r\n", "\n");
+    let mut pieces = vec![];
+    for paragraph in text.split(SEPARATOR).map(str::trim) {
+        let mut rest = paragraph;
+        while rest.len() > size {
+            // Split paragraphs that are too long at the last whitespace that fits
+            let head = crate::util::truncate(rest, size);
+            let mut end = head
+                .rfind(char::is_whitespace)
+                .filter(|&end| end > 0)
+                .unwrap_or(head.len());
+            if end == 0 {
+                // Always make progress, even if the first character doesn't fit
+                end = rest.chars().next().map_or(rest.len(), char::len_utf8);
+            }
+            pieces.push(rest[..end].trim_end());
+            rest = rest[end..].trim_start();
+        }
+        if !rest.is_empty() {
+            pieces.push(rest);
+        }
+    }
+
+    let length = |pieces: &[&str]| {
+        pieces.iter().map(|p| p.len()).sum::<usize>()
+            + SEPARATOR.len() * pieces.len().saturating_sub(1)
+    };
+    let mut chunks = vec![];
+    let mut current: Vec<&str> = vec![];
+    for piece in pieces {
+        if !current.is_empty() && length(&current) + SEPARATOR.len() + piece.len() > size {
+            chunks.push(current.join(SEPARATOR));
+
+            let mut carried = 0;
+            while carried < current.len()
+                && length(&current[current.len() - carried - 1..]) <= overlap
+            {
+                carried += 1;
+            }
+            current.drain(..current.len() - carried);
+            while !current.is_empty() && length(&current) + SEPARATOR.len() + piece.len() > size {
+                current.remove(0);
+            }
+        }
+        current.push(piece);
+    }
+    if !current.is_empty() {
+        chunks.push(current.join(SEPARATOR));
+    }
+    chunks
+}
+
+fn find_documents(directory: &Path, paths: &mut Vec<PathBuf>) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(directory)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_documents(&path, paths)?;
+        } else if path
+            .extension()
+            .and_then(|e| e.to_str())
+            .is_some_and(|e| EXTENSIONS.contains(&e.to_ascii_lowercase().as_str()))
+        {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn hash(parts: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update(part.as_bytes());
+        hasher.update([0]);
+    }
+    hasher
+        .finalize()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+/// Loads the stored chunks of a document, if it was last indexed with the same hash.
+fn load_chunks(
+    database: &Database,
+    collection: &str,
+    source: &str,
+    hash: &str,
+) -> anyhow::Result<Option<Vec<Chunk>>> {
+    database.with_connection(|connection| {
+        let stored_hash: Option<String> = connection
+            .query_row(
+                "SELECT hash FROM document_chunks WHERE collection = ?1 AND source = ?2 LIMIT 1",
+                [collection, source],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if stored_hash.as_deref() != Some(hash) {
+            return Ok(None);
+        }
+
+        connection
+            .prepare(
+                "SELECT text, embedding FROM document_chunks
+                WHERE collection = ?1 AND source = ?2 ORDER BY position",
+            )?
+            .query_map([collection, source], |row| {
+                Ok(Chunk {
+                    source: source.to_string(),
+                    text: row.get(0)?,
+                    embedding: from_bytes(&row.get::<_, Vec<u8>>(1)?),
+                })
+            })?
+            .collect::<rusqlite::Result<_>>()
+            .map(Some)
+    })
+}
+
+/// Replaces the stored chunks of a document.
+fn store_chunks(
+    database: &Database,
+    collection: &str,
+    source: &str,
+    hash: &str,
+    chunks: &[Chunk],
+) -> anyhow::Result<()> {
+    database.with_connection(|connection| {
+        let transaction = connection.unchecked_transaction()?;
+        transaction.execute(
+            "DELETE FROM document_chunks WHERE collection = ?1 AND source = ?2",
+            [collection, source],
+        )?;
+        for (position, chunk) in chunks.iter().enumerate() {
+            transaction.execute(
+                "INSERT INTO document_chunks (collection, source, hash, position, text, embedding)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                rusqlite::params![
+                    collection,
+                    source,
+                    hash,
+                    position,
+                    chunk.text,
+                    to_bytes(&chunk.embedding)
+                ],
+            )?;
+        }
+        transaction.commit()
+    })
+}
+
+/// Removes the stored chunks of documents that are no longer in the collection.
+fn remove_missing(
+    database: &Database,
+    collection: &str,
+    sources: &HashSet<String>,
+) -> anyhow::Result<()> {
+    database.with_connection(|connection| {
+        let stored: Vec<String> = connection
+            .prepare("SELECT DISTINCT source FROM document_chunks WHERE collection = ?1")?
+            .query_map([collection], |row| row.get(0))?
+            .collect::<rusqlite::Result<_>>()?;
+        for source in stored.iter().filter(|s| !sources.contains(*s)) {
+            connection.execute(
+                "DELETE FROM document_chunks WHERE collection = ?1 AND source = ?2",
+                [collection, source],
+            )?;
+        }
+        Ok(())
+    })
+}
+
+fn to_bytes(embedding: &[f32]) -> Vec<u8> {
+    embedding.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+fn from_bytes(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
+}
+
+fn normalize(mut embedding: Vec<f32>) -> Vec<f32> {
+    let norm = dot(&embedding, &embedding).sqrt();
+    if norm > 0.0 {
+        embedding.iter_mut().for_each(|v| *v /= norm);
+    }
+    embedding
+}
+
+fn dot(a: &[f32], b: &[f32]) -> f32 {
+    a.iter().zip(b).map(|(a, b)| a * b).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_text() {
+        let text = "One one.\r\n\r\nTwo two.\n\nThree three.\n\n\n\nFour four.";
+        // Each chunk fits two paragraphs, and repeats the last paragraph of the previous one
+        assert_eq!(
+            chunk_text(text, 24, 12),
+            vec![
+                "One one.\n\nTwo two.",
+                "Two two.\n\nThree three.",
+                "Three three.\n\nFour four.",
+            ]
+        );
+        assert_eq!(
+            chunk_text(text, 24, 0),
+            vec!["One one.\n\nTwo two.", "Three three.\n\nFour four."]
+        );
+
+        // Paragraphs that are too long are split at whitespace
+        assert_eq!(
+            chunk_text("alpha beta gamma delta", 11, 0),
+            vec!["alpha beta", "gamma delta"]
+        );
+        assert!(chunk_text("  \n\n ", 10, 0).is_empty());
+    }
+
+    #[test]
+    fn test_embedding_bytes() {
+        let embedding = vec![0.25, -1.5, 3.0];
+        assert_eq!(from_bytes(&to_bytes(&embedding)), embedding);
+        assert_eq!(normalize(vec![3.0, 4.0]), vec![0.6, 0.8]);
+    }
+}
@@ -0,0 +1,161 @@
+use std::{collections::HashMap, sync::LazyLock};
+
+use serenity::all::Http;
+
+use crate::util;
+
+/// The locale used when there is no interaction to take one from. Strings are written in
+/// English, so this has no catalog.
+pub const DEFAULT_LOCALE: &str = "en-US";
+
+/// The translations of the bot's strings and command descriptions, keyed by Discord
+/// locale and then by the English text.
+static CATALOGS: LazyLock<HashMap<&'static str, HashMap<String, String>>> = LazyLock::new(|| {
+    [
+        ("de", include_str!("../locales/de.toml")),
+        ("es-ES", include_str!("../locales/es-ES.toml")),
+        ("fr", include_str!("../locales/fr.toml")),
+    ]
+    .into_iter()
+    .map(|(locale, catalog)| {
+        let catalog = toml::from_str(catalog)
+            .unwrap_or_else(|err| panic!("invalid localization catalog for {locale}: {err}"));
+        (locale, catalog)
+    })
+    .collect()
+});
+
+/// The catalog for `locale`, falling back to another catalog for the same language
+/// (e.g. `es-ES` for `es-419`).
+fn catalog(locale: &str) -> Option<&'static HashMap<String, String>> {
+    if let Some(catalog) = CATALOGS.get(locale) {
+        return Some(catalog);
+    }
+    let language = locale.split('-').next()?;
+    CATALOGS
+        .iter()
+        .find(|(l, _)| l.split('-').next() == Some(language))
+        .map(|(_, catalog)| catalog)
+}
+
+/// Translates `text` into `locale`, or returns it as-is if there's no translation.
+pub fn text<'a>(locale: &str, text: &'a str) -> &'a str {
+    catalog(locale)
+        .and_then(|catalog| catalog.get(text))
+        .map_or(text, String::as_str)
+}
+
+/// Translates `template` into `locale`, then fills in its `{{name}}` placeholders.
+pub fn format(locale: &str, template: &str, variables: &[(&str, &str)]) -> String {
+    let variables = variables
+        .iter()
+        .map(|(name, value)| (name.to_string(), value.to_string()))
+        .collect();
+    util::render_template(text(locale, template), &variables)
+}
+
+/// Registers a global command (a `CreateCommand`, or one already serialized), with the
+/// names and descriptions of the command, its options and their choices translated into
+/// each locale that has a translation.
+pub async fn register(http: &Http, command: impl serde::Serialize) -> anyhow::Result<()> {
+    let mut command = serde_json::to_value(command)?;
+    localize(&mut command);
+    http.create_global_command(&command).await?;
+    Ok(())
+}
+
+/// Adds `name_localizations` and `description_localizations` throughout a serialized command.
+fn localize(value: &mut serde_json::Value) {
+    let Some(object) = value.as_object_mut() else {
+        return;
+    };
+    for field in ["name", "description"] {
+        let Some(text) = object.get(field).and_then(|v| v.as_str()) else {
+            continue;
+        };
+        let translations: Vec<_> = CATALOGS
+            .iter()
+            .filter_map(|(locale, catalog)| Some((locale.to_string(), catalog.get(text)?.clone())))
+            .collect();
+        if translations.is_empty() {
+            continue;
+        }
+
+        // Localizations given when building the command take precedence
+        let localizations = object
+            .entry(format!("{field}_localizations"))
+            .or_insert_with(|| serde_json::Value::Object(Default::default()));
+        if localizations.is_null() {
+            *localizations = serde_json::Value::Object(Default::default());
+        }
+        if let Some(localizations) = localizations.as_object_mut() {
+            for (locale, translation) in translations {
+                localizations.entry(locale).or_insert(translation.into());
+            }
+        }
+    }
+    for field in ["options", "choices"] {
+        if let Some(children) = object.get_mut(field).and_then(|v| v.as_array_mut()) {
+            children.iter_mut().for_each(localize);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serenity::all::{CommandOptionType, CreateCommand, CreateCommandOption};
+
+    use super::*;
+
+    #[test]
+    fn test_catalogs() {
+        for (locale, catalog) in CATALOGS.iter() {
+            for (english, translation) in catalog {
+                // Placeholders must survive translation
+                for placeholder in english.split("{{").skip(1) {
+                    let placeholder = placeholder.split("}}").next().unwrap_or_default();
+                    assert!(
+                        translation.contains(&format!("{{{{{placeholder}}}}}")),
+                        "{locale} translation of `{english}` is missing `{placeholder}`"
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_text() {
+        assert_eq!(text("de", "Generating..."), "Wird generiert...");
+        // Other variants of a language use its catalog
+        assert_eq!(text("es-419", "Generating..."), "Generando...");
+        assert_eq!(text("ja", "Generating..."), "Generating...");
+        assert_eq!(text(DEFAULT_LOCALE, "Generating..."), "Generating...");
+        assert_eq!(
+            format("fr", "Queued (#{{position}})", &[("position", "3")]),
+            "En file d'attente (n° 3)"
+        );
+    }
+
+    #[test]
+    fn test_localize() {
+        let command = CreateCommand::new("ask")
+            .description("Summarizes the recent messages in this channel.")
+            .add_option(CreateCommandOption::new(
+                CommandOptionType::Integer,
+                "seed",
+                "The seed to use for sampling.",
+            ));
+        let mut command = serde_json::to_value(command).unwrap();
+        localize(&mut command);
+
+        assert_eq!(
+            command["description_localizations"]["de"],
+            "Fasst die letzten Nachrichten in diesem Kanal zusammen."
+        );
+        assert!(command["name_localizations"].get("de").is_none());
+        assert_eq!(
+            command["options"][0]["description_localizations"]["fr"],
+            "La graine à utiliser pour l'échantillonnage."
+        );
+    }
+}
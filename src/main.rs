@@ -1,23 +1,51 @@
-use std::{collections::HashMap, collections::HashSet, sync::Arc};
+use std::{
+    collections::HashMap,
+    collections::HashSet,
+    sync::{
+        Arc,
+        atomic::{AtomicBool, Ordering},
+    },
+};
 
 use anyhow::Context as AnyhowContext;
 use serenity::{
     Client,
     all::{
-        Command, Context, CreateInteractionResponse, CreateInteractionResponseMessage,
-        EventHandler, Http, Interaction, MessageId, Ready,
+        Command, Context, CreateAllowedMentions, CreateAttachment, CreateInteractionResponse,
+        CreateInteractionResponseMessage, EditInteractionResponse, EventHandler, Http, Interaction,
+        MessageId, Ready,
     },
     async_trait,
     model::prelude::GatewayIntents,
 };
 
 mod ai;
+mod attachments;
+mod cache;
 mod cancel;
+mod character_card;
 mod commands;
 mod config;
 mod constant;
+mod database;
+mod details;
+mod documents;
+mod localization;
+mod mentions;
+mod moderation;
 mod outputter;
+mod personas;
+mod preferences;
+mod publish;
+mod queue;
+mod reasoning;
+mod review;
+mod schedules;
+mod statistics;
+mod store;
+mod structured_output;
 mod util;
+mod votes;
 
 use config::Configuration;
 
@@ -31,32 +59,98 @@ async fn main() -> anyhow::Result<()> {
         .context("Expected authentication.discord_token to be filled in config")?;
 
     let ai = Arc::new(ai::Ai::load(&config).await?);
+    let database = Arc::new(database::Database::open()?);
+    let documents = Arc::new(documents::Library::load(&config, &ai, &database).await?);
 
     let (cancel_tx, cancel_rx) = flume::unbounded::<MessageId>();
-    let handlers: HashMap<String, Box<dyn commands::CommandHandler>> = config
+    let details = Arc::new(details::Store::default());
+    let published = Arc::new(publish::Store::default());
+    let held = Arc::new(review::Store::default());
+    let bases: HashMap<String, commands::hallucinate::Handler> = config
         .commands
         .iter()
         .map(|(name, command)| {
-            Box::new(commands::hallucinate::Handler::new(
+            let base = commands::hallucinate::Handler::new(
                 command.clone(),
                 name.to_string(),
                 config.discord.clone(),
                 cancel_rx.clone(),
                 ai.clone(),
-            )) as Box<dyn commands::CommandHandler>
+                commands::hallucinate::Shared {
+                    details: details.clone(),
+                    published: published.clone(),
+                    held: held.clone(),
+                    database: database.clone(),
+                    documents: documents.clone(),
+                },
+            );
+            (name.clone(), base)
+        })
+        .collect();
+    let execute =
+        commands::execute::Handler::new(config.discord.clone(), cancel_rx.clone(), ai.clone());
+    let scheduler = Arc::new(schedules::Scheduler::new(
+        database.clone(),
+        bases.clone(),
+        execute.clone(),
+    ));
+
+    let handlers: HashMap<String, Box<dyn commands::CommandHandler>> = bases
+        .into_iter()
+        .flat_map(|(name, base)| {
+            let app = config.commands[&name]
+                .context_menu
+                .clone()
+                .map(|context_menu| {
+                    Box::new(commands::hallucinate::app::Handler::new(
+                        base.clone(),
+                        context_menu,
+                    )) as Box<dyn commands::CommandHandler>
+                });
+            std::iter::once(Box::new(commands::hallucinate::slash::Handler::new(base))
+                as Box<dyn commands::CommandHandler>)
+            .chain(app)
         })
-        .chain({
-            let base = commands::execute::Handler::new(
+        .chain([
+            Box::new(commands::execute::app::Handler::new(execute.clone()))
+                as Box<dyn commands::CommandHandler>,
+            Box::new(commands::execute::slash::Handler::new(execute)),
+        ])
+        .chain([
+            Box::new(commands::compare::Handler::new(
+                &config.commands,
                 config.discord.clone(),
                 cancel_rx.clone(),
                 ai.clone(),
-            );
-            [
-                Box::new(commands::execute::app::Handler::new(base.clone()))
-                    as Box<dyn commands::CommandHandler>,
-                Box::new(commands::execute::slash::Handler::new(base)),
-            ]
-        })
+                database.clone(),
+                held.clone(),
+            )) as Box<dyn commands::CommandHandler>,
+            Box::new(commands::settings::Handler::new(
+                ai.clone(),
+                database.clone(),
+            )),
+            Box::new(commands::persona::Handler::new(
+                ai.clone(),
+                database.clone(),
+            )),
+            Box::new(commands::summarize::Handler::new(
+                config.discord.clone(),
+                cancel_rx.clone(),
+                ai.clone(),
+                commands::hallucinate::Shared {
+                    details: details.clone(),
+                    published: published.clone(),
+                    held: held.clone(),
+                    database: database.clone(),
+                    documents: documents.clone(),
+                },
+            )),
+            Box::new(commands::schedule::Handler::new(
+                ai.clone(),
+                database.clone(),
+                scheduler.clone(),
+            )),
+        ])
         .map(|handler| (handler.name().to_string(), handler))
         .collect();
 
@@ -64,6 +158,13 @@ async fn main() -> anyhow::Result<()> {
         .event_handler(Handler {
             handlers,
             cancel_tx,
+            queue: ai.queue.clone(),
+            details,
+            published,
+            held,
+            database,
+            scheduler,
+            scheduler_started: AtomicBool::new(false),
         })
         .await
         .context("Error creating client")?;
@@ -78,6 +179,14 @@ async fn main() -> anyhow::Result<()> {
 pub struct Handler {
     handlers: HashMap<String, Box<dyn commands::CommandHandler>>,
     cancel_tx: flume::Sender<MessageId>,
+    queue: Arc<queue::Queue>,
+    details: Arc<details::Store>,
+    published: Arc<publish::Store>,
+    held: Arc<review::Store>,
+    database: Arc<database::Database>,
+    scheduler: Arc<schedules::Scheduler>,
+    /// Whether or not the scheduler has been started, as `ready` is sent again on reconnects
+    scheduler_started: AtomicBool,
 }
 #[async_trait]
 impl EventHandler for Handler {
@@ -85,6 +194,10 @@ impl EventHandler for Handler {
         self.ready_impl(&ctx.http, ready)
             .await
             .expect("Error while registering commands");
+
+        if !self.scheduler_started.swap(true, Ordering::SeqCst) {
+            self.scheduler.clone().start(ctx.http.clone());
+        }
     }
 
     async fn interaction_create(&self, ctx: Context, interaction: Interaction) {
@@ -93,8 +206,13 @@ impl EventHandler for Handler {
         };
 
         if let Err(err) = self.interaction_create_impl(&ctx.http, &interaction).await {
+            let message = localization::format(
+                respondable.locale(),
+                "Error: {{error}}",
+                &[("error", &err.to_string())],
+            );
             respondable
-                .create_or_edit(&ctx.http, &format!("Error: {err}"))
+                .create_or_edit(&ctx.http, &message)
                 .await
                 .unwrap();
         }
@@ -137,12 +255,24 @@ impl Handler {
                     anyhow::bail!("no handler found for command: {name}");
                 }
             }
+            Interaction::Modal(modal) => {
+                let Some((name, key)) = commands::parse_modal_id(&modal.data.custom_id) else {
+                    anyhow::bail!("unknown modal: {}", modal.data.custom_id);
+                };
+                if let Some(handler) = self.handlers.get(name) {
+                    handler.run_modal(http, modal, key).await?;
+                } else {
+                    anyhow::bail!("no handler found for command: {name}");
+                }
+            }
             Interaction::Component(cmp) => {
                 if let Some((message_id, user_id)) = cancel::parse_id(&cmp.data.custom_id) {
                     if cmp.user.id != user_id {
                         return Ok(());
                     }
 
+                    // The job may still be waiting to start, or may already be running
+                    self.queue.cancel(message_id);
                     self.cancel_tx.send(message_id).ok();
                     cmp.create_response(
                         http,
@@ -152,6 +282,137 @@ impl Handler {
                     )
                     .await
                     .ok();
+                } else if let Some(message_id) = details::parse_id(&cmp.data.custom_id) {
+                    let details = self.details.get(&message_id).unwrap_or_else(|| {
+                        localization::text(
+                            &cmp.locale,
+                            "The details for this message are no longer available.",
+                        )
+                        .to_string()
+                    });
+
+                    // Discord limits message content to 2000 characters, so send longer details as a file
+                    let response = if details.len() > 2000 {
+                        CreateInteractionResponseMessage::new()
+                            .add_file(CreateAttachment::bytes(details.into_bytes(), "details.md"))
+                    } else {
+                        CreateInteractionResponseMessage::new().content(details)
+                    };
+                    cmp.create_response(
+                        http,
+                        CreateInteractionResponse::Message(
+                            response
+                                .ephemeral(true)
+                                .allowed_mentions(CreateAllowedMentions::new()),
+                        ),
+                    )
+                    .await?;
+                } else if let Some((message_id, index)) = votes::parse_id(&cmp.data.custom_id) {
+                    let Some(mut ballot) = votes::Ballot::load(&self.database, message_id)? else {
+                        cmp.create_response(
+                            http,
+                            CreateInteractionResponse::Message(
+                                CreateInteractionResponseMessage::new()
+                                    .content(localization::text(
+                                        &cmp.locale,
+                                        "Voting on this comparison has closed.",
+                                    ))
+                                    .ephemeral(true),
+                            ),
+                        )
+                        .await?;
+                        return Ok(());
+                    };
+                    ballot.vote(&self.database, cmp.user.id, index)?;
+                    let buttons = ballot.buttons();
+
+                    cmp.create_response(http, CreateInteractionResponse::Acknowledge)
+                        .await?;
+                    cmp.edit_response(
+                        http,
+                        EditInteractionResponse::new().components(vec![buttons]),
+                    )
+                    .await?;
+                } else if let Some((message_id, user_id)) = publish::parse_id(&cmp.data.custom_id) {
+                    if cmp.user.id != user_id {
+                        return Ok(());
+                    }
+
+                    let content = match self.published.remove(&message_id) {
+                        Some(messages) => {
+                            util::send_reply_chain(http, cmp.channel_id, None, messages).await?;
+                            "Published!"
+                        }
+                        None => {
+                            "This response has already been published, or is no longer available."
+                        }
+                    };
+                    let content = localization::text(&cmp.locale, content);
+
+                    cmp.create_response(
+                        http,
+                        CreateInteractionResponse::Message(
+                            CreateInteractionResponseMessage::new()
+                                .content(content)
+                                .ephemeral(true),
+                        ),
+                    )
+                    .await?;
+                } else if let Some((message_id, approve)) = review::parse_id(&cmp.data.custom_id) {
+                    let is_moderator = cmp
+                        .member
+                        .as_ref()
+                        .and_then(|member| member.permissions)
+                        .is_some_and(|permissions| permissions.manage_messages());
+                    let held = if is_moderator {
+                        self.held.remove(&message_id)
+                    } else {
+                        None
+                    };
+                    let Some(held) = held else {
+                        let content = if is_moderator {
+                            "This response has already been reviewed, or is no longer available."
+                        } else {
+                            "Only moderators can review held responses."
+                        };
+                        let content = localization::text(&cmp.locale, content);
+                        cmp.create_response(
+                            http,
+                            CreateInteractionResponse::Message(
+                                CreateInteractionResponseMessage::new()
+                                    .content(content)
+                                    .ephemeral(true),
+                            ),
+                        )
+                        .await?;
+                        return Ok(());
+                    };
+
+                    cmp.create_response(http, CreateInteractionResponse::Acknowledge)
+                        .await?;
+                    if approve {
+                        held.deliver(http).await?;
+                    }
+                    // The review channel is shared, so the decision is in the server's locale
+                    let decision = localization::format(
+                        cmp.guild_locale
+                            .as_deref()
+                            .unwrap_or(localization::DEFAULT_LOCALE),
+                        if approve {
+                            "**Approved** by {{user}}."
+                        } else {
+                            "**Rejected** by {{user}}."
+                        },
+                        &[("user", &format!("<@{}>", cmp.user.id))],
+                    );
+                    cmp.edit_response(
+                        http,
+                        EditInteractionResponse::new()
+                            .content(format!("{}\n{decision}", cmp.message.content))
+                            .components(vec![])
+                            .allowed_mentions(CreateAllowedMentions::new()),
+                    )
+                    .await?;
                 }
             }
             _ => {}
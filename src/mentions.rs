@@ -0,0 +1,226 @@
+use std::{collections::HashMap, sync::LazyLock};
+
+use chrono::DateTime;
+use regex::{Captures, Regex};
+use serenity::all::{Channel, ChannelId, GuildId, Http, RoleId, UserId};
+
+/// Matches the markup that Discord uses for mentions, custom emoji and timestamps.
+static MARKUP: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(
+        r"<(?:(?P<kind>@!?|@&|#)(?P<id>\d+)|a?:(?P<emoji>\w+):\d+|t:(?P<time>-?\d+)(?::(?P<style>[tTdDfFR]))?)>",
+    )
+    .unwrap()
+});
+/// Matches `@` at the start of a word, which Discord may treat as a mention.
+static AT_SIGN: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"(^|[^\w@])@(\w)").unwrap());
+/// Placed after `@` so that it is no longer read as a mention, while looking the same.
+const ZERO_WIDTH_SPACE: char = '\u{200B}';
+
+/// The names of the users, roles and channels mentioned in some text.
+#[derive(Debug, Default)]
+struct Names {
+    users: HashMap<UserId, String>,
+    roles: HashMap<RoleId, String>,
+    channels: HashMap<ChannelId, String>,
+}
+
+/// Replaces mentions of users, roles and channels with their names, custom emoji with
+/// their names, and timestamps with readable dates, so that the model can understand them.
+/// Mentions that can't be looked up are left as-is.
+pub async fn resolve(http: &Http, guild_id: Option<GuildId>, text: &str) -> String {
+    let mut names = Names::default();
+    for captures in MARKUP.captures_iter(text) {
+        let (Some(kind), Some(id)) = (captures.name("kind"), captures.name("id")) else {
+            continue;
+        };
+        let Some(id) = id.as_str().parse::<u64>().ok().filter(|&id| id != 0) else {
+            continue;
+        };
+        match kind.as_str() {
+            "@&" => {
+                if let (Some(guild_id), true) = (guild_id, names.roles.is_empty()) {
+                    if let Ok(roles) = guild_id.roles(http).await {
+                        names.roles = roles.into_iter().map(|(id, r)| (id, r.name)).collect();
+                    }
+                }
+            }
+            "#" => {
+                let id = ChannelId::new(id);
+                if names.channels.contains_key(&id) {
+                    continue;
+                }
+                if let Ok(channel) = id.to_channel(http).await {
+                    let name = match channel {
+                        Channel::Guild(channel) => channel.name,
+                        Channel::Private(channel) => channel.recipient.display_name().to_string(),
+                        _ => continue,
+                    };
+                    names.channels.insert(id, name);
+                }
+            }
+            _ => {
+                let id = UserId::new(id);
+                if names.users.contains_key(&id) {
+                    continue;
+                }
+                let name = match guild_id {
+                    Some(guild_id) => guild_id
+                        .member(http, id)
+                        .await
+                        .ok()
+                        .map(|member| member.display_name().to_string()),
+                    None => None,
+                };
+                let name = match name {
+                    Some(name) => Some(name),
+                    None => id
+                        .to_user(http)
+                        .await
+                        .ok()
+                        .map(|user| user.display_name().to_string()),
+                };
+                if let Some(name) = name {
+                    names.users.insert(id, name);
+                }
+            }
+        }
+    }
+
+    replace_markup(text, &names)
+}
+
+/// Stops `@` at the start of words from being read as mentions (including `@everyone`
+/// and `@here`) in text from the model, leaving code alone.
+pub fn neutralize(text: &str) -> String {
+    // Prose and code alternate at each code fence, and then at each backtick within the prose
+    let mut output = String::with_capacity(text.len());
+    for (i, block) in text.split("```").enumerate() {
+        if i > 0 {
+            output.push_str("```");
+        }
+        if i % 2 == 1 {
+            output.push_str(block);
+            continue;
+        }
+        for (j, span) in block.split('`').enumerate() {
+            if j > 0 {
+                output.push('`');
+            }
+            if j % 2 == 1 {
+                output.push_str(span);
+            } else {
+                output.push_str(
+                    &AT_SIGN.replace_all(span, format!("${{1}}@{ZERO_WIDTH_SPACE}${{2}}")),
+                );
+            }
+        }
+    }
+    output
+}
+
+fn replace_markup(text: &str, names: &Names) -> String {
+    MARKUP
+        .replace_all(text, |captures: &Captures| {
+            let original = captures[0].to_string();
+            if let Some(emoji) = captures.name("emoji") {
+                return format!(":{}:", emoji.as_str());
+            }
+            if let Some(time) = captures.name("time") {
+                let style = captures
+                    .name("style")
+                    .and_then(|s| s.as_str().chars().next());
+                return time
+                    .as_str()
+                    .parse()
+                    .ok()
+                    .and_then(|time| format_timestamp(time, style))
+                    .unwrap_or(original);
+            }
+
+            let Some(id) = captures["id"].parse::<u64>().ok().filter(|&id| id != 0) else {
+                return original;
+            };
+            let name = match &captures["kind"] {
+                "@&" => names.roles.get(&RoleId::new(id)).map(|n| format!("@{n}")),
+                "#" => names
+                    .channels
+                    .get(&ChannelId::new(id))
+                    .map(|n| format!("#{n}")),
+                _ => names.users.get(&UserId::new(id)).map(|n| format!("@{n}")),
+            };
+            name.unwrap_or(original)
+        })
+        .into_owned()
+}
+
+/// Formats a Unix timestamp as a UTC date and/or time, following the timestamp's style.
+/// Relative timestamps are shown as full dates, as the model doesn't know when it is.
+/// Returns `None` if the timestamp is out of range.
+pub fn format_timestamp(seconds: i64, style: Option<char>) -> Option<String> {
+    let time = DateTime::from_timestamp(seconds, 0)?;
+    let format = match style {
+        Some('d' | 'D') => "%Y-%m-%d",
+        Some('t') => "%H:%M UTC",
+        Some('T') => "%H:%M:%S UTC",
+        _ => "%Y-%m-%d %H:%M UTC",
+    };
+    Some(time.format(format).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replace_markup() {
+        let names = Names {
+            users: HashMap::from_iter([(UserId::new(1), "Alice".to_string())]),
+            roles: HashMap::from_iter([(RoleId::new(2), "Mods".to_string())]),
+            channels: HashMap::from_iter([(ChannelId::new(3), "general".to_string())]),
+        };
+        assert_eq!(
+            replace_markup(
+                "<@1> and <@!1> asked <@&2> in <#3> <:blobcat:4><a:party:5>",
+                &names
+            ),
+            "@Alice and @Alice asked @Mods in #general :blobcat::party:"
+        );
+        // Unknown mentions are left alone
+        assert_eq!(replace_markup("<@9> in <#9>", &names), "<@9> in <#9>");
+        assert_eq!(
+            replace_markup("at <t:1700000000:F>, or <t:1700000000:d>", &names),
+            "at 2023-11-14 22:13 UTC, or 2023-11-14"
+        );
+    }
+
+    #[test]
+    fn test_format_timestamp() {
+        assert_eq!(
+            format_timestamp(0, None).as_deref(),
+            Some("1970-01-01 00:00 UTC")
+        );
+        assert_eq!(
+            format_timestamp(951_782_400, Some('D')).as_deref(),
+            Some("2000-02-29")
+        );
+        assert_eq!(
+            format_timestamp(-1, Some('T')).as_deref(),
+            Some("23:59:59 UTC")
+        );
+        assert_eq!(format_timestamp(i64::MAX, None), None);
+    }
+
+    #[test]
+    fn test_neutralize() {
+        let zwsp = ZERO_WIDTH_SPACE;
+        assert_eq!(
+            neutralize("Hi @everyone and @Alice (me@example.com)"),
+            format!("Hi @{zwsp}everyone and @{zwsp}Alice (me@example.com)")
+        );
+        // Code is left as-is
+        assert_eq!(
+            neutralize("`@here` ```py\n@property\n``` @here"),
+            format!("`@here` ```py\n@property\n``` @{zwsp}here")
+        );
+    }
+}
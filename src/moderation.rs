@@ -0,0 +1,333 @@
+use std::{borrow::Cow, collections::HashMap};
+
+use anyhow::Context as _;
+use async_openai::types::{CreateModerationRequest, ModerationInput};
+use regex::Regex;
+use serenity::all::{ChannelId, GuildId};
+
+use crate::config;
+
+/// What replaces text matching the blocklists when redacting.
+const REDACTED: &str = "█████";
+
+/// Checks prompts and outputs against the configured blocklists and moderation endpoint.
+pub struct Moderator {
+    client: async_openai::Client<async_openai::config::OpenAIConfig>,
+    endpoint_model: Option<String>,
+    endpoint_interval: usize,
+    default_policy: Policy,
+    guild_policies: HashMap<GuildId, Policy>,
+}
+
+/// How content is moderated in a server, or outside of one.
+pub struct Policy {
+    action: config::ModerationAction,
+    review_channel_id: Option<ChannelId>,
+    blocklist: Vec<Regex>,
+}
+
+/// What to do with flagged content.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Block,
+    Redact,
+    /// Hold the content for review in the given channel
+    Hold(ChannelId),
+}
+
+/// Why content was flagged.
+#[derive(Debug, Clone)]
+pub struct Flag {
+    pub reason: String,
+    /// Whether or not the flagged text can be redacted; only blocklist matches can be
+    pub redactable: bool,
+}
+
+/// What should happen to flagged content that can't be redacted.
+#[derive(Debug, Clone)]
+pub enum Verdict {
+    Block(Flag),
+    Hold(ChannelId, Flag),
+}
+impl Verdict {
+    pub fn flag(&self) -> &Flag {
+        match self {
+            Verdict::Block(flag) | Verdict::Hold(_, flag) => flag,
+        }
+    }
+}
+
+impl Moderator {
+    pub fn new(
+        config: &config::Moderation,
+        client: async_openai::Client<async_openai::config::OpenAIConfig>,
+    ) -> anyhow::Result<Self> {
+        let blocklist = compile_blocklist(&config.blocked_words, &config.blocked_patterns)?;
+        let guild_policies = config
+            .guilds
+            .iter()
+            .map(|(guild_id, guild)| {
+                let guild_id = guild_id
+                    .parse::<u64>()
+                    .ok()
+                    .filter(|&id| id != 0)
+                    .map(GuildId::new)
+                    .with_context(|| format!("invalid moderation guild ID `{guild_id}`"))?;
+                let mut guild_blocklist = blocklist.clone();
+                guild_blocklist.extend(compile_blocklist(
+                    &guild.blocked_words,
+                    &guild.blocked_patterns,
+                )?);
+                Ok((
+                    guild_id,
+                    Policy {
+                        action: guild.action.unwrap_or(config.action),
+                        review_channel_id: guild.review_channel_id.map(ChannelId::new),
+                        blocklist: guild_blocklist,
+                    },
+                ))
+            })
+            .collect::<anyhow::Result<_>>()?;
+
+        Ok(Self {
+            client,
+            endpoint_model: config.endpoint_model.clone(),
+            endpoint_interval: config.endpoint_interval.max(1),
+            default_policy: Policy {
+                action: config.action,
+                review_channel_id: None,
+                blocklist,
+            },
+            guild_policies,
+        })
+    }
+
+    /// The policy for the given server, or for outside of a server.
+    pub fn policy(&self, guild_id: Option<GuildId>) -> &Policy {
+        guild_id
+            .and_then(|id| self.guild_policies.get(&id))
+            .unwrap_or(&self.default_policy)
+    }
+
+    /// Checks `text` against the blocklists and the moderation endpoint, if configured,
+    /// returning `None` if it can be shown after being redacted with [`Policy::redact`].
+    pub async fn judge(
+        &self,
+        guild_id: Option<GuildId>,
+        text: &str,
+    ) -> anyhow::Result<Option<Verdict>> {
+        let policy = self.policy(guild_id);
+        if let Some(verdict) = policy.judge_blocklist(text) {
+            return Ok(Some(verdict));
+        }
+        // Anything redacted would likely be flagged again, so only check what's left
+        let flag = self.check_endpoint(&policy.redact(text)).await?;
+        Ok(flag.and_then(|flag| policy.verdict(flag)))
+    }
+
+    /// Starts checking output as it is streamed.
+    pub fn stream_check(&self, guild_id: Option<GuildId>) -> StreamCheck<'_> {
+        StreamCheck {
+            moderator: self,
+            guild_id,
+            checked_length: 0,
+            stopped: false,
+        }
+    }
+
+    async fn check_endpoint(&self, text: &str) -> anyhow::Result<Option<Flag>> {
+        let Some(model) = &self.endpoint_model else {
+            return Ok(None);
+        };
+        if text.trim().is_empty() {
+            return Ok(None);
+        }
+
+        // Not all servers return every category, so the response is parsed leniently
+        let response: serde_json::Value = self
+            .client
+            .moderations()
+            .create_byot(CreateModerationRequest {
+                input: ModerationInput::String(text.to_string()),
+                model: Some(model.clone()),
+            })
+            .await
+            .context("failed to check with the moderation endpoint")?;
+
+        let mut categories = vec![];
+        let mut flagged = false;
+        for result in response["results"].as_array().into_iter().flatten() {
+            flagged |= result["flagged"].as_bool().unwrap_or_default();
+            categories.extend(
+                result["categories"]
+                    .as_object()
+                    .into_iter()
+                    .flatten()
+                    .filter(|(_, value)| value.as_bool().unwrap_or_default())
+                    .map(|(category, _)| category.clone()),
+            );
+        }
+
+        Ok(flagged.then(|| Flag {
+            reason: if categories.is_empty() {
+                "flagged by the moderation endpoint".to_string()
+            } else {
+                format!("flagged for {}", categories.join(", "))
+            },
+            redactable: false,
+        }))
+    }
+}
+
+impl Policy {
+    /// What to do with flagged content. Holding content requires a review channel.
+    pub fn action(&self) -> Action {
+        match (self.action, self.review_channel_id) {
+            (config::ModerationAction::Block, _) => Action::Block,
+            (config::ModerationAction::Redact, _) => Action::Redact,
+            (config::ModerationAction::Hold, Some(channel_id)) => Action::Hold(channel_id),
+            (config::ModerationAction::Hold, None) => Action::Block,
+        }
+    }
+
+    /// Replaces text matching the blocklists, if the policy is to redact.
+    pub fn redact<'a>(&self, text: &'a str) -> Cow<'a, str> {
+        if self.action() != Action::Redact {
+            return Cow::Borrowed(text);
+        }
+
+        let mut text = Cow::Borrowed(text);
+        for regex in &self.blocklist {
+            if let Cow::Owned(redacted) = regex.replace_all(&text, REDACTED) {
+                text = Cow::Owned(redacted);
+            }
+        }
+        text
+    }
+
+    fn check_blocklist(&self, text: &str) -> Option<Flag> {
+        self.blocklist
+            .iter()
+            .any(|regex| regex.is_match(text))
+            .then(|| Flag {
+                reason: "contains blocked words".to_string(),
+                redactable: true,
+            })
+    }
+
+    fn judge_blocklist(&self, text: &str) -> Option<Verdict> {
+        self.check_blocklist(text)
+            .and_then(|flag| self.verdict(flag))
+    }
+
+    fn verdict(&self, flag: Flag) -> Option<Verdict> {
+        match self.action() {
+            Action::Redact if flag.redactable => None,
+            Action::Hold(channel_id) => Some(Verdict::Hold(channel_id, flag)),
+            _ => Some(Verdict::Block(flag)),
+        }
+    }
+}
+
+/// Checks output as it is streamed. The blocklists are checked on every update, but the
+/// moderation endpoint is only used once enough new output has arrived.
+pub struct StreamCheck<'a> {
+    moderator: &'a Moderator,
+    guild_id: Option<GuildId>,
+    /// The length of the output when it was last checked with the endpoint
+    checked_length: usize,
+    /// Set once a verdict has been reached, after which nothing more is checked
+    stopped: bool,
+}
+impl StreamCheck<'_> {
+    /// Checks the output so far. If `finished`, the endpoint is always used.
+    pub async fn check(&mut self, text: &str, finished: bool) -> anyhow::Result<Option<Verdict>> {
+        if self.stopped {
+            return Ok(None);
+        }
+
+        let policy = self.moderator.policy(self.guild_id);
+        let mut verdict = policy.judge_blocklist(text);
+        if verdict.is_none()
+            && (finished || text.len() >= self.checked_length + self.moderator.endpoint_interval)
+        {
+            self.checked_length = text.len();
+            let flag = self.moderator.check_endpoint(&policy.redact(text)).await?;
+            verdict = flag.and_then(|flag| policy.verdict(flag));
+        }
+
+        self.stopped = verdict.is_some();
+        Ok(verdict)
+    }
+
+    /// Stops checking the output, such as when it is no longer being shown.
+    pub fn stop(&mut self) {
+        self.stopped = true;
+    }
+
+    /// Redacts the output for display, according to the policy.
+    pub fn redact<'t>(&self, text: &'t str) -> Cow<'t, str> {
+        self.moderator.policy(self.guild_id).redact(text)
+    }
+}
+
+fn compile_blocklist(words: &[String], patterns: &[String]) -> anyhow::Result<Vec<Regex>> {
+    let mut blocklist = vec![];
+    let words: Vec<_> = words
+        .iter()
+        .filter(|w| !w.trim().is_empty())
+        .map(|w| regex::escape(w.trim()))
+        .collect();
+    if !words.is_empty() {
+        blocklist.push(Regex::new(&format!(r"(?i)\b(?:{})\b", words.join("|")))?);
+    }
+    for pattern in patterns {
+        blocklist.push(
+            Regex::new(pattern).with_context(|| format!("invalid blocked pattern `{pattern}`"))?,
+        );
+    }
+    Ok(blocklist)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(action: config::ModerationAction) -> Policy {
+        Policy {
+            action,
+            review_channel_id: None,
+            blocklist: compile_blocklist(&["darn".to_string()], &[r"\d{4}-\d{4}".to_string()])
+                .unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_blocklist() {
+        let policy = policy(config::ModerationAction::Block);
+        assert!(policy.check_blocklist("Oh DARN it").is_some());
+        assert!(policy.check_blocklist("call 1234-5678").is_some());
+        // Words only match as whole words
+        assert!(policy.check_blocklist("darning socks").is_none());
+    }
+
+    #[test]
+    fn test_redact() {
+        let policy = policy(config::ModerationAction::Redact);
+        assert_eq!(
+            policy.redact("darn, call 1234-5678"),
+            format!("{REDACTED}, call {REDACTED}")
+        );
+        // Other actions leave the text as-is
+        let policy = self::policy(config::ModerationAction::Block);
+        assert_eq!(policy.redact("darn"), "darn");
+    }
+
+    #[test]
+    fn test_hold_without_review_channel() {
+        assert_eq!(
+            policy(config::ModerationAction::Hold).action(),
+            Action::Block
+        );
+    }
+}
@@ -1,51 +1,171 @@
 use serenity::all::{
-    CommandInteraction, CreateAllowedMentions, CreateInteractionResponse,
-    CreateInteractionResponseMessage, CreateMessage, EditMessage, Http, Message, MessageId, UserId,
+    ChannelId, Colour, CreateActionRow, CreateAllowedMentions, CreateAttachment, CreateButton,
+    CreateEmbed, CreateEmbedFooter, CreateInteractionResponseFollowup,
+    CreateInteractionResponseMessage, CreateMessage, CreateThread, EditInteractionResponse,
+    EditMessage, GuildId, Http, Message, MessageId, UserId,
 };
 
+use crate::{config, localization, util::RespondableInteraction};
+
+/// The appearance of output rendered into embeds, instead of message content.
+pub struct EmbedStyle {
+    /// Shown as the title of the first embed
+    pub title: String,
+    pub colour: Option<Colour>,
+    /// Shown in the footer of the last embed, before the output's footer
+    pub footer: String,
+}
+
+/// Where an output is shown.
+#[derive(Clone, Copy)]
+pub enum Destination<'a> {
+    /// In response to an interaction
+    Interaction(&'a dyn RespondableInteraction),
+    /// In a channel, on behalf of a user, such as for scheduled jobs
+    Channel {
+        channel_id: ChannelId,
+        guild_id: Option<GuildId>,
+        user_id: UserId,
+    },
+}
+impl<'a> Destination<'a> {
+    pub fn channel_id(&self) -> ChannelId {
+        match self {
+            Destination::Interaction(interaction) => interaction.channel_id(),
+            Destination::Channel { channel_id, .. } => *channel_id,
+        }
+    }
+
+    pub fn guild_id(&self) -> Option<GuildId> {
+        match self {
+            Destination::Interaction(interaction) => interaction.guild_id(),
+            Destination::Channel { guild_id, .. } => *guild_id,
+        }
+    }
+
+    /// The user that the output is for.
+    pub fn user_id(&self) -> UserId {
+        match self {
+            Destination::Interaction(interaction) => interaction.user().id,
+            Destination::Channel { user_id, .. } => *user_id,
+        }
+    }
+
+    /// The locale that text in the output is translated into.
+    pub fn locale(&self) -> &'a str {
+        match self {
+            Destination::Interaction(interaction) => interaction.locale(),
+            Destination::Channel { .. } => localization::DEFAULT_LOCALE,
+        }
+    }
+
+    /// The locale that text posted for the whole server, such as reports to moderators,
+    /// is translated into.
+    pub fn guild_locale(&self) -> &'a str {
+        match self {
+            Destination::Interaction(interaction) => interaction
+                .guild_locale()
+                .unwrap_or(localization::DEFAULT_LOCALE),
+            Destination::Channel { .. } => localization::DEFAULT_LOCALE,
+        }
+    }
+}
+
 pub struct Outputter<'a> {
     http: &'a Http,
+    destination: Destination<'a>,
+    /// Whether or not the output is only visible to the user, in which case all messages
+    /// are sent and edited through the interaction
+    ephemeral: bool,
 
     user_id: UserId,
     messages: Vec<Message>,
+    output: String,
     chunks: Vec<String>,
+    /// The thread that messages beyond `max_messages` are sent to, once created
+    thread_id: Option<ChannelId>,
 
     in_terminal_state: bool,
+    footer: Option<String>,
+    final_buttons: Vec<CreateButton>,
+    publish_button: Option<CreateButton>,
+    embed: Option<EmbedStyle>,
 
+    overflow: config::Overflow,
+    max_messages: usize,
     last_update: std::time::Instant,
     last_update_duration: std::time::Duration,
 }
 impl<'a> Outputter<'a> {
-    const MESSAGE_CHUNK_SIZE: usize = 1500;
+    pub const MESSAGE_CHUNK_SIZE: usize = 1500;
+    /// Embed descriptions can be up to 4096 characters; leave room for the final word.
+    const EMBED_CHUNK_SIZE: usize = 3500;
+    /// Embed titles can be up to 256 characters.
+    const EMBED_TITLE_SIZE: usize = 256;
+    /// Thread names can be up to 100 characters.
+    const THREAD_NAME_SIZE: usize = 100;
+    /// The amount of the output to show when the rest of it is attached as a file.
+    const PREVIEW_SIZE: usize = 1000;
+    const ATTACHMENT_FILENAME: &'static str = "output.md";
 
     pub async fn new(
         http: &'a Http,
-        cmd: &CommandInteraction,
-        last_update_duration: std::time::Duration,
+        destination: Destination<'a>,
+        ephemeral: bool,
+        discord_config: &config::Discord,
         initial_message: &str,
     ) -> anyhow::Result<Outputter<'a>> {
-        cmd.create_response(
-            http,
-            CreateInteractionResponse::Message(
-                CreateInteractionResponseMessage::new()
-                    .content(initial_message)
-                    .allowed_mentions(CreateAllowedMentions::new()),
-            ),
-        )
-        .await?;
-        let starting_message = cmd.get_response(http).await?;
+        let starting_message = match destination {
+            Destination::Interaction(interaction) => {
+                interaction
+                    .create_message(
+                        http,
+                        CreateInteractionResponseMessage::new()
+                            .content(initial_message)
+                            .ephemeral(ephemeral)
+                            .allowed_mentions(CreateAllowedMentions::new()),
+                    )
+                    .await?;
+                interaction.get_interaction_message(http).await?
+            }
+            Destination::Channel { channel_id, .. } => {
+                if ephemeral {
+                    anyhow::bail!("outputs in channels can't be private");
+                }
+                channel_id
+                    .send_message(
+                        http,
+                        CreateMessage::new()
+                            .content(initial_message)
+                            .allowed_mentions(CreateAllowedMentions::new()),
+                    )
+                    .await?
+            }
+        };
 
         Ok(Self {
             http,
+            destination,
+            ephemeral,
 
-            user_id: cmd.user.id,
+            user_id: destination.user_id(),
             messages: vec![starting_message],
+            output: String::new(),
             chunks: vec![],
+            thread_id: None,
 
             in_terminal_state: false,
+            footer: None,
+            final_buttons: vec![],
+            publish_button: None,
+            embed: None,
 
+            overflow: discord_config.overflow,
+            max_messages: discord_config.max_output_messages.max(1),
             last_update: std::time::Instant::now(),
-            last_update_duration,
+            last_update_duration: std::time::Duration::from_millis(
+                discord_config.message_update_interval_ms,
+            ),
         })
     }
 
@@ -53,12 +173,23 @@ impl<'a> Outputter<'a> {
         self.messages.first().unwrap().id
     }
 
+    /// The locale that text in the output is translated into.
+    pub fn locale(&self) -> &'a str {
+        self.destination.locale()
+    }
+
     pub async fn update(&mut self, message: &str) -> anyhow::Result<()> {
         if self.in_terminal_state {
             return Ok(());
         }
 
-        self.chunks = chunk_message(message, Self::MESSAGE_CHUNK_SIZE);
+        let chunk_size = if self.embed.is_some() {
+            Self::EMBED_CHUNK_SIZE
+        } else {
+            Self::MESSAGE_CHUNK_SIZE
+        };
+        self.output = message.to_string();
+        self.chunks = chunk_message(message, chunk_size);
 
         if self.last_update.elapsed() > self.last_update_duration {
             self.sync_messages_with_chunks().await?;
@@ -68,115 +199,444 @@ impl<'a> Outputter<'a> {
         Ok(())
     }
 
+    /// Shows `message` in place of the output straight away, such as while waiting to start.
+    pub async fn status(&mut self, message: &str) -> anyhow::Result<()> {
+        if self.in_terminal_state {
+            return Ok(());
+        }
+
+        self.output = message.to_string();
+        self.chunks = vec![message.to_string()];
+        self.sync_messages_with_chunks().await?;
+        self.last_update = std::time::Instant::now();
+        Ok(())
+    }
+
+    /// Renders the output into embeds with the given style, instead of message content.
+    pub fn set_embed(&mut self, embed: EmbedStyle) {
+        self.embed = Some(embed);
+    }
+
+    /// Sets a footer to show below the output when it is finished.
+    pub fn set_footer(&mut self, footer: String) {
+        self.footer = Some(footer);
+    }
+
+    /// Adds a button to show on the last message when the output is finished.
+    pub fn add_final_button(&mut self, button: CreateButton) {
+        self.final_buttons.push(button);
+    }
+
+    /// Sets the button that publishes the finished output. Unlike the other final buttons,
+    /// it is not included in [`Self::published_messages`].
+    pub fn set_publish_button(&mut self, button: CreateButton) {
+        self.publish_button = Some(button);
+    }
+
+    /// Builds the messages that reproduce the finished output publicly.
+    pub fn published_messages(&self) -> Vec<CreateMessage> {
+        self.bodies(false)
+            .into_iter()
+            .map(MessageBody::into_create_message)
+            .collect()
+    }
+
     pub async fn error(&mut self, err: &str) -> anyhow::Result<()> {
         self.on_error(err).await
     }
 
     pub async fn cancelled(&mut self) -> anyhow::Result<()> {
-        self.on_error("The generation was cancelled.").await
+        let message = localization::text(self.locale(), "The generation was cancelled.");
+        self.on_error(message).await
+    }
+
+    /// Replaces the output with `message`, such as when it has been stopped by moderation.
+    /// Nothing more is shown after this.
+    pub async fn withdraw(&mut self, message: &str) -> anyhow::Result<()> {
+        self.output = message.to_string();
+        self.chunks = vec![message.to_string()];
+        self.footer = None;
+        self.final_buttons.clear();
+        self.publish_button = None;
+        self.finish().await
     }
 
     pub async fn finish(&mut self) -> anyhow::Result<()> {
-        for msg in &mut self.messages {
-            msg.edit(self.http, EditMessage::new().components(vec![]))
-                .await?;
+        self.in_terminal_state = true;
+        self.sync_messages_with_chunks().await
+    }
+
+    async fn sync_messages_with_chunks(&mut self) -> anyhow::Result<()> {
+        let bodies = self.bodies(true);
+
+        // Delete excess messages
+        if bodies.len() < self.messages.len() {
+            for msg in self.messages.split_off(bodies.len()) {
+                self.delete_message(msg).await?;
+            }
+        }
+
+        // Update existing messages, and create new messages for the remaining bodies
+        for (i, body) in bodies.into_iter().enumerate() {
+            if i < self.messages.len() {
+                self.edit_message(i, body).await?;
+            } else {
+                let msg = self.send_message(i, body).await?;
+                self.messages.push(msg);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// How the output is laid out across messages, given its current length.
+    fn layout(&self) -> Layout {
+        if self.chunks.len() <= self.max_messages {
+            return Layout::Messages;
+        }
+
+        match self.overflow {
+            config::Overflow::Replies => Layout::Messages,
+            config::Overflow::Attachment => Layout::Attachment,
+            // Threads can't be created from ephemeral messages or in DMs
+            config::Overflow::Thread if self.ephemeral || self.destination.guild_id().is_none() => {
+                Layout::Attachment
+            }
+            config::Overflow::Thread => Layout::Thread,
+        }
+    }
+
+    /// Builds the complete contents of each message of the output.
+    fn bodies(&self, include_publish_button: bool) -> Vec<MessageBody> {
+        let layout = self.layout();
+        let texts = match layout {
+            Layout::Attachment => vec![self.preview()],
+            Layout::Messages | Layout::Thread => self.chunks.clone(),
+        };
+
+        let count = texts.len();
+        texts
+            .into_iter()
+            .enumerate()
+            .map(|(i, text)| {
+                let last = i + 1 == count;
+                let mut body = match &self.embed {
+                    Some(style) => MessageBody {
+                        embed: Some(self.create_embed(style, text, i == 0, last)),
+                        ..Default::default()
+                    },
+                    // Embeds show the footer in the embed's footer instead
+                    None => match self
+                        .footer
+                        .as_ref()
+                        .filter(|_| last && self.in_terminal_state)
+                    {
+                        Some(footer) if text.is_empty() => MessageBody {
+                            content: format!("-# {footer}"),
+                            ..Default::default()
+                        },
+                        Some(footer) => MessageBody {
+                            content: format!("{text}\n-# {footer}"),
+                            ..Default::default()
+                        },
+                        None => MessageBody {
+                            content: text,
+                            ..Default::default()
+                        },
+                    },
+                };
+                if !last {
+                    return body;
+                }
+
+                if !self.in_terminal_state {
+                    // The cancel button lives on the last message until the output is finished
+                    body.components = vec![CreateActionRow::Buttons(vec![crate::cancel::button(
+                        self.starting_message_id(),
+                        self.user_id,
+                        self.destination.locale(),
+                    )])];
+                    return body;
+                }
+
+                let publish_button = self
+                    .publish_button
+                    .iter()
+                    .filter(|_| include_publish_button);
+                let buttons: Vec<_> = self
+                    .final_buttons
+                    .iter()
+                    .chain(publish_button)
+                    .cloned()
+                    .collect();
+                if !buttons.is_empty() {
+                    body.components = vec![CreateActionRow::Buttons(buttons)];
+                }
+                if let Layout::Attachment = layout {
+                    body.attachment = Some(CreateAttachment::bytes(
+                        self.output.clone().into_bytes(),
+                        Self::ATTACHMENT_FILENAME,
+                    ));
+                }
+                body
+            })
+            .collect()
+    }
+
+    /// The start of the output, shown in place of the output when it is attached as a file.
+    fn preview(&self) -> String {
+        let note = if self.in_terminal_state {
+            "The full output is attached."
+        } else {
+            "The output is too long to show here, so it will be attached when finished."
+        };
+        let note = localization::text(self.locale(), note);
+        format!(
+            "{}…\n-# {note}",
+            crate::util::truncate(&self.output, Self::PREVIEW_SIZE)
+        )
+    }
+
+    /// Builds an embed showing `text` in the given style.
+    fn create_embed(
+        &self,
+        style: &EmbedStyle,
+        text: String,
+        first: bool,
+        last: bool,
+    ) -> CreateEmbed {
+        let mut embed = CreateEmbed::new();
+        // Discord rejects empty descriptions
+        if !text.is_empty() {
+            embed = embed.description(text);
+        }
+        if first {
+            embed = embed.title(crate::util::truncate(&style.title, Self::EMBED_TITLE_SIZE));
+        }
+        if let Some(colour) = style.colour {
+            embed = embed.colour(colour);
+        }
+        if last {
+            let footer = match self.footer.as_ref().filter(|_| self.in_terminal_state) {
+                Some(footer) => format!("{} · {footer}", style.footer),
+                None => style.footer.clone(),
+            };
+            embed = embed.footer(CreateEmbedFooter::new(footer));
+        }
+        embed
+    }
+
+    async fn on_error(&mut self, error_message: &str) -> anyhow::Result<()> {
+        for i in 0..self.messages.len() {
+            let msg = &self.messages[i];
+            let body = match msg.embeds.first() {
+                Some(embed) => {
+                    let description = embed.description.as_deref().unwrap_or_default();
+                    MessageBody {
+                        embed: Some(
+                            CreateEmbed::from(embed.clone())
+                                .description(format!("~~{description}~~"))
+                                .colour(Colour::RED),
+                        ),
+                        ..Default::default()
+                    }
+                }
+                None => MessageBody {
+                    content: format!("~~{}~~", msg.content),
+                    ..Default::default()
+                },
+            };
+            self.edit_message(i, body).await?;
         }
 
         self.in_terminal_state = true;
-        self.sync_messages_with_chunks().await?;
+        self.send_message(
+            self.messages.len(),
+            MessageBody {
+                content: error_message.to_string(),
+                ..Default::default()
+            },
+        )
+        .await?;
 
         Ok(())
     }
 
-    async fn sync_messages_with_chunks(&mut self) -> anyhow::Result<()> {
-        // Update existing messages to match chunks
-        for (msg, chunk) in self.messages.iter_mut().zip(self.chunks.iter()) {
-            msg.edit(self.http, EditMessage::new().content(chunk))
+    /// Replaces the message at `index` with `body`.
+    async fn edit_message(&mut self, index: usize, body: MessageBody) -> anyhow::Result<()> {
+        if !self.ephemeral {
+            self.messages[index]
+                .edit(self.http, body.into_edit_message())
+                .await?;
+        } else if index == 0 {
+            self.messages[index] = self
+                .interaction()
+                .edit_message(self.http, body.into_edit_interaction_response())
+                .await?;
+        } else {
+            self.messages[index] = self
+                .interaction()
+                .edit_followup_message(self.http, self.messages[index].id, body.into_followup())
                 .await?;
         }
+        Ok(())
+    }
 
-        if self.chunks.len() < self.messages.len() {
-            // Delete excess messages
-            for msg in self.messages.drain(self.chunks.len()..) {
-                msg.delete(self.http).await?;
-            }
-        } else if self.chunks.len() > self.messages.len() {
-            // Remove the cancel button from all existing messages
-            for msg in &mut self.messages {
-                msg.edit(
+    /// Sends `body` as the message at `index`, after the last message.
+    async fn send_message(&mut self, index: usize, body: MessageBody) -> anyhow::Result<Message> {
+        if self.ephemeral {
+            return self
+                .interaction()
+                .create_followup_message(self.http, body.into_followup().ephemeral(true))
+                .await;
+        }
+
+        if matches!(self.layout(), Layout::Thread) && index >= self.max_messages {
+            let thread_id = self.thread_id().await?;
+            return Ok(thread_id
+                .send_message(
                     self.http,
-                    EditMessage::new()
-                        .components(vec![])
+                    body.into_create_message()
                         .allowed_mentions(CreateAllowedMentions::new()),
                 )
-                .await?;
-            }
+                .await?);
+        }
 
-            // Create new messages for the remaining chunks
-            for chunk in self.chunks[self.messages.len()..].iter() {
-                let last = self.messages.last_mut().unwrap();
-                let msg = reply_to_message_without_mentions(self.http, last, chunk).await?;
-                self.messages.push(msg);
-            }
+        let last = self.messages.last().unwrap();
+        reply_to_message_without_mentions(self.http, last, body.into_create_message()).await
+    }
+
+    /// Returns the thread for the messages beyond `max_messages`, creating it from the
+    /// last message before them if necessary.
+    async fn thread_id(&mut self) -> anyhow::Result<ChannelId> {
+        if let Some(thread_id) = self.thread_id {
+            return Ok(thread_id);
         }
 
-        let Some(first_id) = self.messages.first().map(|m| m.id) else {
-            return Ok(());
+        let name = match &self.embed {
+            Some(style) => style.title.clone(),
+            None => self
+                .chunks
+                .first()
+                .and_then(|c| c.lines().next())
+                .unwrap_or_default()
+                .replace('*', ""),
+        };
+        let name = match crate::util::truncate(name.trim(), Self::THREAD_NAME_SIZE) {
+            "" => localization::text(self.destination.locale(), "Output"),
+            name => name,
         };
 
-        // Add the cancel button to the last message
-        if !self.in_terminal_state {
-            if let Some(last) = self.messages.last_mut() {
-                // TODO: if-let chain, 1.88
-                if last.components.is_empty() {
-                    crate::cancel::add_button(self.http, first_id, last, self.user_id).await?;
-                }
-            }
+        let msg = &self.messages[self.max_messages - 1];
+        let thread = msg
+            .channel_id
+            .create_thread_from_message(self.http, msg.id, CreateThread::new(name))
+            .await?;
+        self.thread_id = Some(thread.id);
+        Ok(thread.id)
+    }
+
+    /// The interaction that ephemeral outputs are shown through.
+    fn interaction(&self) -> &'a dyn RespondableInteraction {
+        match self.destination {
+            Destination::Interaction(interaction) => interaction,
+            Destination::Channel { .. } => unreachable!("outputs in channels are never ephemeral"),
         }
+    }
 
+    async fn delete_message(&self, msg: Message) -> anyhow::Result<()> {
+        if !self.ephemeral {
+            msg.delete(self.http).await?;
+        } else if msg.id == self.starting_message_id() {
+            self.interaction().delete_message(self.http).await?;
+        } else {
+            self.interaction()
+                .delete_followup_message(self.http, msg.id)
+                .await?;
+        }
         Ok(())
     }
+}
 
-    async fn on_error(&mut self, error_message: &str) -> anyhow::Result<()> {
-        for msg in &mut self.messages {
-            let cut_content = format!("~~{}~~", msg.content);
-            msg.edit(
-                self.http,
-                EditMessage::new()
-                    .components(vec![])
-                    .allowed_mentions(CreateAllowedMentions::new())
-                    .content(cut_content),
-            )
-            .await?;
+/// How the chunks of the output are laid out across messages.
+enum Layout {
+    /// A message for each chunk, each replying to the last
+    Messages,
+    /// A single message with a preview, with the full output attached once finished
+    Attachment,
+    /// A message for each chunk, with those beyond `max_messages` in a thread
+    Thread,
+}
+
+/// The complete state of one of the output's messages. Every edit sends the whole
+/// message, as edits made through an interaction replace any unspecified embeds.
+#[derive(Default)]
+struct MessageBody {
+    content: String,
+    embed: Option<CreateEmbed>,
+    components: Vec<CreateActionRow>,
+    attachment: Option<CreateAttachment>,
+}
+impl MessageBody {
+    fn into_edit_message(self) -> EditMessage {
+        let edit = EditMessage::new()
+            .content(self.content)
+            .embeds(self.embed.into_iter().collect())
+            .components(self.components)
+            .allowed_mentions(CreateAllowedMentions::new());
+        match self.attachment {
+            Some(attachment) => edit.new_attachment(attachment),
+            None => edit,
         }
+    }
 
-        self.in_terminal_state = true;
-        if let Some(last) = self.messages.last_mut() {
-            reply_to_message_without_mentions(self.http, last, error_message).await?;
+    fn into_edit_interaction_response(self) -> EditInteractionResponse {
+        let edit = EditInteractionResponse::new()
+            .content(self.content)
+            .embeds(self.embed.into_iter().collect())
+            .components(self.components)
+            .allowed_mentions(CreateAllowedMentions::new());
+        match self.attachment {
+            Some(attachment) => edit.new_attachment(attachment),
+            None => edit,
         }
+    }
 
-        Ok(())
+    fn into_followup(self) -> CreateInteractionResponseFollowup {
+        CreateInteractionResponseFollowup::new()
+            .content(self.content)
+            .embeds(self.embed.into_iter().collect())
+            .components(self.components)
+            .allowed_mentions(CreateAllowedMentions::new())
+            .files(self.attachment)
+    }
+
+    fn into_create_message(self) -> CreateMessage {
+        CreateMessage::new()
+            .content(self.content)
+            .embeds(self.embed.into_iter().collect())
+            .components(self.components)
+            .files(self.attachment)
     }
 }
 
 async fn reply_to_message_without_mentions(
     http: &Http,
     msg: &Message,
-    content: &str,
+    message: CreateMessage,
 ) -> anyhow::Result<Message> {
     Ok(msg
         .channel_id
         .send_message(
             http,
-            CreateMessage::new()
+            message
                 .reference_message(msg)
-                .content(content)
                 .allowed_mentions(CreateAllowedMentions::new()),
         )
         .await?)
 }
 
-fn chunk_message(message: &str, chunk_size: usize) -> Vec<String> {
+pub fn chunk_message(message: &str, chunk_size: usize) -> Vec<String> {
     let mut chunks: Vec<String> = vec!["".to_string()];
 
     for word in message.split(' ') {
@@ -0,0 +1,211 @@
+use rusqlite::{OptionalExtension, Row};
+use serenity::all::{GuildId, UserId};
+
+use crate::{
+    config::{ExampleMessage, ExampleRole},
+    database::{Database, from_sql_id, to_sql_id},
+};
+
+/// The longest name a persona can have.
+pub const MAX_NAME_LENGTH: u16 = 32;
+/// The longest system prompt a persona can have, in characters, as that's the most that
+/// fits in the form it's edited in.
+pub const MAX_SYSTEM_PROMPT_LENGTH: u16 = 4000;
+
+/// A named system prompt and parameters, created by a user. Personas can be shared with
+/// a guild, which lets everyone in the guild use them.
+#[derive(Debug, Clone)]
+pub struct Persona {
+    pub name: String,
+    pub owner_id: UserId,
+    /// The guild the persona is shared with, if any
+    pub guild_id: Option<GuildId>,
+    pub system_prompt: String,
+    pub model: Option<String>,
+    pub temperature: Option<f32>,
+    /// Example conversation turns, which replace the command's examples if there are any
+    pub examples: Vec<ExampleMessage>,
+    /// The message the persona opens the conversation with, if any
+    pub greeting: Option<String>,
+}
+impl Persona {
+    const COLUMNS: &str =
+        "name, owner_id, guild_id, system_prompt, model, temperature, examples, greeting";
+
+    /// The example turns to insert before the user's message, ending with the greeting.
+    pub fn example_messages(&self) -> Vec<ExampleMessage> {
+        let mut examples = self.examples.clone();
+        examples.extend(self.greeting.clone().map(|content| ExampleMessage {
+            role: ExampleRole::Assistant,
+            content,
+        }));
+        examples
+    }
+
+    /// Finds the persona called `name` that the user can use: their own, or one shared
+    /// with the guild.
+    pub fn find(
+        database: &Database,
+        user_id: UserId,
+        guild_id: Option<GuildId>,
+        name: &str,
+    ) -> anyhow::Result<Option<Self>> {
+        database.with_connection(|connection| {
+            connection
+                .query_row(
+                    &format!(
+                        "SELECT {} FROM personas
+                        WHERE name = ?1 AND (owner_id = ?2 OR guild_id = ?3)
+                        ORDER BY owner_id = ?2 DESC
+                        LIMIT 1",
+                        Self::COLUMNS
+                    ),
+                    rusqlite::params![
+                        name,
+                        to_sql_id(user_id.get()),
+                        guild_id.map(|g| to_sql_id(g.get()))
+                    ],
+                    Self::from_row,
+                )
+                .optional()
+        })
+    }
+
+    /// Finds the persona called `name` owned by the user.
+    pub fn find_owned(
+        database: &Database,
+        user_id: UserId,
+        name: &str,
+    ) -> anyhow::Result<Option<Self>> {
+        database.with_connection(|connection| {
+            connection
+                .query_row(
+                    &format!(
+                        "SELECT {} FROM personas WHERE name = ?1 AND owner_id = ?2",
+                        Self::COLUMNS
+                    ),
+                    rusqlite::params![name, to_sql_id(user_id.get())],
+                    Self::from_row,
+                )
+                .optional()
+        })
+    }
+
+    /// Lists the personas that the user can use, sorted by name.
+    pub fn list(
+        database: &Database,
+        user_id: UserId,
+        guild_id: Option<GuildId>,
+    ) -> anyhow::Result<Vec<Self>> {
+        database.with_connection(|connection| {
+            connection
+                .prepare(&format!(
+                    "SELECT {} FROM personas WHERE owner_id = ?1 OR guild_id = ?2 ORDER BY name",
+                    Self::COLUMNS
+                ))?
+                .query_map(
+                    rusqlite::params![
+                        to_sql_id(user_id.get()),
+                        guild_id.map(|g| to_sql_id(g.get()))
+                    ],
+                    Self::from_row,
+                )?
+                .collect()
+        })
+    }
+
+    /// Creates the persona, or replaces the owner's persona of the same name.
+    pub fn save(&self, database: &Database) -> anyhow::Result<()> {
+        let examples = serde_json::to_string(&self.examples)?;
+        database.with_connection(|connection| {
+            connection.execute(
+                &format!(
+                    "INSERT INTO personas ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+                    ON CONFLICT (owner_id, name) DO UPDATE SET
+                        guild_id = excluded.guild_id,
+                        system_prompt = excluded.system_prompt,
+                        model = excluded.model,
+                        temperature = excluded.temperature,
+                        examples = excluded.examples,
+                        greeting = excluded.greeting",
+                    Self::COLUMNS
+                ),
+                rusqlite::params![
+                    self.name,
+                    to_sql_id(self.owner_id.get()),
+                    self.guild_id.map(|g| to_sql_id(g.get())),
+                    self.system_prompt,
+                    self.model,
+                    self.temperature,
+                    examples,
+                    self.greeting
+                ],
+            )?;
+            Ok(())
+        })
+    }
+
+    /// Deletes the owner's persona called `name`, returning whether or not it existed.
+    pub fn delete(database: &Database, user_id: UserId, name: &str) -> anyhow::Result<bool> {
+        database.with_connection(|connection| {
+            let deleted = connection.execute(
+                "DELETE FROM personas WHERE name = ?1 AND owner_id = ?2",
+                rusqlite::params![name, to_sql_id(user_id.get())],
+            )?;
+            Ok(deleted > 0)
+        })
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        Ok(Self {
+            name: row.get(0)?,
+            owner_id: UserId::new(from_sql_id(row.get(1)?)),
+            guild_id: row
+                .get::<_, Option<i64>>(2)?
+                .map(|id| GuildId::new(from_sql_id(id))),
+            system_prompt: row.get(3)?,
+            model: row.get(4)?,
+            temperature: row.get(5)?,
+            examples: serde_json::from_str(&row.get::<_, String>(6)?).map_err(|err| {
+                rusqlite::Error::FromSqlConversionFailure(
+                    6,
+                    rusqlite::types::Type::Text,
+                    err.into(),
+                )
+            })?,
+            greeting: row.get(7)?,
+        })
+    }
+}
+
+/// Checks that a system prompt is short enough for a persona, so that it can be edited.
+pub fn check_system_prompt(system_prompt: &str) -> anyhow::Result<()> {
+    let length = system_prompt.chars().count();
+    if length > MAX_SYSTEM_PROMPT_LENGTH as usize {
+        anyhow::bail!(
+            "the system prompt is {length} characters long, but a persona's can be at most {MAX_SYSTEM_PROMPT_LENGTH}"
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::character_card::CharacterCard;
+
+    #[test]
+    fn test_check_system_prompt() {
+        let card = |description: &str| {
+            let json = serde_json::json!({
+                "spec": "chara_card_v2",
+                "data": { "name": "Alice", "description": description },
+            });
+            CharacterCard::parse(json.to_string().as_bytes()).unwrap()
+        };
+
+        assert!(check_system_prompt(&card("A cat.").persona_system_prompt()).is_ok());
+        let oversized = card(&"A very long description. ".repeat(200));
+        assert!(check_system_prompt(&oversized.persona_system_prompt()).is_err());
+    }
+}
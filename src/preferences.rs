@@ -0,0 +1,67 @@
+use rusqlite::OptionalExtension;
+use serenity::all::UserId;
+
+use crate::database::{Database, to_sql_id};
+
+/// A user's preferences, which are used when the corresponding option is not specified.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Preferences {
+    pub model: Option<String>,
+    pub private: Option<bool>,
+    /// Made available to the templates as `{{language}}`
+    pub language: Option<String>,
+    pub temperature: Option<f32>,
+}
+impl Preferences {
+    /// Loads the preferences of the given user, which are empty if they have not set any.
+    pub fn load(database: &Database, user_id: UserId) -> anyhow::Result<Self> {
+        database
+            .with_connection(|connection| {
+                connection
+                    .query_row(
+                        "SELECT model, private, language, temperature FROM preferences WHERE user_id = ?1",
+                        [to_sql_id(user_id.get())],
+                        |row| {
+                            Ok(Self {
+                                model: row.get(0)?,
+                                private: row.get(1)?,
+                                language: row.get(2)?,
+                                temperature: row.get(3)?,
+                            })
+                        },
+                    )
+                    .optional()
+            })
+            .map(Option::unwrap_or_default)
+    }
+
+    pub fn save(&self, database: &Database, user_id: UserId) -> anyhow::Result<()> {
+        database.with_connection(|connection| {
+            if *self == Self::default() {
+                connection.execute(
+                    "DELETE FROM preferences WHERE user_id = ?1",
+                    [to_sql_id(user_id.get())],
+                )?;
+                return Ok(());
+            }
+
+            connection.execute(
+                "INSERT INTO preferences (user_id, model, private, language, temperature)
+                VALUES (?1, ?2, ?3, ?4, ?5)
+                ON CONFLICT (user_id) DO UPDATE SET
+                    model = excluded.model,
+                    private = excluded.private,
+                    language = excluded.language,
+                    temperature = excluded.temperature",
+                rusqlite::params![
+                    to_sql_id(user_id.get()),
+                    self.model,
+                    self.private,
+                    self.language,
+                    self.temperature
+                ],
+            )?;
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,33 @@
+use serenity::all::{ButtonStyle, CreateButton, CreateMessage, MessageId, UserId};
+
+use crate::localization;
+
+pub const PUBLISH_ID_BASE: &str = "publish";
+
+/// Builds a publish button message ID from a message ID and a user ID.
+pub fn build_id(first_id: MessageId, user_id: UserId) -> String {
+    format!("{PUBLISH_ID_BASE}#{first_id}#{user_id}")
+}
+
+/// Parses a publish button message ID into a message ID and a user ID.
+pub fn parse_id(id: &str) -> Option<(MessageId, UserId)> {
+    let mut split_id = id.split('#');
+    if split_id.next() != Some(PUBLISH_ID_BASE) {
+        return None;
+    }
+    Some((
+        MessageId::new(split_id.next()?.parse::<u64>().ok()?),
+        UserId::new(split_id.next()?.parse::<u64>().ok()?),
+    ))
+}
+
+/// Creates the button that posts the private output starting at `first_id` publicly.
+pub fn button(first_id: MessageId, user_id: UserId, locale: &str) -> CreateButton {
+    CreateButton::new(build_id(first_id, user_id))
+        .style(ButtonStyle::Primary)
+        .label(localization::text(locale, "Publish"))
+}
+
+/// The messages of recent private outputs, keyed by the ID of their first message,
+/// so that they can be posted when the publish button is pressed.
+pub type Store = crate::store::Store<MessageId, Vec<CreateMessage>>;
@@ -0,0 +1,281 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use serenity::all::{MessageId, UserId};
+use tokio::sync::watch;
+
+use crate::config;
+
+/// Limits how many generations run at once, on the API server as a whole and for each
+/// model. Jobs beyond the limits wait their turn, with each user's jobs interleaved with
+/// everyone else's so that no one user can hold up the queue.
+pub struct Queue {
+    inner: Mutex<Inner>,
+    /// Notified whenever a job starts or leaves, so that waiting jobs can check their position
+    changed: watch::Sender<()>,
+    max_concurrent: Option<usize>,
+    model_limits: HashMap<String, usize>,
+}
+struct Inner {
+    next_id: u64,
+    /// All jobs that haven't been dropped, in the order they joined
+    jobs: Vec<Entry>,
+    /// The number of jobs started so far
+    started: u64,
+    /// When each user's latest job started, counted in jobs started
+    last_started: HashMap<UserId, u64>,
+}
+struct Entry {
+    id: u64,
+    /// The message that the job is shown in, so that it can be cancelled
+    key: MessageId,
+    user_id: UserId,
+    model: String,
+    status: Status,
+}
+
+/// Where a job is in the queue.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    /// Waiting to start, at the given position (starting from 1)
+    Waiting(usize),
+    Running,
+    /// Removed from the queue before it could start
+    Cancelled,
+}
+
+impl Queue {
+    pub fn new(config: &config::Queue, models: &HashMap<String, config::Model>) -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                next_id: 0,
+                jobs: vec![],
+                started: 0,
+                last_started: HashMap::new(),
+            }),
+            changed: watch::Sender::new(()),
+            max_concurrent: config.max_concurrent,
+            model_limits: models
+                .iter()
+                .filter_map(|(name, model)| Some((name.clone(), model.max_concurrent?)))
+                .collect(),
+        }
+    }
+
+    /// Adds a job for `model` to the queue, which starts straight away if there is room.
+    /// The job holds its place until it is dropped.
+    pub fn join(self: &Arc<Self>, key: MessageId, user_id: UserId, model: &str) -> Job {
+        let id = {
+            let mut inner = self.inner.lock().unwrap();
+            let id = inner.next_id;
+            inner.next_id += 1;
+            inner.jobs.push(Entry {
+                id,
+                key,
+                user_id,
+                model: model.to_string(),
+                status: Status::Waiting(0),
+            });
+            self.dispatch(&mut inner);
+            id
+        };
+        self.changed.send_replace(());
+
+        Job {
+            queue: self.clone(),
+            id,
+            changed: self.changed.subscribe(),
+        }
+    }
+
+    /// Removes the waiting jobs shown in the given message from the queue. Returns whether
+    /// or not there were any.
+    pub fn cancel(&self, key: MessageId) -> bool {
+        let mut cancelled = false;
+        {
+            let mut inner = self.inner.lock().unwrap();
+            for entry in inner.jobs.iter_mut().filter(|e| e.key == key) {
+                if let Status::Waiting(_) = entry.status {
+                    entry.status = Status::Cancelled;
+                    cancelled = true;
+                }
+            }
+            if cancelled {
+                self.dispatch(&mut inner);
+            }
+        }
+        if cancelled {
+            self.changed.send_replace(());
+        }
+        cancelled
+    }
+
+    /// Starts as many waiting jobs as the limits allow, in turn, then updates the
+    /// positions of the rest.
+    fn dispatch(&self, inner: &mut Inner) {
+        let mut order = waiting_order(inner);
+        while let Some(index) = order.iter().position(|&i| self.has_room(inner, i)) {
+            let entry = &mut inner.jobs[order[index]];
+            entry.status = Status::Running;
+            inner.started += 1;
+            inner.last_started.insert(entry.user_id, inner.started);
+            order = waiting_order(inner);
+        }
+        for (position, i) in order.into_iter().enumerate() {
+            inner.jobs[i].status = Status::Waiting(position + 1);
+        }
+    }
+
+    /// Whether or not the job at `index` can start without exceeding the limits.
+    fn has_room(&self, inner: &Inner, index: usize) -> bool {
+        let model = &inner.jobs[index].model;
+        let running = inner.jobs.iter().filter(|e| e.status == Status::Running);
+        let within_limit = self
+            .max_concurrent
+            .is_none_or(|max| running.clone().count() < max);
+        let within_model_limit = self
+            .model_limits
+            .get(model)
+            .is_none_or(|&max| running.filter(|e| e.model == *model).count() < max);
+        within_limit && within_model_limit
+    }
+
+    fn remove(&self, id: u64) {
+        {
+            let mut inner = self.inner.lock().unwrap();
+            inner.jobs.retain(|e| e.id != id);
+            self.dispatch(&mut inner);
+        }
+        self.changed.send_replace(());
+    }
+}
+
+/// The indices of the waiting jobs, in the order they will start. Each user's jobs take
+/// turns with other users' jobs, counting those already running. Ties go to the user who
+/// started a job least recently, and then to whichever job joined first.
+fn waiting_order(inner: &Inner) -> Vec<usize> {
+    let mut turns: HashMap<UserId, usize> = HashMap::new();
+    for entry in inner.jobs.iter().filter(|e| e.status == Status::Running) {
+        *turns.entry(entry.user_id).or_default() += 1;
+    }
+
+    let mut order: Vec<(usize, u64, usize)> = vec![];
+    for (i, entry) in inner.jobs.iter().enumerate() {
+        if let Status::Waiting(_) = entry.status {
+            let turn = turns.entry(entry.user_id).or_default();
+            let last_started = inner
+                .last_started
+                .get(&entry.user_id)
+                .copied()
+                .unwrap_or_default();
+            order.push((*turn, last_started, i));
+            *turn += 1;
+        }
+    }
+    order.sort();
+    order.into_iter().map(|(_, _, i)| i).collect()
+}
+
+/// A place in the [`Queue`]. Dropping the job leaves the queue, and frees its slot if
+/// it was running.
+pub struct Job {
+    queue: Arc<Queue>,
+    id: u64,
+    changed: watch::Receiver<()>,
+}
+impl Job {
+    pub fn status(&self) -> Status {
+        self.queue
+            .inner
+            .lock()
+            .unwrap()
+            .jobs
+            .iter()
+            .find(|e| e.id == self.id)
+            .map_or(Status::Cancelled, |e| e.status)
+    }
+
+    /// Waits until the queue has changed since this was last called.
+    pub async fn changed(&mut self) {
+        // The sender lives as long as the queue, which this job keeps alive
+        self.changed.changed().await.ok();
+    }
+
+    /// Waits until the job can start, returning `false` if it was cancelled instead.
+    pub async fn wait(&mut self) -> bool {
+        loop {
+            match self.status() {
+                Status::Running => return true,
+                Status::Cancelled => return false,
+                Status::Waiting(_) => self.changed().await,
+            }
+        }
+    }
+}
+impl Drop for Job {
+    fn drop(&mut self) {
+        self.queue.remove(self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn queue(max_concurrent: usize) -> Arc<Queue> {
+        Arc::new(Queue::new(
+            &config::Queue {
+                max_concurrent: Some(max_concurrent),
+            },
+            &HashMap::from_iter([(
+                "small".to_string(),
+                config::Model {
+                    max_concurrent: Some(1),
+                    ..Default::default()
+                },
+            )]),
+        ))
+    }
+
+    #[test]
+    fn test_fair_order() {
+        let queue = queue(1);
+        let (alice, bob) = (UserId::new(1), UserId::new(2));
+        let key = MessageId::new(1);
+
+        let running = queue.join(key, alice, "large");
+        let alice_2 = queue.join(key, alice, "large");
+        let alice_3 = queue.join(key, alice, "large");
+        let bob_1 = queue.join(key, bob, "large");
+        assert_eq!(running.status(), Status::Running);
+        // Bob hasn't had a turn yet, so he goes before Alice's later jobs
+        assert_eq!(bob_1.status(), Status::Waiting(1));
+        assert_eq!(alice_2.status(), Status::Waiting(2));
+        assert_eq!(alice_3.status(), Status::Waiting(3));
+
+        drop(running);
+        assert_eq!(bob_1.status(), Status::Running);
+        assert_eq!(alice_2.status(), Status::Waiting(1));
+    }
+
+    #[test]
+    fn test_model_limit_and_cancel() {
+        let queue = queue(3);
+        let user_id = UserId::new(1);
+
+        let small_1 = queue.join(MessageId::new(1), user_id, "small");
+        let small_2 = queue.join(MessageId::new(2), user_id, "small");
+        // Other models can still run while `small` is at its limit
+        let large = queue.join(MessageId::new(3), user_id, "large");
+        assert_eq!(small_1.status(), Status::Running);
+        assert_eq!(small_2.status(), Status::Waiting(1));
+        assert_eq!(large.status(), Status::Running);
+
+        assert!(queue.cancel(MessageId::new(2)));
+        assert_eq!(small_2.status(), Status::Cancelled);
+        // Running jobs can't be cancelled through the queue
+        assert!(!queue.cancel(MessageId::new(1)));
+    }
+}
@@ -0,0 +1,85 @@
+//! Support for reasoning models, which either stream their reasoning separately
+//! (as `reasoning_content`), or inline at the start of their output in a `<think>` block.
+
+const OPEN_TAG: &str = "<think>";
+const CLOSE_TAG: &str = "</think>";
+
+/// Splits streamed content into its inline reasoning (if any) and the answer.
+///
+/// Handles content that is still being streamed. If `implicit_open_tag` is set (for models
+/// whose prompt templates open the block themselves), content with only the closing tag is
+/// reasoning up to that tag; otherwise, a closing tag on its own is part of the answer.
+pub fn split(content: &str, implicit_open_tag: bool) -> (&str, &str) {
+    let trimmed = content.trim_start();
+    if let Some(after_open) = trimmed.strip_prefix(OPEN_TAG) {
+        return match after_open.split_once(CLOSE_TAG) {
+            Some((reasoning, answer)) => (reasoning.trim(), answer.trim_start()),
+            None => (after_open.trim(), ""),
+        };
+    }
+
+    // The opening tag may not have been fully streamed yet
+    if !trimmed.is_empty() && OPEN_TAG.starts_with(trimmed) {
+        return ("", "");
+    }
+
+    match content.split_once(CLOSE_TAG) {
+        Some((reasoning, answer)) if implicit_open_tag => (reasoning.trim(), answer.trim_start()),
+        _ => ("", content),
+    }
+}
+
+/// Returns at most the last `max_len` bytes of `text`, cut at a character boundary.
+pub fn tail(text: &str, max_len: usize) -> &str {
+    if text.len() <= max_len {
+        return text;
+    }
+
+    let mut start = text.len() - max_len;
+    while !text.is_char_boundary(start) {
+        start += 1;
+    }
+    &text[start..]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_split() {
+        // No reasoning
+        assert_eq!(split("Hello, world!", false), ("", "Hello, world!"));
+
+        // Complete reasoning block
+        assert_eq!(
+            split("<think>\nThe user greeted me.\n</think>\n\nHello!", false),
+            ("The user greeted me.", "Hello!")
+        );
+
+        // Reasoning block still being streamed
+        assert_eq!(split("<think>The user", false), ("The user", ""));
+
+        // Opening tag still being streamed
+        assert_eq!(split("<thi", false), ("", ""));
+
+        // Only the closing tag is present
+        assert_eq!(
+            split("The user greeted me.</think>Hello!", true),
+            ("The user greeted me.", "Hello!")
+        );
+        // ...which is only reasoning for models that are known to leave out the opening tag
+        assert_eq!(
+            split("Models close their reasoning with </think>.", false),
+            ("", "Models close their reasoning with </think>.")
+        );
+    }
+
+    #[test]
+    fn test_tail() {
+        assert_eq!(tail("hello", 10), "hello");
+        assert_eq!(tail("hello", 3), "llo");
+        // Does not cut in the middle of a character
+        assert_eq!(tail("héllo", 4), "llo");
+    }
+}
@@ -0,0 +1,231 @@
+use serenity::{
+    all::{
+        ButtonStyle, ChannelId, CreateActionRow, CreateAllowedMentions, CreateAttachment,
+        CreateButton, CreateInteractionResponseFollowup, CreateMessage, Http, MessageId, UserId,
+    },
+    builder::Builder as _,
+};
+
+use crate::{
+    localization,
+    moderation::Flag,
+    outputter::{self, Destination, Outputter},
+    util,
+};
+
+pub const REVIEW_ID_BASE: &str = "review";
+
+/// Shown instead of outputs that are being held for review.
+pub const HELD_MESSAGE: &str = "This response has been held for review by the moderators.";
+
+/// Builds a review button message ID from the held output's message ID and the decision.
+pub fn build_id(first_id: MessageId, approve: bool) -> String {
+    let decision = if approve { "approve" } else { "reject" };
+    format!("{REVIEW_ID_BASE}#{first_id}#{decision}")
+}
+
+/// Parses a review button message ID into the held output's message ID and whether
+/// or not it was approved.
+pub fn parse_id(id: &str) -> Option<(MessageId, bool)> {
+    let mut split_id = id.split('#');
+    if split_id.next() != Some(REVIEW_ID_BASE) {
+        return None;
+    }
+    let message_id = MessageId::new(split_id.next()?.parse::<u64>().ok()?);
+    let approve = match split_id.next()? {
+        "approve" => true,
+        "reject" => false,
+        _ => return None,
+    };
+    Some((message_id, approve))
+}
+
+/// Creates the buttons that approve or reject the held output starting at `first_id`.
+pub fn buttons(first_id: MessageId, locale: &str) -> CreateActionRow {
+    CreateActionRow::Buttons(vec![
+        CreateButton::new(build_id(first_id, true))
+            .style(ButtonStyle::Success)
+            .label(localization::text(locale, "Approve")),
+        CreateButton::new(build_id(first_id, false))
+            .style(ButtonStyle::Danger)
+            .label(localization::text(locale, "Reject")),
+    ])
+}
+
+/// An output held for review by moderators.
+#[derive(Debug, Clone)]
+pub struct Held {
+    pub recipient: Recipient,
+    pub output: String,
+}
+
+/// Where a held output is sent once it's approved.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Recipient {
+    /// The channel it was requested in, replying to the given message if there is one
+    Channel {
+        channel_id: ChannelId,
+        reply_to: Option<MessageId>,
+    },
+    /// Only the user who requested it, as the output was private. It's sent as a private
+    /// follow-up to their interaction while that can still be followed up, and as a
+    /// direct message after that.
+    User {
+        user_id: UserId,
+        interaction_token: Option<String>,
+    },
+}
+
+impl Held {
+    /// Holds the output sent to `destination`, whose first message is `first_id`.
+    pub fn new(
+        destination: &Destination<'_>,
+        private: bool,
+        first_id: MessageId,
+        output: String,
+    ) -> Self {
+        let recipient = if private {
+            Recipient::User {
+                user_id: destination.user_id(),
+                interaction_token: match destination {
+                    Destination::Interaction(interaction) => Some(interaction.token().to_string()),
+                    Destination::Channel { .. } => None,
+                },
+            }
+        } else {
+            Recipient::Channel {
+                channel_id: destination.channel_id(),
+                reply_to: Some(first_id),
+            }
+        };
+        Self { recipient, output }
+    }
+
+    /// Sends the approved output to its recipient.
+    pub async fn deliver(self, http: &Http) -> anyhow::Result<()> {
+        let chunks = outputter::chunk_message(&self.output, Outputter::MESSAGE_CHUNK_SIZE);
+        match self.recipient {
+            Recipient::Channel {
+                channel_id,
+                reply_to,
+            } => {
+                let messages = chunks
+                    .into_iter()
+                    .map(|chunk| CreateMessage::new().content(chunk))
+                    .collect();
+                util::send_reply_chain(http, channel_id, reply_to, messages).await
+            }
+            Recipient::User {
+                user_id,
+                interaction_token,
+            } => {
+                let mut chunks = chunks.into_iter().peekable();
+                if let Some(token) = &interaction_token {
+                    while let Some(chunk) = chunks.peek() {
+                        let followup = CreateInteractionResponseFollowup::new()
+                            .content(chunk)
+                            .ephemeral(true)
+                            .allowed_mentions(CreateAllowedMentions::new());
+                        if followup.execute(http, (None, token)).await.is_err() {
+                            // The interaction can no longer be followed up
+                            break;
+                        }
+                        chunks.next();
+                    }
+                }
+
+                let remaining: Vec<_> = chunks.collect();
+                if remaining.is_empty() {
+                    return Ok(());
+                }
+                let channel = user_id.create_dm_channel(http).await?;
+                for chunk in remaining {
+                    channel
+                        .send_message(
+                            http,
+                            CreateMessage::new()
+                                .content(chunk)
+                                .allowed_mentions(CreateAllowedMentions::new()),
+                        )
+                        .await?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Sends an output to the server's review channel, where moderators can approve it to
+/// send it on, or reject it. The report is written in `locale`, the server's, and
+/// `source` describes the command and who ran it where.
+pub async fn submit(
+    http: &Http,
+    store: &Store,
+    (review_channel_id, flag): (ChannelId, Flag),
+    (locale, source): (&str, &str),
+    first_id: MessageId,
+    prompt: &str,
+    held: Held,
+) -> anyhow::Result<()> {
+    let report = format!(
+        "{}\n-# {source}",
+        localization::format(
+            locale,
+            "**Held for review:** {{reason}}",
+            &[("reason", &flag.reason)]
+        )
+    );
+    let transcript = format!("## Prompt\n\n{prompt}\n\n## Response\n\n{}", held.output);
+    review_channel_id
+        .send_message(
+            http,
+            CreateMessage::new()
+                .content(report)
+                .add_file(CreateAttachment::bytes(transcript.into_bytes(), "held.md"))
+                .components(vec![buttons(first_id, locale)])
+                .allowed_mentions(CreateAllowedMentions::new()),
+        )
+        .await?;
+    store.insert(first_id, held);
+
+    Ok(())
+}
+
+/// Recently held outputs, keyed by the ID of their first message, so that they can be
+/// posted when approved.
+pub type Store = crate::store::Store<MessageId, Held>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_recipient() {
+        let destination = Destination::Channel {
+            channel_id: ChannelId::new(1),
+            guild_id: None,
+            user_id: UserId::new(2),
+        };
+        let first_id = MessageId::new(3);
+
+        // Public outputs are posted in the channel, in reply to where they were held
+        let held = Held::new(&destination, false, first_id, "Hello!".to_string());
+        assert_eq!(
+            held.recipient,
+            Recipient::Channel {
+                channel_id: ChannelId::new(1),
+                reply_to: Some(first_id),
+            }
+        );
+
+        // Private outputs are only ever sent to the user
+        let held = Held::new(&destination, true, first_id, "Hello!".to_string());
+        assert_eq!(
+            held.recipient,
+            Recipient::User {
+                user_id: UserId::new(2),
+                interaction_token: None,
+            }
+        );
+    }
+}
@@ -0,0 +1,342 @@
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use anyhow::Context as _;
+use chrono::{DateTime, Utc};
+use croner::Cron;
+use rusqlite::Row;
+use serenity::all::{ChannelId, CreateAllowedMentions, CreateMessage, GuildId, Http, UserId};
+
+use crate::{
+    commands::{execute, hallucinate},
+    database::{Database, from_sql_id, to_sql_id},
+    localization,
+    outputter::Destination,
+};
+
+/// How often the scheduler checks for jobs that are due. Schedules have a resolution of
+/// a minute, so jobs start up to this long after they're due.
+const CHECK_INTERVAL: Duration = Duration::from_secs(15);
+
+/// A job that runs on a recurring schedule, posting its output to a channel.
+#[derive(Debug, Clone)]
+pub struct Schedule {
+    pub id: i64,
+    pub guild_id: GuildId,
+    /// The channel the output is posted to
+    pub channel_id: ChannelId,
+    /// The user who created the schedule, who the job runs on behalf of
+    pub owner_id: UserId,
+    /// When the job runs, as a cron expression in UTC
+    pub cron: String,
+    pub job: Job,
+}
+
+/// What a scheduled job does.
+#[derive(Debug, Clone)]
+pub enum Job {
+    /// Runs a configured command with a fixed prompt
+    Command {
+        command: String,
+        prompt: String,
+        model: Option<String>,
+    },
+    /// Runs a Lua script, as with `/execute`
+    Script { code: String },
+}
+
+impl Schedule {
+    const COLUMNS: &str = "id, guild_id, channel_id, owner_id, cron, command, prompt, model, code";
+
+    /// Saves a new schedule, returning it with its ID.
+    pub fn create(
+        database: &Database,
+        guild_id: GuildId,
+        channel_id: ChannelId,
+        owner_id: UserId,
+        cron: String,
+        job: Job,
+    ) -> anyhow::Result<Self> {
+        let (command, prompt, model, code) = match &job {
+            Job::Command {
+                command,
+                prompt,
+                model,
+            } => (Some(command), Some(prompt), model.as_ref(), None),
+            Job::Script { code } => (None, None, None, Some(code)),
+        };
+        let id = database.with_connection(|connection| {
+            connection.execute(
+                "INSERT INTO schedules (guild_id, channel_id, owner_id, cron, command, prompt, model, code)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                rusqlite::params![
+                    to_sql_id(guild_id.get()),
+                    to_sql_id(channel_id.get()),
+                    to_sql_id(owner_id.get()),
+                    cron,
+                    command,
+                    prompt,
+                    model,
+                    code
+                ],
+            )?;
+            Ok(connection.last_insert_rowid())
+        })?;
+
+        Ok(Self {
+            id,
+            guild_id,
+            channel_id,
+            owner_id,
+            cron,
+            job,
+        })
+    }
+
+    /// Lists the guild's schedules, oldest first.
+    pub fn list(database: &Database, guild_id: GuildId) -> anyhow::Result<Vec<Self>> {
+        database.with_connection(|connection| {
+            connection
+                .prepare(&format!(
+                    "SELECT {} FROM schedules WHERE guild_id = ?1 ORDER BY id",
+                    Self::COLUMNS
+                ))?
+                .query_map([to_sql_id(guild_id.get())], Self::from_row)?
+                .collect()
+        })
+    }
+
+    /// Lists the schedules of every guild.
+    pub fn all(database: &Database) -> anyhow::Result<Vec<Self>> {
+        database.with_connection(|connection| {
+            connection
+                .prepare(&format!("SELECT {} FROM schedules", Self::COLUMNS))?
+                .query_map([], Self::from_row)?
+                .collect()
+        })
+    }
+
+    /// Deletes the guild's schedule with the given ID, returning whether or not it existed.
+    pub fn delete(database: &Database, guild_id: GuildId, id: i64) -> anyhow::Result<bool> {
+        database.with_connection(|connection| {
+            let deleted = connection.execute(
+                "DELETE FROM schedules WHERE id = ?1 AND guild_id = ?2",
+                rusqlite::params![id, to_sql_id(guild_id.get())],
+            )?;
+            Ok(deleted > 0)
+        })
+    }
+
+    /// The first time the job runs after `time`, if it ever does.
+    pub fn next_run(&self, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        parse_cron(&self.cron)
+            .ok()?
+            .find_next_occurrence(&time, false)
+            .ok()
+    }
+
+    fn from_row(row: &Row) -> rusqlite::Result<Self> {
+        let command: Option<String> = row.get(5)?;
+        let job = match command {
+            Some(command) => Job::Command {
+                command,
+                prompt: row.get::<_, Option<String>>(6)?.unwrap_or_default(),
+                model: row.get(7)?,
+            },
+            None => Job::Script {
+                code: row.get::<_, Option<String>>(8)?.unwrap_or_default(),
+            },
+        };
+        Ok(Self {
+            id: row.get(0)?,
+            guild_id: GuildId::new(from_sql_id(row.get(1)?)),
+            channel_id: ChannelId::new(from_sql_id(row.get(2)?)),
+            owner_id: UserId::new(from_sql_id(row.get(3)?)),
+            cron: row.get(4)?,
+            job,
+        })
+    }
+}
+
+/// Parses a cron expression with five fields (minute, hour, day of month, month and day
+/// of week), or a nickname like `@daily`.
+pub fn parse_cron(cron: &str) -> anyhow::Result<Cron> {
+    Cron::new(cron)
+        .parse()
+        .with_context(|| format!("`{cron}` is not a valid cron expression"))
+}
+
+/// Runs scheduled jobs when they're due.
+pub struct Scheduler {
+    database: Arc<Database>,
+    /// The handlers of the configured commands, keyed by their name
+    commands: HashMap<String, hallucinate::Handler>,
+    execute: execute::Handler,
+}
+impl Scheduler {
+    pub fn new(
+        database: Arc<Database>,
+        commands: HashMap<String, hallucinate::Handler>,
+        execute: execute::Handler,
+    ) -> Self {
+        Self {
+            database,
+            commands,
+            execute,
+        }
+    }
+
+    /// The names of the commands that can be scheduled, sorted by name.
+    pub fn schedulable_commands(&self) -> Vec<&str> {
+        let mut names: Vec<_> = self
+            .commands
+            .iter()
+            .filter(|(_, handler)| handler.can_run_unattended())
+            .map(|(name, _)| name.as_str())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// Checks for due jobs in the background, for as long as the bot runs.
+    pub fn start(self: Arc<Self>, http: Arc<Http>) {
+        tokio::spawn(async move {
+            let mut last_check = Utc::now();
+            let mut interval = tokio::time::interval(CHECK_INTERVAL);
+            loop {
+                interval.tick().await;
+                let now = Utc::now();
+                let schedules = match Schedule::all(&self.database) {
+                    Ok(schedules) => schedules,
+                    Err(err) => {
+                        // Try again next time, catching up on any jobs that were due
+                        println!("Failed to load schedules: {err:?}");
+                        continue;
+                    }
+                };
+
+                for schedule in schedules {
+                    if !is_due(&schedule.cron, last_check, now) {
+                        continue;
+                    }
+                    let scheduler = self.clone();
+                    let http = http.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = scheduler.run(&http, &schedule).await {
+                            scheduler.report_error(&http, &schedule, err).await;
+                        }
+                    });
+                }
+                last_check = now;
+            }
+        });
+    }
+
+    async fn run(&self, http: &Http, schedule: &Schedule) -> anyhow::Result<()> {
+        let destination = Destination::Channel {
+            channel_id: schedule.channel_id,
+            guild_id: Some(schedule.guild_id),
+            user_id: schedule.owner_id,
+        };
+        match &schedule.job {
+            Job::Command {
+                command,
+                prompt,
+                model,
+            } => {
+                let handler = self
+                    .commands
+                    .get(command)
+                    .with_context(|| format!("the command `{command}` no longer exists"))?;
+                handler
+                    .run(
+                        http,
+                        destination,
+                        hallucinate::Request {
+                            title: prompt.clone(),
+                            prompt: prompt.clone(),
+                            model: model.clone(),
+                            seed: 0,
+                            variables: HashMap::new(),
+                            attachments: vec![],
+                            system_prompt: None,
+                            private: Some(false),
+                            persona: None,
+                            // Each run should get a new response
+                            use_cache: false,
+                        },
+                    )
+                    .await
+            }
+            Job::Script { code } => self.execute.run(http, destination, code).await,
+        }
+    }
+
+    /// Posts the error from a job to its channel, as there's no one to respond to.
+    async fn report_error(&self, http: &Http, schedule: &Schedule, err: anyhow::Error) {
+        let message = CreateMessage::new()
+            .content(localization::format(
+                localization::DEFAULT_LOCALE,
+                "Error in scheduled job `#{{id}}`: {{error}}",
+                &[
+                    ("id", &schedule.id.to_string()),
+                    ("error", &err.to_string()),
+                ],
+            ))
+            .allowed_mentions(CreateAllowedMentions::new());
+        if let Err(err) = schedule.channel_id.send_message(http, message).await {
+            println!(
+                "Failed to report error in scheduled job #{}: {err:?}",
+                schedule.id
+            );
+        }
+    }
+}
+
+/// Whether or not the job should run at some time after `last_check`, up to `now`.
+fn is_due(cron: &str, last_check: DateTime<Utc>, now: DateTime<Utc>) -> bool {
+    parse_cron(cron)
+        .and_then(|cron| Ok(cron.find_next_occurrence(&last_check, false)?))
+        .is_ok_and(|next| next <= now)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn time(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_is_due() {
+        let hourly = "0 * * * *";
+        assert!(is_due(
+            hourly,
+            time("2024-01-01T09:59:50Z"),
+            time("2024-01-01T10:00:05Z")
+        ));
+        // A run exactly at the last check has already happened
+        assert!(!is_due(
+            hourly,
+            time("2024-01-01T10:00:00Z"),
+            time("2024-01-01T10:00:15Z")
+        ));
+        assert!(!is_due(
+            hourly,
+            time("2024-01-01T10:00:05Z"),
+            time("2024-01-01T10:59:59Z")
+        ));
+        assert!(!is_due(
+            "not a cron expression",
+            time("2024-01-01T00:00:00Z"),
+            time("2024-12-31T00:00:00Z")
+        ));
+    }
+
+    #[test]
+    fn test_parse_cron() {
+        assert!(parse_cron("*/15 9-17 * * MON-FRI").is_ok());
+        assert!(parse_cron("@daily").is_ok());
+        assert!(parse_cron("61 * * * *").is_err());
+    }
+}
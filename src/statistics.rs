@@ -0,0 +1,198 @@
+use std::time::{Duration, Instant};
+
+use async_openai::types::{CompletionUsage, CreateChatCompletionRequest, FinishReason};
+
+use crate::localization;
+
+/// Statistics about a single generation, gathered while it is streamed.
+pub struct Statistics {
+    seed: u32,
+    started_at: Instant,
+    first_token_at: Option<Instant>,
+    finished_at: Option<Instant>,
+    /// The number of content deltas received; used if the server does not report usage.
+    chunk_count: u32,
+    usage: Option<CompletionUsage>,
+    finish_reason: Option<FinishReason>,
+}
+impl Statistics {
+    pub fn new(seed: u32) -> Self {
+        Self {
+            seed,
+            started_at: Instant::now(),
+            first_token_at: None,
+            finished_at: None,
+            chunk_count: 0,
+            usage: None,
+            finish_reason: None,
+        }
+    }
+
+    /// Records that a content delta was received.
+    pub fn on_content(&mut self) {
+        self.first_token_at.get_or_insert_with(Instant::now);
+        self.chunk_count += 1;
+    }
+
+    /// Records the usage of a request. Usage is summed across requests, so that
+    /// tool-calling rounds are included.
+    pub fn on_usage(&mut self, usage: CompletionUsage) {
+        match &mut self.usage {
+            Some(total) => {
+                total.prompt_tokens += usage.prompt_tokens;
+                total.completion_tokens += usage.completion_tokens;
+                total.total_tokens += usage.total_tokens;
+            }
+            None => self.usage = Some(usage),
+        }
+    }
+
+    pub fn on_finish_reason(&mut self, finish_reason: FinishReason) {
+        self.finish_reason = Some(finish_reason);
+    }
+
+    /// Records that the generation has finished.
+    pub fn finish(&mut self) {
+        self.finished_at = Some(Instant::now());
+    }
+
+    fn time_to_first_token(&self) -> Option<Duration> {
+        self.first_token_at.map(|t| t - self.started_at)
+    }
+
+    fn total_time(&self) -> Duration {
+        self.finished_at.unwrap_or_else(Instant::now) - self.started_at
+    }
+
+    fn completion_tokens(&self) -> u32 {
+        self.usage
+            .as_ref()
+            .map_or(self.chunk_count, |u| u.completion_tokens)
+    }
+
+    fn tokens_per_second(&self) -> Option<f64> {
+        let generation_time =
+            self.total_time() - self.time_to_first_token().unwrap_or(Duration::ZERO);
+        (generation_time.as_secs_f64() > 0.0)
+            .then(|| self.completion_tokens() as f64 / generation_time.as_secs_f64())
+    }
+
+    fn finish_reason(&self) -> &'static str {
+        match self.finish_reason {
+            Some(FinishReason::Stop) => "stop",
+            Some(FinishReason::Length) => "length",
+            Some(FinishReason::ToolCalls) => "tool calls",
+            Some(FinishReason::ContentFilter) => "content filter",
+            Some(FinishReason::FunctionCall) => "function call",
+            None => "unknown",
+        }
+    }
+
+    /// A compact, single-line summary in `locale`, suitable for appending to the output.
+    pub fn footer(&self, locale: &str) -> String {
+        let format = |template, variables: &[(&str, &str)]| {
+            localization::format(locale, template, variables)
+        };
+        let mut parts = vec![];
+        if let Some(ttft) = self.time_to_first_token() {
+            let seconds = format!("{:.1}", ttft.as_secs_f64());
+            parts.push(format(
+                "{{seconds}}s to first token",
+                &[("seconds", &seconds)],
+            ));
+        }
+        let seconds = format!("{:.1}", self.total_time().as_secs_f64());
+        parts.push(format("{{seconds}}s total", &[("seconds", &seconds)]));
+        if let Some(tps) = self.tokens_per_second() {
+            parts.push(format(
+                "{{rate}} tokens/s",
+                &[("rate", &format!("{tps:.1}"))],
+            ));
+        }
+        match &self.usage {
+            Some(usage) => parts.push(format(
+                "{{prompt}} prompt / {{completion}} completion tokens",
+                &[
+                    ("prompt", &usage.prompt_tokens.to_string()),
+                    ("completion", &usage.completion_tokens.to_string()),
+                ],
+            )),
+            None => parts.push(format(
+                "~{{count}} completion tokens",
+                &[("count", &self.chunk_count.to_string())],
+            )),
+        }
+        parts.push(format(
+            "finish: {{reason}}",
+            &[("reason", self.finish_reason())],
+        ));
+        parts.push(format("seed {{seed}}", &[("seed", &self.seed.to_string())]));
+
+        parts.join(" · ")
+    }
+
+    /// A full breakdown in `locale`, including the parameters of the request and the
+    /// system prompt, if it can be shown to everyone.
+    pub fn details(
+        &self,
+        locale: &str,
+        system_prompt: Option<&str>,
+        request: &CreateChatCompletionRequest,
+    ) -> String {
+        let line = |output: &mut String, template, value: String| {
+            *output += &localization::format(locale, template, &[("value", &value)]);
+            output.push('\n');
+        };
+        let mut output = format!("**{}**\n", localization::text(locale, "Statistics"));
+        if let Some(ttft) = self.time_to_first_token() {
+            let seconds = format!("{:.2}", ttft.as_secs_f64());
+            line(&mut output, "Time to first token: {{value}}s", seconds);
+        }
+        let seconds = format!("{:.2}", self.total_time().as_secs_f64());
+        line(&mut output, "Total time: {{value}}s", seconds);
+        if let Some(tps) = self.tokens_per_second() {
+            line(
+                &mut output,
+                "Tokens per second: {{value}}",
+                format!("{tps:.2}"),
+            );
+        }
+        match &self.usage {
+            Some(usage) => {
+                let (prompt, completion) = (usage.prompt_tokens, usage.completion_tokens);
+                line(&mut output, "Prompt tokens: {{value}}", prompt.to_string());
+                line(
+                    &mut output,
+                    "Completion tokens: {{value}}",
+                    completion.to_string(),
+                );
+            }
+            None => line(
+                &mut output,
+                "Completion tokens: {{value}}",
+                format!("~{}", self.chunk_count),
+            ),
+        }
+        let reason = self.finish_reason().to_string();
+        line(&mut output, "Finish reason: {{value}}", reason);
+
+        // Everything but the messages, which are too large to show
+        let mut parameters = serde_json::to_value(request).unwrap_or_default();
+        if let Some(parameters) = parameters.as_object_mut() {
+            parameters.remove("messages");
+        }
+        output += &format!(
+            "\n**{}**\n```json\n{}\n```\n",
+            localization::text(locale, "Parameters"),
+            serde_json::to_string_pretty(&parameters).unwrap_or_default()
+        );
+        if let Some(system_prompt) = system_prompt {
+            output += &format!(
+                "\n**{}**\n```\n{system_prompt}\n```",
+                localization::text(locale, "System prompt")
+            );
+        }
+
+        output
+    }
+}
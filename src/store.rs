@@ -0,0 +1,55 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::Hash,
+    sync::Mutex,
+};
+
+/// Keeps recently-inserted values in memory, discarding the oldest once there are
+/// more than `CAPACITY`. Used for state that buttons need after a command has finished.
+pub struct Store<K, V> {
+    inner: Mutex<StoreInner<K, V>>,
+}
+struct StoreInner<K, V> {
+    values: HashMap<K, V>,
+    order: VecDeque<K>,
+}
+impl<K, V> Default for Store<K, V> {
+    fn default() -> Self {
+        Self {
+            inner: Mutex::new(StoreInner {
+                values: HashMap::new(),
+                order: VecDeque::new(),
+            }),
+        }
+    }
+}
+impl<K: Eq + Hash + Clone, V> Store<K, V> {
+    /// The number of values to keep before the oldest are discarded.
+    const CAPACITY: usize = 1000;
+
+    pub fn insert(&self, key: K, value: V) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.values.insert(key.clone(), value).is_none() {
+            inner.order.push_back(key);
+        }
+        while inner.order.len() > Self::CAPACITY {
+            if let Some(key) = inner.order.pop_front() {
+                inner.values.remove(&key);
+            }
+        }
+    }
+
+    pub fn get(&self, key: &K) -> Option<V>
+    where
+        V: Clone,
+    {
+        self.inner.lock().unwrap().values.get(key).cloned()
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        let mut inner = self.inner.lock().unwrap();
+        let value = inner.values.remove(key)?;
+        inner.order.retain(|k| k != key);
+        Some(value)
+    }
+}
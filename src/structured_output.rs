@@ -0,0 +1,289 @@
+//! Support for structured (JSON) output: parsing, validation against a JSON schema,
+//! and conversion to template variables.
+//!
+//! Only the commonly-used subset of JSON Schema is validated: `type`, `enum`, `const`,
+//! `properties`, `required`, `additionalProperties`, `items`, `minItems`/`maxItems`,
+//! `minLength`/`maxLength` and `minimum`/`maximum`. Other keywords are ignored.
+
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+/// Parses a model's response as JSON, ignoring any surrounding Markdown code block.
+pub fn parse(response: &str) -> Result<Value, serde_json::Error> {
+    let response = response.trim();
+    let response = response
+        .strip_prefix("```json")
+        .or_else(|| response.strip_prefix("```"))
+        .and_then(|r| r.strip_suffix("```"))
+        .unwrap_or(response);
+    serde_json::from_str(response)
+}
+
+/// Validates `value` against `schema`, returning a description of each problem found.
+pub fn validate(schema: &Value, value: &Value) -> Vec<String> {
+    let mut errors = vec![];
+    validate_at(schema, value, "$", &mut errors);
+    errors
+}
+
+fn validate_at(schema: &Value, value: &Value, path: &str, errors: &mut Vec<String>) {
+    let Some(schema) = schema.as_object() else {
+        return;
+    };
+
+    if let Some(expected) = schema.get("type") {
+        let types: Vec<&str> = match expected {
+            Value::String(t) => vec![t.as_str()],
+            Value::Array(ts) => ts.iter().filter_map(Value::as_str).collect(),
+            _ => vec![],
+        };
+        if !types.is_empty() && !types.iter().any(|t| is_type(value, t)) {
+            errors.push(format!(
+                "{path}: expected {}, got {}",
+                types.join(" or "),
+                type_name(value)
+            ));
+            return;
+        }
+    }
+
+    if let Some(options) = schema.get("enum").and_then(Value::as_array) {
+        if !options.contains(value) {
+            errors.push(format!(
+                "{path}: expected one of {}",
+                Value::from(options.clone())
+            ));
+        }
+    }
+    if let Some(expected) = schema.get("const") {
+        if expected != value {
+            errors.push(format!("{path}: expected {expected}"));
+        }
+    }
+
+    match value {
+        Value::Object(object) => {
+            let properties = schema.get("properties").and_then(Value::as_object);
+            for required in schema
+                .get("required")
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+                .filter_map(Value::as_str)
+            {
+                if !object.contains_key(required) {
+                    errors.push(format!("{path}: missing required property `{required}`"));
+                }
+            }
+            for (key, value) in object {
+                let property_path = format!("{path}.{key}");
+                match properties.and_then(|p| p.get(key)) {
+                    Some(property_schema) => {
+                        validate_at(property_schema, value, &property_path, errors)
+                    }
+                    None => match schema.get("additionalProperties") {
+                        Some(Value::Bool(false)) => {
+                            errors.push(format!("{property_path}: unexpected property"))
+                        }
+                        Some(additional @ Value::Object(_)) => {
+                            validate_at(additional, value, &property_path, errors)
+                        }
+                        _ => {}
+                    },
+                }
+            }
+        }
+        Value::Array(items) => {
+            check_bounds(
+                schema,
+                "minItems",
+                "maxItems",
+                items.len(),
+                "items",
+                path,
+                errors,
+            );
+            if let Some(item_schema) = schema.get("items") {
+                for (i, item) in items.iter().enumerate() {
+                    validate_at(item_schema, item, &format!("{path}[{i}]"), errors);
+                }
+            }
+        }
+        Value::String(s) => {
+            let length = s.chars().count();
+            check_bounds(
+                schema,
+                "minLength",
+                "maxLength",
+                length,
+                "characters",
+                path,
+                errors,
+            );
+        }
+        Value::Number(n) => {
+            let n = n.as_f64().unwrap_or_default();
+            if let Some(minimum) = schema.get("minimum").and_then(Value::as_f64) {
+                if n < minimum {
+                    errors.push(format!("{path}: must be at least {minimum}"));
+                }
+            }
+            if let Some(maximum) = schema.get("maximum").and_then(Value::as_f64) {
+                if n > maximum {
+                    errors.push(format!("{path}: must be at most {maximum}"));
+                }
+            }
+        }
+        _ => {}
+    }
+}
+
+fn check_bounds(
+    schema: &serde_json::Map<String, Value>,
+    min_key: &str,
+    max_key: &str,
+    length: usize,
+    unit: &str,
+    path: &str,
+    errors: &mut Vec<String>,
+) {
+    if let Some(min) = schema.get(min_key).and_then(Value::as_u64) {
+        if (length as u64) < min {
+            errors.push(format!("{path}: must have at least {min} {unit}"));
+        }
+    }
+    if let Some(max) = schema.get(max_key).and_then(Value::as_u64) {
+        if (length as u64) > max {
+            errors.push(format!("{path}: must have at most {max} {unit}"));
+        }
+    }
+}
+
+fn is_type(value: &Value, expected: &str) -> bool {
+    match expected {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64(),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        _ => true,
+    }
+}
+
+fn type_name(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+/// Flattens `value` into template variables, with nested properties available as
+/// `{{a.b}}` and array elements as `{{a.0}}`. Arrays themselves are available as a
+/// comma-separated list, and the entire value is available as `{{json}}`.
+pub fn template_variables(value: &Value) -> HashMap<String, String> {
+    let mut variables = HashMap::from_iter([(
+        "json".to_string(),
+        serde_json::to_string_pretty(value).unwrap_or_default(),
+    )]);
+    add_variables(value, None, &mut variables);
+    variables
+}
+
+fn add_variables(value: &Value, prefix: Option<&str>, variables: &mut HashMap<String, String>) {
+    let key = |name: &str| match prefix {
+        Some(prefix) => format!("{prefix}.{name}"),
+        None => name.to_string(),
+    };
+
+    match value {
+        Value::Object(object) => {
+            for (name, value) in object {
+                add_variables(value, Some(&key(name)), variables);
+            }
+        }
+        Value::Array(items) => {
+            for (i, item) in items.iter().enumerate() {
+                add_variables(item, Some(&key(&i.to_string())), variables);
+            }
+            if let Some(prefix) = prefix {
+                let list = items.iter().map(to_text).collect::<Vec<_>>().join(", ");
+                variables.insert(prefix.to_string(), list);
+            }
+        }
+        scalar => {
+            if let Some(prefix) = prefix {
+                variables.insert(prefix.to_string(), to_text(scalar));
+            }
+        }
+    }
+}
+
+fn to_text(value: &Value) -> String {
+    match value {
+        Value::String(s) => s.clone(),
+        other => other.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse(r#"{"a": 1}"#).unwrap(), json!({"a": 1}));
+        assert_eq!(parse("```json\n{\"a\": 1}\n```").unwrap(), json!({"a": 1}));
+        assert!(parse("not json").is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "name": { "type": "string", "minLength": 1 },
+                "age": { "type": "integer", "minimum": 0 },
+                "mood": { "enum": ["happy", "sad"] },
+                "tags": { "type": "array", "items": { "type": "string" } }
+            },
+            "required": ["name", "age"],
+            "additionalProperties": false
+        });
+
+        let valid = json!({ "name": "Ferris", "age": 9, "mood": "happy", "tags": ["crab"] });
+        assert!(validate(&schema, &valid).is_empty());
+
+        let invalid = json!({ "name": "", "mood": "angry", "tags": [1], "extra": true });
+        assert_eq!(
+            validate(&schema, &invalid),
+            vec![
+                "$: missing required property `age`",
+                "$.extra: unexpected property",
+                "$.mood: expected one of [\"happy\",\"sad\"]",
+                "$.name: must have at least 1 characters",
+                "$.tags[0]: expected string, got number",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_template_variables() {
+        let variables = template_variables(&json!({
+            "name": "Ferris",
+            "stats": { "legs": 10 },
+            "tags": ["crab", "rust"]
+        }));
+        assert_eq!(variables["name"], "Ferris");
+        assert_eq!(variables["stats.legs"], "10");
+        assert_eq!(variables["tags"], "crab, rust");
+        assert_eq!(variables["tags.1"], "rust");
+    }
+}
@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use serenity::{all::*, async_trait};
 
 pub fn get_value<'a>(
@@ -21,6 +23,94 @@ pub fn value_to_integer(v: &CommandDataOptionValue) -> Option<i64> {
     }
 }
 
+pub fn value_to_number(v: &CommandDataOptionValue) -> Option<f64> {
+    match v {
+        CommandDataOptionValue::Number(v) => Some(*v),
+        _ => None,
+    }
+}
+
+pub fn value_to_bool(v: &CommandDataOptionValue) -> Option<bool> {
+    match v {
+        CommandDataOptionValue::Boolean(v) => Some(*v),
+        _ => None,
+    }
+}
+
+pub fn value_to_attachment_id(v: &CommandDataOptionValue) -> Option<AttachmentId> {
+    match v {
+        CommandDataOptionValue::Attachment(v) => Some(*v),
+        _ => None,
+    }
+}
+
+pub fn value_to_channel_id(v: &CommandDataOptionValue) -> Option<ChannelId> {
+    match v {
+        CommandDataOptionValue::Channel(v) => Some(*v),
+        _ => None,
+    }
+}
+
+/// Replaces every `{{name}}` in `template` with the corresponding value from `variables`.
+/// Unknown variables are left untouched.
+pub fn render_template(template: &str, variables: &HashMap<String, String>) -> String {
+    let mut output = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        output.push_str(&rest[..start]);
+        let after_start = &rest[start + 2..];
+        let Some(end) = after_start.find("}}") else {
+            rest = &rest[start..];
+            break;
+        };
+
+        let name = after_start[..end].trim();
+        match variables.get(name) {
+            Some(value) => output.push_str(value),
+            None => output.push_str(&rest[start..start + 2 + end + 2]),
+        }
+        rest = &after_start[end + 2..];
+    }
+    output.push_str(rest);
+    output
+}
+
+/// Returns at most the first `max_len` bytes of `text`, cut at a character boundary.
+pub fn truncate(text: &str, max_len: usize) -> &str {
+    if text.len() <= max_len {
+        return text;
+    }
+
+    let mut end = max_len;
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    &text[..end]
+}
+
+/// Posts messages in a channel as a chain of replies, like a public output, with the
+/// first replying to `reply_to` if set.
+pub async fn send_reply_chain(
+    http: &Http,
+    channel_id: ChannelId,
+    reply_to: Option<MessageId>,
+    messages: Vec<CreateMessage>,
+) -> anyhow::Result<()> {
+    let mut previous: Option<MessageReference> =
+        reply_to.map(|message_id| (channel_id, message_id).into());
+    for message in messages {
+        let message = match previous {
+            Some(previous) => message.reference_message(previous),
+            None => message,
+        };
+        let message = channel_id
+            .send_message(http, message.allowed_mentions(CreateAllowedMentions::new()))
+            .await?;
+        previous = Some((&message).into());
+    }
+    Ok(())
+}
+
 #[async_trait]
 #[allow(unused)]
 pub trait RespondableInteraction: Send + Sync {
@@ -29,10 +119,44 @@ pub trait RespondableInteraction: Send + Sync {
     async fn edit(&self, http: &Http, message: &str) -> anyhow::Result<()>;
     async fn create_or_edit(&self, http: &Http, message: &str) -> anyhow::Result<()>;
 
+    async fn create_message(
+        &self,
+        http: &Http,
+        message: CreateInteractionResponseMessage,
+    ) -> anyhow::Result<()>;
+    async fn edit_message(
+        &self,
+        http: &Http,
+        edit: EditInteractionResponse,
+    ) -> anyhow::Result<Message>;
+    async fn delete_message(&self, http: &Http) -> anyhow::Result<()>;
+    async fn create_followup_message(
+        &self,
+        http: &Http,
+        followup: CreateInteractionResponseFollowup,
+    ) -> anyhow::Result<Message>;
+    async fn edit_followup_message(
+        &self,
+        http: &Http,
+        message_id: MessageId,
+        followup: CreateInteractionResponseFollowup,
+    ) -> anyhow::Result<Message>;
+    async fn delete_followup_message(
+        &self,
+        http: &Http,
+        message_id: MessageId,
+    ) -> anyhow::Result<()>;
+
     fn channel_id(&self) -> ChannelId;
     fn guild_id(&self) -> Option<GuildId>;
     fn message(&self) -> Option<&Message>;
     fn user(&self) -> &User;
+    /// The token that the interaction can be followed up with, for up to 15 minutes.
+    fn token(&self) -> &str;
+    /// The locale of the user's client, which user-facing text is translated into.
+    fn locale(&self) -> &str;
+    /// The server's preferred locale, for text that everyone in the server sees.
+    fn guild_locale(&self) -> Option<&str>;
 }
 macro_rules! implement_respondable_interaction {
     ($name:ident) => {
@@ -52,20 +176,59 @@ macro_rules! implement_respondable_interaction {
                 Ok(self.get_response(http).await?)
             }
             async fn edit(&self, http: &Http, message: &str) -> anyhow::Result<()> {
+                // Edit through the interaction, so that ephemeral responses can be edited too
+                self.edit_response(http, EditInteractionResponse::new().content(message))
+                    .await?;
+                Ok(())
+            }
+            async fn create_or_edit(&self, http: &Http, message: &str) -> anyhow::Result<()> {
+                Ok(if self.get_interaction_message(http).await.is_ok() {
+                    self.edit(http, message).await?
+                } else {
+                    self.create(http, message).await?
+                })
+            }
+
+            async fn create_message(
+                &self,
+                http: &Http,
+                message: CreateInteractionResponseMessage,
+            ) -> anyhow::Result<()> {
                 Ok(self
-                    .get_interaction_message(http)
-                    .await?
-                    .edit(http, EditMessage::new().content(message))
+                    .create_response(http, CreateInteractionResponse::Message(message))
                     .await?)
             }
-            async fn create_or_edit(&self, http: &Http, message: &str) -> anyhow::Result<()> {
-                Ok(
-                    if let Ok(mut msg) = self.get_interaction_message(http).await {
-                        msg.edit(http, EditMessage::new().content(message)).await?
-                    } else {
-                        self.create(http, message).await?
-                    },
-                )
+            async fn edit_message(
+                &self,
+                http: &Http,
+                edit: EditInteractionResponse,
+            ) -> anyhow::Result<Message> {
+                Ok(self.edit_response(http, edit).await?)
+            }
+            async fn delete_message(&self, http: &Http) -> anyhow::Result<()> {
+                Ok(self.delete_response(http).await?)
+            }
+            async fn create_followup_message(
+                &self,
+                http: &Http,
+                followup: CreateInteractionResponseFollowup,
+            ) -> anyhow::Result<Message> {
+                Ok(self.create_followup(http, followup).await?)
+            }
+            async fn edit_followup_message(
+                &self,
+                http: &Http,
+                message_id: MessageId,
+                followup: CreateInteractionResponseFollowup,
+            ) -> anyhow::Result<Message> {
+                Ok(self.edit_followup(http, message_id, followup).await?)
+            }
+            async fn delete_followup_message(
+                &self,
+                http: &Http,
+                message_id: MessageId,
+            ) -> anyhow::Result<()> {
+                Ok(self.delete_followup(http, message_id).await?)
             }
 
             fn channel_id(&self) -> ChannelId {
@@ -77,6 +240,15 @@ macro_rules! implement_respondable_interaction {
             fn user(&self) -> &User {
                 &self.user
             }
+            fn token(&self) -> &str {
+                &self.token
+            }
+            fn locale(&self) -> &str {
+                &self.locale
+            }
+            fn guild_locale(&self) -> Option<&str> {
+                self.guild_locale.as_deref()
+            }
             respondable_interaction_message!($name);
         }
     };
@@ -112,3 +284,33 @@ pub fn interaction_to_respondable_interaction(
         _ => None,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_template() {
+        let variables = HashMap::from_iter([
+            ("prompt".to_string(), "Hello!".to_string()),
+            ("target_language".to_string(), "French".to_string()),
+        ]);
+
+        assert_eq!(
+            render_template(
+                "Translate into {{target_language}}: {{ prompt }}",
+                &variables
+            ),
+            "Translate into French: Hello!"
+        );
+
+        // Unknown variables are left as-is
+        assert_eq!(
+            render_template("{{unknown}} {{prompt}}", &variables),
+            "{{unknown}} Hello!"
+        );
+
+        // Unterminated variables are left as-is
+        assert_eq!(render_template("{{prompt", &variables), "{{prompt");
+    }
+}
@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+
+use rusqlite::OptionalExtension;
+use serenity::all::{ButtonStyle, CreateActionRow, CreateButton, MessageId, UserId};
+
+use crate::database::{Database, from_sql_id, to_sql_id};
+
+pub const VOTE_ID_BASE: &str = "vote";
+
+/// Builds a vote button message ID from a message ID and the index of the choice.
+pub fn build_id(message_id: MessageId, index: usize) -> String {
+    format!("{VOTE_ID_BASE}#{message_id}#{index}")
+}
+
+/// Parses a vote button message ID into a message ID and the index of the choice.
+pub fn parse_id(id: &str) -> Option<(MessageId, usize)> {
+    let mut split_id = id.split('#');
+    if split_id.next() != Some(VOTE_ID_BASE) {
+        return None;
+    }
+    Some((
+        MessageId::new(split_id.next()?.parse::<u64>().ok()?),
+        split_id.next()?.parse::<usize>().ok()?,
+    ))
+}
+
+/// The models compared in a comparison, and the choice of each user that has voted,
+/// which are kept so that the preferences can be looked at later.
+pub struct Ballot {
+    message_id: MessageId,
+    choices: Vec<String>,
+    votes: HashMap<UserId, usize>,
+}
+impl Ballot {
+    /// Saves a new ballot for the comparison posted as `message_id`.
+    pub fn create(
+        database: &Database,
+        message_id: MessageId,
+        command: &str,
+        prompt: &str,
+        choices: Vec<String>,
+    ) -> anyhow::Result<Self> {
+        let models = serde_json::to_string(&choices)?;
+        database.with_connection(|connection| {
+            connection.execute(
+                "INSERT INTO comparisons (message_id, command, prompt, models) VALUES (?1, ?2, ?3, ?4)",
+                rusqlite::params![to_sql_id(message_id.get()), command, prompt, models],
+            )
+        })?;
+
+        Ok(Self {
+            message_id,
+            choices,
+            votes: HashMap::new(),
+        })
+    }
+
+    /// Loads the ballot for the comparison posted as `message_id`, if there is one.
+    pub fn load(database: &Database, message_id: MessageId) -> anyhow::Result<Option<Self>> {
+        let id = to_sql_id(message_id.get());
+        let Some(models) = database.with_connection(|connection| {
+            connection
+                .query_row(
+                    "SELECT models FROM comparisons WHERE message_id = ?1",
+                    [id],
+                    |row| row.get::<_, String>(0),
+                )
+                .optional()
+        })?
+        else {
+            return Ok(None);
+        };
+        let votes = database.with_connection(|connection| {
+            connection
+                .prepare("SELECT user_id, choice FROM comparison_votes WHERE message_id = ?1")?
+                .query_map([id], |row| {
+                    Ok((
+                        UserId::new(from_sql_id(row.get(0)?)),
+                        row.get::<_, i64>(1)? as usize,
+                    ))
+                })?
+                .collect()
+        })?;
+
+        Ok(Some(Self {
+            message_id,
+            choices: serde_json::from_str(&models)?,
+            votes,
+        }))
+    }
+
+    /// Records the user's vote, replacing any earlier vote. Voting for the same choice
+    /// again withdraws the vote.
+    pub fn vote(
+        &mut self,
+        database: &Database,
+        user_id: UserId,
+        index: usize,
+    ) -> anyhow::Result<()> {
+        if index >= self.choices.len() {
+            return Ok(());
+        }
+
+        let message_id = to_sql_id(self.message_id.get());
+        let user = to_sql_id(user_id.get());
+        if self.votes.get(&user_id) == Some(&index) {
+            database.with_connection(|connection| {
+                connection.execute(
+                    "DELETE FROM comparison_votes WHERE message_id = ?1 AND user_id = ?2",
+                    rusqlite::params![message_id, user],
+                )
+            })?;
+            self.votes.remove(&user_id);
+        } else {
+            database.with_connection(|connection| {
+                connection.execute(
+                    "INSERT INTO comparison_votes (message_id, user_id, choice) VALUES (?1, ?2, ?3)
+                    ON CONFLICT (message_id, user_id) DO UPDATE SET choice = excluded.choice",
+                    rusqlite::params![message_id, user, index as i64],
+                )
+            })?;
+            self.votes.insert(user_id, index);
+        }
+        Ok(())
+    }
+
+    /// Creates the row of buttons for the vote, showing the current counts.
+    pub fn buttons(&self) -> CreateActionRow {
+        /// Button labels can be up to 80 characters.
+        const LABEL_SIZE: usize = 70;
+
+        CreateActionRow::Buttons(
+            self.choices
+                .iter()
+                .enumerate()
+                .map(|(i, choice)| {
+                    let count = self.votes.values().filter(|v| **v == i).count();
+                    CreateButton::new(build_id(self.message_id, i))
+                        .style(ButtonStyle::Secondary)
+                        .label(format!(
+                            "{} ({count})",
+                            crate::util::truncate(choice, LABEL_SIZE)
+                        ))
+                })
+                .collect(),
+        )
+    }
+}